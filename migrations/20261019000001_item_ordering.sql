@@ -0,0 +1,8 @@
+-- 'episodic' (newest first) or 'serial' (listen in order). itunes:type
+ALTER TABLE channel ADD COLUMN itunes_type TEXT NOT NULL DEFAULT 'episodic'
+  CHECK (itunes_type IN ('episodic', 'serial'));
+-- max number of items rendered in the feed. NULL == no limit.
+ALTER TABLE channel ADD COLUMN max_items INT CHECK (max_items > 0);
+
+-- optional. itunes:season, tie-breaker after pub_date.
+ALTER TABLE item ADD COLUMN season INT;
@@ -0,0 +1,51 @@
+-- Clean up rows that would violate the constraints below.
+
+-- duplicate external_id: oldest channel keeps it, the rest get a fresh id.
+UPDATE channel SET external_id = gen_random_uuid()
+WHERE id IN (
+  SELECT id FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY external_id ORDER BY id) AS rn
+    FROM channel
+  ) dup WHERE dup.rn > 1
+);
+
+-- titles differing only by case: oldest keeps it, the rest get their id appended.
+UPDATE channel SET title = title || ' (' || id || ')'
+WHERE id IN (
+  SELECT id FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY LOWER(title) ORDER BY id) AS rn
+    FROM channel
+  ) dup WHERE dup.rn > 1
+);
+
+-- items pointing at no channel can't be rendered. Kept aside rather than dropped.
+CREATE TABLE item_orphaned (LIKE item INCLUDING ALL);
+INSERT INTO item_orphaned
+  SELECT * FROM item WHERE channel_id NOT IN (SELECT external_id FROM channel);
+DELETE FROM item WHERE channel_id NOT IN (SELECT external_id FROM channel);
+
+-- repeated ep_number within a channel/season: later uploads are moved past the current max.
+UPDATE item SET ep_number = renumbered.new_ep_number
+FROM (
+  SELECT dup.id, mx.max_ep + ROW_NUMBER() OVER (PARTITION BY dup.channel_id ORDER BY dup.pub_date, dup.id) AS new_ep_number
+  FROM (
+    SELECT id, channel_id, pub_date,
+      ROW_NUMBER() OVER (PARTITION BY channel_id, COALESCE(season, 0), ep_number ORDER BY pub_date, id) AS rn
+    FROM item
+  ) dup
+  JOIN (SELECT channel_id, MAX(ep_number) AS max_ep FROM item GROUP BY channel_id) mx
+    ON mx.channel_id = dup.channel_id
+  WHERE dup.rn > 1
+) renumbered
+WHERE item.id = renumbered.id;
+
+ALTER TABLE channel ADD CONSTRAINT channel_external_id_key UNIQUE (external_id);
+CREATE UNIQUE INDEX channel_lower_title_idx ON channel (LOWER(title));
+
+-- deleting a channel takes its items with it.
+ALTER TABLE item ADD CONSTRAINT item_channel_id_fkey
+  FOREIGN KEY (channel_id) REFERENCES channel (external_id)
+  ON UPDATE CASCADE ON DELETE CASCADE;
+-- episode numbers restart per season; items without a season share one numbering.
+CREATE UNIQUE INDEX item_channel_ep_number_idx ON item (channel_id, COALESCE(season, 0), ep_number);
+CREATE INDEX item_channel_id_idx ON item (channel_id);
@@ -0,0 +1,15 @@
+-- up to three Apple category/subcategory pairs per channel, rendered in position order.
+-- channel.category stays as the primary (position 0) category, used for googleplay:category.
+CREATE TABLE channel_category(
+  channel_id uuid NOT NULL REFERENCES channel (external_id) ON UPDATE CASCADE ON DELETE CASCADE,
+  position SMALLINT NOT NULL CHECK (position BETWEEN 0 AND 2),
+  category TEXT NOT NULL,
+  -- NULL for top level only
+  subcategory TEXT,
+  PRIMARY KEY (channel_id, position)
+);
+CREATE UNIQUE INDEX channel_category_pair_idx 
+  ON channel_category (channel_id, category, COALESCE(subcategory, ''));
+
+INSERT INTO channel_category (channel_id, position, category)
+  SELECT external_id, 0, category FROM channel;
@@ -0,0 +1,9 @@
+-- resized copies of uploaded artwork. owner_id is a channel external_id or an item id.
+CREATE TABLE artwork_variant(
+  owner_id uuid NOT NULL,
+  -- square, px
+  size INT NOT NULL,
+  object_key TEXT NOT NULL,
+  url TEXT NOT NULL,
+  PRIMARY KEY (owner_id, size)
+);
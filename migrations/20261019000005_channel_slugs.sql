@@ -0,0 +1,36 @@
+-- Feed URLs were derived from the title (lowercase, spaces as dashes). Channels now own a
+-- stable slug, keep the slugs they had before, and may publish at a custom path.
+ALTER TABLE channel ADD COLUMN slug TEXT;
+ALTER TABLE channel ADD COLUMN custom_path TEXT;
+
+UPDATE channel SET slug = TRIM(BOTH '-' FROM REGEXP_REPLACE(LOWER(title), '[^a-z0-9]+', '-', 'g'));
+UPDATE channel SET slug = 'channel-' || id WHERE slug = '';
+-- two titles can reduce to the same slug: oldest keeps it.
+UPDATE channel SET slug = slug || '-' || id
+WHERE id IN (
+  SELECT id FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY slug ORDER BY id) AS rn
+    FROM channel
+  ) dup WHERE dup.rn > 1
+);
+
+ALTER TABLE channel ALTER COLUMN slug SET NOT NULL;
+ALTER TABLE channel ADD CONSTRAINT channel_slug_key UNIQUE (slug);
+ALTER TABLE channel ADD CONSTRAINT channel_slug_check CHECK (slug ~ '^[a-z0-9]+(-[a-z0-9]+)*$');
+ALTER TABLE channel ADD CONSTRAINT channel_custom_path_key UNIQUE (custom_path);
+
+-- retired slugs answer with a 301 to the channel's current one.
+CREATE TABLE channel_slug_history (
+  slug TEXT PRIMARY KEY,
+  channel_id uuid NOT NULL REFERENCES channel (external_id) ON UPDATE CASCADE ON DELETE CASCADE,
+  retired_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX channel_slug_history_channel_id_idx ON channel_slug_history (channel_id);
+
+-- keep the old title based URL working where it differs from the new slug.
+INSERT INTO channel_slug_history (slug, channel_id)
+  SELECT DISTINCT ON (legacy) legacy, external_id FROM (
+    SELECT REPLACE(LOWER(TRIM(title)), ' ', '-') AS legacy, external_id, slug, id FROM channel
+  ) c
+  WHERE legacy <> slug AND legacy NOT IN (SELECT slug FROM channel)
+  ORDER BY legacy, id;
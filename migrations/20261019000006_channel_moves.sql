@@ -0,0 +1,18 @@
+-- A channel moving to another host announces the new feed with itunes:new-feed-url until
+-- redirect_after, then its feed URLs answer 301 to moved_to.
+ALTER TABLE channel ADD COLUMN moved_to TEXT;
+ALTER TABLE channel ADD COLUMN redirect_after TIMESTAMPTZ;
+ALTER TABLE channel ADD CONSTRAINT channel_moved_check
+  CHECK ((moved_to IS NULL) = (redirect_after IS NULL));
+
+-- every move and cancellation, kept for as long as the channel exists.
+CREATE TABLE channel_move_audit (
+  id BIGSERIAL PRIMARY KEY,
+  channel_id uuid NOT NULL REFERENCES channel (external_id) ON UPDATE CASCADE ON DELETE CASCADE,
+  action TEXT NOT NULL CHECK (action IN ('moved', 'cancelled')),
+  moved_to TEXT,
+  redirect_after TIMESTAMPTZ,
+  previous_moved_to TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX channel_move_audit_channel_id_idx ON channel_move_audit (channel_id, created_at);
@@ -0,0 +1,3 @@
+-- original <guid> of imported episodes, so podcatchers don't see them as new.
+ALTER TABLE item ADD COLUMN guid TEXT;
+CREATE UNIQUE INDEX item_channel_guid_idx ON item (channel_id, guid) WHERE guid IS NOT NULL;
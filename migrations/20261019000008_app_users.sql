@@ -0,0 +1,9 @@
+-- operators who can get a session token, managed with santigold-admin. The configured
+-- admin_password only logs in while this table is empty; the first row retires it.
+CREATE TABLE app_user(
+  username TEXT PRIMARY KEY CHECK (username ~ '^[a-z0-9_.-]{1,64}$'),
+  -- argon2id PHC string
+  password_hash TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  rotated_at TIMESTAMPTZ
+);
@@ -0,0 +1,11 @@
+-- episodes whose audio goes straight to the bucket through a presigned PUT. The row holds
+-- the validated podcast_data until finalize_upload publishes it.
+CREATE TABLE presigned_upload(
+  episode_id uuid PRIMARY KEY,
+  object_key TEXT NOT NULL,
+  content_length BIGINT NOT NULL CHECK (content_length > 0),
+  podcast_data TEXT NOT NULL,
+  -- the PUT URL stops working here; finalize still works until the row is collected.
+  expires_at TIMESTAMPTZ NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
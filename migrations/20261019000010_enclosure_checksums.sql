@@ -0,0 +1,2 @@
+-- hex SHA-256 of the audio as the server uploaded it; NULL where it never read the file.
+ALTER TABLE item ADD COLUMN enclosure_sha256 TEXT CHECK (enclosure_sha256 ~ '^[0-9a-f]{64}$');
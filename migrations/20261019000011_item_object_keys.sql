@@ -0,0 +1,6 @@
+-- bucket key of each enclosure, so a changed key_template or prefix doesn't lose track of
+-- existing objects. NULL for enclosures hosted elsewhere.
+ALTER TABLE item ADD COLUMN object_key TEXT;
+-- everything uploaded so far went to the bucket root as {id}.mp3.
+UPDATE item SET object_key = id || '.mp3' WHERE enclosure_url LIKE '%/' || id || '.mp3';
+CREATE UNIQUE INDEX item_object_key_idx ON item (object_key) WHERE object_key IS NOT NULL;
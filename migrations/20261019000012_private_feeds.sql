@@ -0,0 +1,25 @@
+-- private channels are only served to subscribers, at /podcast/{slug}/{token}, and their
+-- media objects are not public-read.
+ALTER TABLE channel ADD COLUMN private BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- one row per issued feed token. Only the token's sha256 is kept, it is shown once.
+CREATE TABLE feed_subscriber (
+  id uuid PRIMARY KEY,
+  channel_id uuid NOT NULL REFERENCES channel (external_id) ON UPDATE CASCADE ON DELETE CASCADE,
+  label TEXT NOT NULL CHECK (LENGTH(label) BETWEEN 1 AND 255),
+  token_sha256 TEXT NOT NULL UNIQUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  revoked_at TIMESTAMPTZ
+);
+CREATE INDEX feed_subscriber_channel_id_idx ON feed_subscriber (channel_id);
+
+-- every feed poll (item_id NULL) and media request made with a token.
+CREATE TABLE feed_access_log (
+  id BIGSERIAL PRIMARY KEY,
+  subscriber_id uuid NOT NULL REFERENCES feed_subscriber (id) ON DELETE CASCADE,
+  item_id uuid,
+  ip TEXT,
+  user_agent TEXT,
+  accessed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+CREATE INDEX feed_access_log_subscriber_id_idx ON feed_access_log (subscriber_id, accessed_at);
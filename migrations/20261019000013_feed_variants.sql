@@ -0,0 +1,28 @@
+-- named extra feeds of a channel (ad-free, bonus, trailers...), at /podcast/{slug}/variant/{name}.
+CREATE TABLE feed_variant (
+  id uuid PRIMARY KEY,
+  channel_id uuid NOT NULL REFERENCES channel (external_id) ON UPDATE CASCADE ON DELETE CASCADE,
+  name TEXT NOT NULL CHECK (name ~ '^[a-z0-9]+(-[a-z0-9]+)*$' AND LENGTH(name) <= 100),
+  -- replaces the channel title in this feed; '' keeps it.
+  title TEXT NOT NULL DEFAULT '',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (channel_id, name)
+);
+
+-- the episodes in a variant. Enclosure columns are NULL when the variant uses the
+-- episode's own audio.
+CREATE TABLE item_variant (
+  item_id uuid NOT NULL REFERENCES item (id) ON DELETE CASCADE,
+  variant_id uuid NOT NULL REFERENCES feed_variant (id) ON DELETE CASCADE,
+  enclosure_url TEXT,
+  enclosure_type TEXT,
+  enclosure_length TEXT,
+  enclosure_sha256 TEXT CHECK (enclosure_sha256 ~ '^[0-9a-f]{64}$'),
+  object_key TEXT UNIQUE,
+  PRIMARY KEY (item_id, variant_id),
+  CHECK ((enclosure_url IS NULL) = (object_key IS NULL))
+);
+CREATE INDEX item_variant_variant_id_idx ON item_variant (variant_id);
+
+-- FALSE: the episode is only in the variants it was added to, e.g. bonus episodes.
+ALTER TABLE item ADD COLUMN main_feed BOOLEAN NOT NULL DEFAULT TRUE;
@@ -0,0 +1,163 @@
+use {
+    crate::{
+        AppError, ChannelFeeds, S3,
+        refresh_xml_buffer, request_feed_rebuild,
+    },
+    sqlx::{
+        PgPool, migrate::Migrator, types::Uuid,
+    },
+    std::collections::BTreeSet,
+};
+
+/// every migration in ./migrations, embedded at build time.
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// apply pending migrations; returns the schema version afterwards.
+pub async fn run_migrations(pg_conn_pool: &PgPool) -> Result<i64, AppError>{
+    MIGRATOR.run(pg_conn_pool).await
+        .map_err(|e| AppError::Internal(format!("migration failed: {}", e)))?;
+    return Ok(MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0));
+}
+
+#[derive(Clone, Debug)]
+pub struct ChannelSummary{
+    pub id: i32,
+    pub external_id: Uuid,
+    pub slug: String,
+    pub title: String,
+    pub episodes: i64,
+    pub moved_to: Option<String>,
+    pub private: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct EpisodeSummary{
+    pub id: Uuid,
+    pub season: Option<i32>,
+    pub ep_number: i32,
+    pub title: String,
+    pub pub_date: String,
+    pub enclosure_url: String,
+}
+
+pub async fn list_channels(pg_conn_pool: &PgPool) -> Result<Vec<ChannelSummary>, AppError>{
+    let channels = sqlx::query_as!(ChannelSummary, r#"
+        SELECT channel.id, channel.external_id, channel.slug, channel.title, channel.moved_to, channel.private,
+        COUNT(item.id) AS "episodes!"
+        FROM channel LEFT JOIN item ON item.channel_id = channel.external_id
+        GROUP BY channel.id ORDER BY channel.id
+        "#
+    ).fetch_all(pg_conn_pool)
+    .await?;
+    return Ok(channels);
+}
+
+pub async fn list_episodes(ch_external_id: &Uuid, pg_conn_pool: &PgPool) -> Result<Vec<EpisodeSummary>, AppError>{
+    let episodes = sqlx::query_as!(EpisodeSummary, r#"
+        SELECT id, season, ep_number, title, pub_date, enclosure_url FROM item
+        WHERE channel_id = $1
+        ORDER BY season DESC NULLS LAST, ep_number DESC
+        "#, ch_external_id
+    ).fetch_all(pg_conn_pool)
+    .await?;
+    return Ok(episodes);
+}
+
+/// external_id of the channel with this slug or external_id.
+pub async fn resolve_channel(slug_or_id: &str, pg_conn_pool: &PgPool) -> Result<Uuid, AppError>{
+    let ch = sqlx::query!(
+        r#" SELECT external_id FROM channel WHERE slug = $1 OR external_id::TEXT = $1 "#, slug_or_id
+    ).fetch_optional(pg_conn_pool)
+    .await?;
+    return match ch{
+        Some(ch) => Ok(ch.external_id),
+        None => Err(AppError::NotFound(format!("no channel '{}'", slug_or_id))),
+    };
+}
+
+/// render a channel's feed from the database and have running servers swap it in.
+pub async fn rebuild_feed(ch_external_id: &Uuid, pg_conn_pool: &PgPool) -> Result<ChannelFeeds, AppError>{
+    let channel_feeds = refresh_xml_buffer(&ch_external_id.to_string(), pg_conn_pool).await?;
+    request_feed_rebuild(ch_external_id, pg_conn_pool).await?;
+    return Ok(channel_feeds);
+}
+
+/// rows whose media URLs pointed somewhere other than S3::public_link.
+#[derive(Clone, Debug, Default)]
+pub struct MediaUrlRewrite{
+    pub enclosures: u64,
+    pub artwork_variants: u64,
+    /// item itunes_image and channel image_url values that were artwork variant URLs.
+    pub images: u64,
+    pub channels: BTreeSet<Uuid>,
+}
+
+/// point enclosure and artwork URLs of objects in our bucket at S3::public_link, after
+/// public_base_url (or the bucket endpoint) changed, and rebuild the feeds of the channels
+/// touched. URLs hosted elsewhere are left alone. `dry_run` counts without changing anything.
+pub async fn rewrite_media_urls(s3: &S3, dry_run: bool, pg_conn_pool: &PgPool) -> Result<MediaUrlRewrite, AppError>{
+    let mut rewrite = MediaUrlRewrite::default();
+    let mut tx = pg_conn_pool.begin().await?;
+
+    for ep in sqlx::query!(r#"
+        UPDATE item SET enclosure_url = $1 || '/' || object_key
+        WHERE object_key IS NOT NULL AND enclosure_url <> $1 || '/' || object_key
+        RETURNING channel_id
+        "#, s3.public_link
+    ).fetch_all(&mut tx)
+    .await?{
+        rewrite.enclosures += 1;
+        rewrite.channels.insert(ep.channel_id);
+    }
+    for ep in sqlx::query!(r#"
+        UPDATE item_variant iv SET enclosure_url = $1 || '/' || iv.object_key
+        FROM feed_variant v
+        WHERE v.id = iv.variant_id AND iv.object_key IS NOT NULL AND iv.enclosure_url <> $1 || '/' || iv.object_key
+        RETURNING v.channel_id
+        "#, s3.public_link
+    ).fetch_all(&mut tx)
+    .await?{
+        rewrite.enclosures += 1;
+        rewrite.channels.insert(ep.channel_id);
+    }
+    // images first, they're matched on the variant URLs before those change.
+    for ep in sqlx::query!(r#"
+        UPDATE item SET itunes_image = $1 || '/' || v.object_key
+        FROM artwork_variant v
+        WHERE item.itunes_image = v.url AND v.url <> $1 || '/' || v.object_key
+        RETURNING item.channel_id
+        "#, s3.public_link
+    ).fetch_all(&mut tx)
+    .await?{
+        rewrite.images += 1;
+        rewrite.channels.insert(ep.channel_id);
+    }
+    for ch in sqlx::query!(r#"
+        UPDATE channel SET image_url = $1 || '/' || v.object_key
+        FROM artwork_variant v
+        WHERE channel.image_url = v.url AND v.url <> $1 || '/' || v.object_key
+        RETURNING channel.external_id
+        "#, s3.public_link
+    ).fetch_all(&mut tx)
+    .await?{
+        rewrite.images += 1;
+        rewrite.channels.insert(ch.external_id);
+    }
+    rewrite.artwork_variants = sqlx::query!(r#"
+        UPDATE artwork_variant SET url = $1 || '/' || object_key
+        WHERE url <> $1 || '/' || object_key
+        "#, s3.public_link
+    ).execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    if dry_run{
+        tx.rollback().await?;
+        return Ok(rewrite);
+    }
+    tx.commit().await?;
+    for ch_external_id in &rewrite.channels{
+        rebuild_feed(ch_external_id, pg_conn_pool).await?;
+    }
+    return Ok(rewrite);
+}
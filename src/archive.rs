@@ -0,0 +1,536 @@
+use {
+    crate::{
+        AppError, S3, ObjectCannedAcl, upload_file,
+        refresh_xml_buffer, MIGRATOR,
+    },
+    serde::{
+        Serialize, Deserialize,
+    },
+    chrono::{
+        DateTime, Utc,
+    },
+    sha2::{
+        Digest, Sha256,
+    },
+    futures::TryStreamExt,
+    sqlx::{
+        PgPool, migrate::Migrator, types::Uuid,
+    },
+    std::{
+        collections::{
+            HashMap, HashSet,
+        },
+        fs::File,
+        io::{
+            Read, Write,
+        },
+        path::Path,
+    },
+};
+
+/// bump when the archive layout changes; restore refuses other versions.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+/// every table that belongs to a site, parents first. Operator accounts (app_user) are
+/// left out: a restored site starts with admin_password until `santigold-admin user create`.
+pub const ARCHIVE_TABLES: &[&str] = &[
+    "channel", "channel_category", "item",
+    "artwork_variant", "channel_slug_history", "channel_move_audit",
+    "feed_subscriber", "feed_variant", "item_variant",
+];
+
+/// `manifest.json`, the last entry of an archive. Layout:
+/// `db/{table}.json` rows as JSON arrays, `feeds/{slug}.{rss,atom,json}` as served at
+/// export time (for reference, restore renders them again), `media/{key}` bucket objects.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveManifest{
+    pub format_version: u32,
+    /// newest migration applied when the rows were dumped.
+    pub schema_version: i64,
+    pub created_at: DateTime<Utc>,
+    /// S3::public_link of the exporting server; media URLs starting with it are rewritten
+    /// to the restoring server's.
+    pub media_base_url: String,
+    pub tables: Vec<ArchiveTable>,
+    pub feeds: Vec<String>,
+    pub includes_media: bool,
+    pub media: Vec<ArchiveObject>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveTable{
+    pub name: String,
+    pub rows: i64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveObject{
+    pub key: String,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: Option<String>,
+}
+
+fn schema_version() -> i64{
+    return MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+}
+
+fn archive_error(e: impl std::fmt::Display) -> AppError{
+    return AppError::Storage(format!("archive: {}", e));
+}
+
+fn append_bytes<W: Write>(tar: &mut tar::Builder<W>, path: &str, data: &[u8]) -> Result<(), AppError>{
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp() as u64);
+    return tar.append_data(&mut header, path, data).map_err(archive_error);
+}
+
+/// write every site row, the rendered feeds and, with `include_media`, every object in
+/// the bucket to a tar archive at `path`. Rows are read in one snapshot.
+pub async fn export_archive(
+    path: &Path,
+    include_media: bool,
+    pg_conn_pool: &PgPool,
+    s3: &S3,
+) -> Result<ArchiveManifest, AppError>{
+    let mut tx = pg_conn_pool.begin().await?;
+    sqlx::query!(r#" SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY "#)
+        .execute(&mut tx)
+        .await?;
+    let dump = sqlx::query!(r#"
+        SELECT json_build_object(
+            'channel', (SELECT COALESCE(json_agg(t ORDER BY t.id), '[]') FROM channel t),
+            'channel_category', (SELECT COALESCE(json_agg(t ORDER BY t.channel_id, t.position), '[]') FROM channel_category t),
+            'item', (SELECT COALESCE(json_agg(t ORDER BY t.channel_id, t.pub_date, t.id), '[]') FROM item t),
+            'artwork_variant', (SELECT COALESCE(json_agg(t ORDER BY t.owner_id, t.size), '[]') FROM artwork_variant t),
+            'channel_slug_history', (SELECT COALESCE(json_agg(t ORDER BY t.slug), '[]') FROM channel_slug_history t),
+            'channel_move_audit', (SELECT COALESCE(json_agg(t ORDER BY t.id), '[]') FROM channel_move_audit t),
+            'feed_subscriber', (SELECT COALESCE(json_agg(t ORDER BY t.created_at, t.id), '[]') FROM feed_subscriber t),
+            'feed_variant', (SELECT COALESCE(json_agg(t ORDER BY t.channel_id, t.name), '[]') FROM feed_variant t),
+            'item_variant', (SELECT COALESCE(json_agg(t ORDER BY t.variant_id, t.item_id), '[]') FROM item_variant t)
+        )::TEXT AS "tables!"
+        "#
+    ).fetch_one(&mut tx)
+    .await?;
+    let channel_ids = sqlx::query!(r#" SELECT external_id FROM channel ORDER BY id "#)
+        .fetch_all(&mut tx)
+        .await?;
+    tx.commit().await?;
+
+    let mut tables: HashMap<String, Vec<serde_json::Value>> = serde_json::from_str(&dump.tables)
+        .map_err(archive_error)?;
+    let mut manifest = ArchiveManifest{
+        format_version: ARCHIVE_FORMAT_VERSION,
+        schema_version: schema_version(),
+        created_at: Utc::now(),
+        media_base_url: s3.public_link.clone(),
+        tables: Vec::new(),
+        feeds: Vec::new(),
+        includes_media: include_media,
+        media: Vec::new(),
+    };
+
+    let mut tar = tar::Builder::new(File::create(path).map_err(archive_error)?);
+    for name in ARCHIVE_TABLES{
+        let rows = tables.remove(*name).unwrap_or_default();
+        let data = serde_json::to_vec(&rows).map_err(archive_error)?;
+        append_bytes(&mut tar, &format!("db/{}.json", name), &data)?;
+        manifest.tables.push(ArchiveTable{ name: name.to_string(), rows: rows.len() as i64 });
+    }
+
+    // feeds as of now; a channel edited since the dump still matches its rows closely enough
+    // for reference, restore never reads these.
+    for ch in channel_ids{
+        let channel_feeds = match refresh_xml_buffer(&ch.external_id.to_string(), pg_conn_pool).await{
+            Ok(channel_feeds) => channel_feeds,
+            Err(AppError::NotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let feeds = &channel_feeds.feeds;
+        for (ext, feed) in [("rss", &feeds.rss), ("atom", &feeds.atom), ("json", &feeds.json)]{
+            let feed_path = format!("feeds/{}.{}", channel_feeds.slug, ext);
+            append_bytes(&mut tar, &feed_path, &feed.body)?;
+            manifest.feeds.push(feed_path);
+        }
+    }
+
+    if include_media{
+        let mut pages = s3.client.list_objects_v2()
+            .bucket(&s3.bucket)
+            .set_prefix(s3.list_prefix())
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.try_next().await.map_err(archive_error)?{
+            for object in page.contents().unwrap_or_default(){
+                if let Some(key) = object.key(){
+                    manifest.media.push(export_object(key, &mut tar, s3).await?);
+                }
+            }
+        }
+    }
+
+    let data = serde_json::to_vec_pretty(&manifest).map_err(archive_error)?;
+    append_bytes(&mut tar, "manifest.json", &data)?;
+    tar.into_inner().and_then(|mut file| file.flush()).map_err(archive_error)?;
+    return Ok(manifest);
+}
+
+/// copy one object into the archive through a temp file, hashing it on the way.
+async fn export_object<W: Write>(key: &str, tar: &mut tar::Builder<W>, s3: &S3) -> Result<ArchiveObject, AppError>{
+    let output = s3.client.get_object()
+        .bucket(&s3.bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| AppError::Storage(format!("could not download {}: {}", key, e)))?;
+    let content_type = output.content_type().map(|c| c.to_string());
+    let mut body = output.body;
+
+    let temp_file = format!("{}/export-{}", s3.temp_dir, Uuid::new_v4());
+    let mut file = File::create(&temp_file).map_err(archive_error)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let copied: Result<(), AppError> = async {
+        while let Some(chunk) = body.try_next().await
+            .map_err(|e| AppError::Storage(format!("could not download {}: {}", key, e)))?{
+            hasher.update(&chunk);
+            size += chunk.len() as u64;
+            file.write_all(&chunk).map_err(archive_error)?;
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        return tar.append_data(&mut header, format!("media/{}", key), File::open(&temp_file).map_err(archive_error)?)
+            .map_err(archive_error);
+    }.await;
+    if let Err(e) = std::fs::remove_file(&temp_file){
+        log::info!("export_archive: could not remove {}. Err: {}", temp_file, e);
+    }
+    copied?;
+
+    return Ok(ArchiveObject{
+        key: key.to_string(),
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+        content_type,
+    });
+}
+
+/// manifest and table dumps of an archive.
+fn read_archive_rows(path: &Path) -> Result<(ArchiveManifest, HashMap<String, String>), AppError>{
+    let mut archive = tar::Archive::new(File::open(path).map_err(archive_error)?);
+    let mut manifest = None;
+    let mut rows = HashMap::new();
+    for entry in archive.entries().map_err(archive_error)?{
+        let mut entry = entry.map_err(archive_error)?;
+        let entry_path = entry.path().map_err(archive_error)?.to_string_lossy().to_string();
+        if entry_path == "manifest.json"{
+            let mut data = String::new();
+            entry.read_to_string(&mut data).map_err(archive_error)?;
+            manifest = Some(serde_json::from_str::<ArchiveManifest>(&data).map_err(archive_error)?);
+        } else if let Some(table) = entry_path.strip_prefix("db/").and_then(|p| p.strip_suffix(".json")){
+            let mut data = String::new();
+            entry.read_to_string(&mut data).map_err(archive_error)?;
+            rows.insert(table.to_string(), data);
+        }
+    }
+    let manifest = manifest.ok_or_else(|| archive_error("no manifest.json, not a site archive"))?;
+    if manifest.format_version != ARCHIVE_FORMAT_VERSION{
+        return Err(archive_error(format!("format version {} is not supported, expected {}",
+            manifest.format_version, ARCHIVE_FORMAT_VERSION)));
+    }
+    if manifest.schema_version > schema_version(){
+        return Err(archive_error(format!("schema version {} is newer than this server's {}",
+            manifest.schema_version, schema_version())));
+    }
+    for table in &manifest.tables{
+        if !rows.contains_key(&table.name){
+            return Err(archive_error(format!("db/{}.json is missing", table.name)));
+        }
+    }
+    return Ok((manifest, rows));
+}
+
+/// recreate a site from an archive in an empty database. The schema is migrated to the
+/// archive's version, rows are loaded as they were (ids, GUIDs, slugs and custom paths
+/// unchanged), then the remaining migrations run. Archived media is uploaded under the
+/// same keys and URLs pointing at the old bucket are rewritten to this one. Rows are
+/// committed only once the media is in; objects this restore created are removed if either
+/// step fails. Operator accounts are not part of an archive, see ARCHIVE_TABLES.
+pub async fn restore_archive(
+    path: &Path,
+    pg_conn_pool: &PgPool,
+    s3: &S3,
+) -> Result<ArchiveManifest, AppError>{
+    let (manifest, rows) = read_archive_rows(path)?;
+
+    // rows are loaded in the archive's shape, which a newer schema may not accept.
+    let migrated = sqlx::query!(r#" SELECT to_regclass('_sqlx_migrations') IS NOT NULL AS "migrated!" "#)
+        .fetch_one(pg_conn_pool)
+        .await?
+        .migrated;
+    if migrated{
+        // sqlx's own table, not checked at compile time.
+        let newest = sqlx::query_scalar::<_, Option<i64>>(r#" SELECT MAX(version) FROM _sqlx_migrations "#)
+            .fetch_one(pg_conn_pool)
+            .await?
+            .unwrap_or(0);
+        if newest > manifest.schema_version{
+            return Err(AppError::Validation(format!(
+                "the database is already migrated to {}, past the archive's schema {}; restore into a newly \
+                created database, without running `santigold-admin migrate` first", newest, manifest.schema_version)));
+        }
+    }
+    let archived_schema = Migrator{
+        migrations: MIGRATOR.iter()
+            .filter(|m| m.version <= manifest.schema_version)
+            .cloned()
+            .collect::<Vec<_>>()
+            .into(),
+        ignore_missing: false,
+        locking: true,
+    };
+    archived_schema.run(pg_conn_pool).await
+        .map_err(|e| AppError::Internal(format!("could not migrate to schema {}: {}", manifest.schema_version, e)))?;
+    let existing = sqlx::query!(r#" SELECT COUNT(*) AS "count!" FROM channel "#)
+        .fetch_one(pg_conn_pool)
+        .await?;
+    if existing.count > 0{
+        return Err(AppError::Validation("restore needs an empty database".to_string()));
+    }
+
+    let mut tx = pg_conn_pool.begin().await?;
+    for table in ARCHIVE_TABLES{
+        let data = match rows.get(*table){
+            Some(data) => data.as_str(),
+            None => continue,
+        };
+        match *table{
+            "channel" => sqlx::query!(
+                r#" INSERT INTO channel SELECT * FROM json_populate_recordset(NULL::channel, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "channel_category" => sqlx::query!(
+                r#" INSERT INTO channel_category SELECT * FROM json_populate_recordset(NULL::channel_category, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "item" => sqlx::query!(
+                r#" INSERT INTO item SELECT * FROM json_populate_recordset(NULL::item, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "artwork_variant" => sqlx::query!(
+                r#" INSERT INTO artwork_variant SELECT * FROM json_populate_recordset(NULL::artwork_variant, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "channel_slug_history" => sqlx::query!(
+                r#" INSERT INTO channel_slug_history SELECT * FROM json_populate_recordset(NULL::channel_slug_history, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "channel_move_audit" => sqlx::query!(
+                r#" INSERT INTO channel_move_audit SELECT * FROM json_populate_recordset(NULL::channel_move_audit, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "feed_subscriber" => sqlx::query!(
+                r#" INSERT INTO feed_subscriber SELECT * FROM json_populate_recordset(NULL::feed_subscriber, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "feed_variant" => sqlx::query!(
+                r#" INSERT INTO feed_variant SELECT * FROM json_populate_recordset(NULL::feed_variant, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            "item_variant" => sqlx::query!(
+                r#" INSERT INTO item_variant SELECT * FROM json_populate_recordset(NULL::item_variant, $1::TEXT::json) "#, data
+            ).execute(&mut tx).await?,
+            _ => unreachable!("every ARCHIVE_TABLES entry has a loader"),
+        };
+    }
+    sqlx::query!(r#" SELECT setval(pg_get_serial_sequence('channel', 'id'), COALESCE(MAX(id), 0) + 1, false) FROM channel "#)
+        .fetch_one(&mut tx)
+        .await?;
+    sqlx::query!(r#" SELECT setval(pg_get_serial_sequence('channel_move_audit', 'id'), COALESCE(MAX(id), 0) + 1, false) FROM channel_move_audit "#)
+        .fetch_one(&mut tx)
+        .await?;
+
+    let (old_base, new_base) = (&manifest.media_base_url, &s3.public_link);
+    if manifest.includes_media && old_base != new_base{
+        sqlx::query!(r#"
+            UPDATE item SET enclosure_url = $2 || SUBSTR(enclosure_url, LENGTH($1) + 1)
+            WHERE STARTS_WITH(enclosure_url, $1)
+            "#, old_base, new_base
+        ).execute(&mut tx)
+        .await?;
+        sqlx::query!(r#"
+            UPDATE item SET itunes_image = $2 || SUBSTR(itunes_image, LENGTH($1) + 1)
+            WHERE STARTS_WITH(itunes_image, $1)
+            "#, old_base, new_base
+        ).execute(&mut tx)
+        .await?;
+        sqlx::query!(r#"
+            UPDATE channel SET image_url = $2 || SUBSTR(image_url, LENGTH($1) + 1)
+            WHERE STARTS_WITH(image_url, $1)
+            "#, old_base, new_base
+        ).execute(&mut tx)
+        .await?;
+        sqlx::query!(r#"
+            UPDATE artwork_variant SET url = $2 || SUBSTR(url, LENGTH($1) + 1)
+            WHERE STARTS_WITH(url, $1)
+            "#, old_base, new_base
+        ).execute(&mut tx)
+        .await?;
+        // archives from before feed variants don't have the table yet.
+        if rows.contains_key("item_variant"){
+            sqlx::query!(r#"
+                UPDATE item_variant SET enclosure_url = $2 || SUBSTR(enclosure_url, LENGTH($1) + 1)
+                WHERE STARTS_WITH(enclosure_url, $1)
+                "#, old_base, new_base
+            ).execute(&mut tx)
+            .await?;
+        }
+    }
+
+    let mut created = Vec::new();
+    if manifest.includes_media{
+        // private channels' audio must never be public, not even while restoring.
+        let private_keys = private_media_keys(&rows)?;
+        if let Err(e) = restore_media(path, &manifest, &private_keys, &mut created, s3).await{
+            remove_restored_objects(&created, s3).await;
+            return Err(e);
+        }
+    }
+    if let Err(e) = tx.commit().await{
+        remove_restored_objects(&created, s3).await;
+        return Err(e.into());
+    }
+
+    MIGRATOR.run(pg_conn_pool).await
+        .map_err(|e| AppError::Internal(format!("could not migrate restored database: {}", e)))?;
+    return Ok(manifest);
+}
+
+/// object keys of audio in private channels, from an archive's table dumps. Archives from
+/// before private channels have no private column and so no private keys.
+fn private_media_keys(rows: &HashMap<String, String>) -> Result<HashSet<String>, AppError>{
+    let table = |name: &str| -> Result<Vec<serde_json::Value>, AppError>{
+        return match rows.get(name){
+            Some(data) => serde_json::from_str(data).map_err(archive_error),
+            None => Ok(Vec::new()),
+        };
+    };
+    let field = |row: &serde_json::Value, name: &str| row[name].as_str().map(|value| value.to_string());
+
+    let channels: HashSet<String> = table("channel")?.iter()
+        .filter(|row| row["private"].as_bool().unwrap_or(false))
+        .filter_map(|row| field(row, "external_id"))
+        .collect();
+    let variants: HashSet<String> = table("feed_variant")?.iter()
+        .filter(|row| field(row, "channel_id").is_some_and(|ch| channels.contains(&ch)))
+        .filter_map(|row| field(row, "id"))
+        .collect();
+    let mut keys: HashSet<String> = table("item")?.iter()
+        .filter(|row| field(row, "channel_id").is_some_and(|ch| channels.contains(&ch)))
+        .filter_map(|row| field(row, "object_key"))
+        .collect();
+    keys.extend(table("item_variant")?.iter()
+        .filter(|row| field(row, "variant_id").is_some_and(|v| variants.contains(&v)))
+        .filter_map(|row| field(row, "object_key")));
+    return Ok(keys);
+}
+
+/// upload every `media/` entry under its original key, checked against the manifest. Keys
+/// that weren't in the bucket before are added to `created`.
+async fn restore_media(
+    path: &Path,
+    manifest: &ArchiveManifest,
+    private_keys: &HashSet<String>,
+    created: &mut Vec<String>,
+    s3: &S3,
+) -> Result<(), AppError>{
+    let objects: HashMap<&str, &ArchiveObject> = manifest.media.iter()
+        .map(|object| (object.key.as_str(), object))
+        .collect();
+    let mut archive = tar::Archive::new(File::open(path).map_err(archive_error)?);
+    let mut restored = 0;
+    for entry in archive.entries().map_err(archive_error)?{
+        let mut entry = entry.map_err(archive_error)?;
+        let entry_path = entry.path().map_err(archive_error)?.to_string_lossy().to_string();
+        let object = match entry_path.strip_prefix("media/").and_then(|key| objects.get(key)){
+            Some(object) => *object,
+            None => continue,
+        };
+
+        // e.g. restoring next to the site the archive came from, in the same bucket.
+        let existed = s3.client.head_object().bucket(&s3.bucket).key(&object.key).send().await.is_ok();
+        let temp_file = format!("{}/restore-{}", s3.temp_dir, Uuid::new_v4());
+        let copied = File::create(&temp_file)
+            .and_then(|mut file| std::io::copy(&mut entry, &mut file))
+            .map_err(archive_error);
+        let uploaded = match copied{
+            Ok(_) => restore_object(&temp_file, object, private_keys.contains(&object.key), s3).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = std::fs::remove_file(&temp_file){
+            log::info!("restore_archive: could not remove {}. Err: {}", temp_file, e);
+        }
+        uploaded?;
+        if !existed{
+            created.push(object.key.clone());
+        }
+        restored += 1;
+    }
+    if restored != manifest.media.len(){
+        return Err(archive_error(format!("{} of {} media objects found in the archive",
+            restored, manifest.media.len())));
+    }
+    return Ok(());
+}
+
+/// undo restore_media after a failed restore. What can't be removed is left to gc.
+async fn remove_restored_objects(keys: &[String], s3: &S3){
+    for key in keys{
+        if let Err(e) = s3.client.delete_object().bucket(&s3.bucket).key(key).send().await{
+            log::error!("restore_archive: could not remove {}, leaving it to gc. Err: {}", key, e);
+        }
+    }
+}
+
+/// upload an archived object, removing it again if it isn't the one the manifest lists.
+async fn restore_object(temp_file: &str, object: &ArchiveObject, private: bool, s3: &S3) -> Result<(), AppError>{
+    let acl = if private { ObjectCannedAcl::Private } else { ObjectCannedAcl::PublicRead };
+    let content_type = object.content_type.as_deref().unwrap_or("application/octet-stream");
+    let checksums = upload_file(&object.key, Path::new(temp_file), content_type, acl, s3).await?;
+    if checksums.sha256 != object.sha256{
+        if let Err(e) = s3.client.delete_object().bucket(&s3.bucket).key(&object.key).send().await{
+            log::error!("restore_archive: could not remove {}. Err: {}", object.key, e);
+        }
+        return Err(archive_error(format!("media/{} does not match its checksum", object.key)));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn private_media_keys_cover_items_and_variants_of_private_channels(){
+        let rows: HashMap<String, String> = [
+            ("channel", r#"[{"external_id": "c1", "private": true}, {"external_id": "c2", "private": false}]"#),
+            ("item", r#"[
+                {"channel_id": "c1", "object_key": "private.mp3"},
+                {"channel_id": "c1", "object_key": null},
+                {"channel_id": "c2", "object_key": "public.mp3"}
+            ]"#),
+            ("feed_variant", r#"[{"id": "v1", "channel_id": "c1"}, {"id": "v2", "channel_id": "c2"}]"#),
+            ("item_variant", r#"[
+                {"variant_id": "v1", "object_key": "private-ad-free.mp3"},
+                {"variant_id": "v2", "object_key": "public-ad-free.mp3"}
+            ]"#),
+        ].iter().map(|(table, data)| (table.to_string(), data.to_string())).collect();
+        let keys = private_media_keys(&rows).unwrap();
+        assert_eq!(keys, HashSet::from(["private.mp3".to_string(), "private-ad-free.mp3".to_string()]));
+    }
+
+    #[test]
+    fn archives_without_private_channels_have_no_private_keys(){
+        let rows: HashMap<String, String> = [
+            ("channel", r#"[{"external_id": "c1"}]"#),
+            ("item", r#"[{"channel_id": "c1", "object_key": "a.mp3"}]"#),
+        ].iter().map(|(table, data)| (table.to_string(), data.to_string())).collect();
+        assert!(private_media_keys(&rows).unwrap().is_empty());
+    }
+}
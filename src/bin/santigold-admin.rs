@@ -0,0 +1,274 @@
+use {
+    l19_santigold::{
+        get_configuration, Settings,
+        PgPool, S3, AppError,
+        list_channels, list_episodes, resolve_channel,
+        create_user, rotate_password, generate_password,
+        run_migrations, rebuild_feed, check_storage, collect_garbage,
+        export_archive, restore_archive, rewrite_media_urls,
+        set_channel_private, create_subscriber, list_subscribers, revoke_subscriber,
+        SUBSCRIBER_ACTIVITY_DAYS,
+        create_variant, list_variants, delete_variant,
+        add_variant_episode, remove_variant_episode, set_main_feed,
+        Uuid,
+    },
+    std::path::Path,
+};
+
+const USAGE: &str = "usage: santigold-admin <command>
+
+  channels                          list channels
+  episodes <channel>                list a channel's episodes, by slug or external_id
+  user create <username> [password] add an operator; prints a generated password if none given.
+                                    Once one exists, admin_password no longer logs in
+  user rotate <username>            replace an operator's password with a generated one; sessions
+                                    already issued stay valid until the server restarts
+  migrate                           apply pending database migrations
+  rebuild-feed <channel>            re-render a channel's feeds on every running server
+  private <channel> on|off          serve a channel to subscribers only, or publicly again
+  subscriber add <channel> <label>  issue a private feed token; it is shown once
+  subscriber list <channel>         list a channel's subscribers and their recent activity
+  subscriber revoke <id>            stop one subscriber's token from working
+  variant add <channel> <name> [title]
+                                    add a feed variant at /podcast/<slug>/variant/<name>, or
+                                    /podcast/<slug>/<token>/variant/<name> if the channel is private
+  variant list <channel>            list a channel's feed variants
+  variant remove <channel> <name>   delete a variant and the audio uploaded for it
+  variant episode <channel> <name> <episode_id> [audio.mp3]
+                                    put an episode in a variant, with its own audio if given
+  variant drop <channel> <name> <episode_id>
+                                    take an episode out of a variant
+  main-feed <episode_id> on|off     show or hide an episode in its channel's main feed
+  check-storage                     compare database rows with bucket objects
+  gc [--delete-orphans [--all-bucket]] [--dry-run]
+                                    remove temp files past [storage_gc] temp_file_ttl_hours and,
+                                    with --delete-orphans, objects no row refers to; without a
+                                    key_prefix that is the whole bucket and needs --all-bucket
+  rewrite-media-urls [--dry-run]    point media URLs at public_base_url (or the bucket) and rebuild feeds
+  export <archive.tar> [--media]    write the site, and optionally its media, to an archive
+  restore <archive.tar>             recreate the site from an archive in a new, unmigrated database.
+                                    Operators aren't archived; add them again with user create";
+
+#[tokio::main]
+async fn main(){
+    env_logger::Builder::from_env(env_logger::Env::default()
+        .default_filter_or("warn")).init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    if args.is_empty() || args == ["help"] || args == ["--help"]{
+        println!("{}", USAGE);
+        return;
+    }
+
+    let config = get_configuration()
+        .expect("Failed to read config file");
+    let db_conn_pool = PgPool::connect(&config.database_connection_string())
+        .await
+        .expect("Failed to connect to Postgres");
+    let s3 = S3::from_settings(&config);
+
+    if let Err(e) = command(&args, &config, &db_conn_pool, &s3).await{
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn command(args: &[&str], config: &Settings, pg_conn_pool: &PgPool, s3: &S3) -> Result<(), AppError>{
+    match args{
+        ["channels"] => {
+            for ch in list_channels(pg_conn_pool).await?{
+                println!("{:>5}  {}  {:<32} {:>4} eps  {}{}{}", ch.id, ch.external_id, ch.slug, ch.episodes, ch.title,
+                    if ch.private { "  (private)" } else { "" },
+                    ch.moved_to.map(|to| format!("  (moved to {})", to)).unwrap_or_default());
+            }
+        },
+        ["episodes", channel] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            for ep in list_episodes(&ch_external_id, pg_conn_pool).await?{
+                let number = match ep.season{
+                    Some(season) => format!("S{}E{}", season, ep.ep_number),
+                    None => format!("E{}", ep.ep_number),
+                };
+                println!("{:>8}  {}  {}  {}", number, ep.id, ep.pub_date, ep.title);
+            }
+        },
+        ["user", "create", username] => {
+            let password = generate_password();
+            create_user(username, &password, pg_conn_pool).await?;
+            println!("created {}, password: {}", username, password);
+        },
+        ["user", "create", username, password] => {
+            create_user(username, password, pg_conn_pool).await?;
+            println!("created {}", username);
+        },
+        ["user", "rotate", username] => {
+            let password = generate_password();
+            rotate_password(username, &password, pg_conn_pool).await?;
+            println!("rotated {}, new password: {}", username, password);
+            println!("session tokens issued before now stay valid until the server restarts");
+        },
+        ["migrate"] => {
+            let version = run_migrations(pg_conn_pool).await?;
+            println!("schema at version {}", version);
+        },
+        ["rebuild-feed", channel] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            let channel_feeds = rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            let feeds = &channel_feeds.feeds;
+            println!("rebuilt {}: rss {} bytes, atom {} bytes, json {} bytes",
+                channel_feeds.slug, feeds.rss.body.len(), feeds.atom.body.len(), feeds.json.body.len());
+        },
+        ["private", channel, setting @ ("on" | "off")] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            let private = *setting == "on";
+            let objects = set_channel_private(&ch_external_id, private, s3, pg_conn_pool).await?;
+            let channel_feeds = rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            println!("{} is now {}, {} media objects {}", channel_feeds.slug,
+                if private { "private" } else { "public" }, objects,
+                if private { "made private" } else { "made public-read" });
+        },
+        ["subscriber", "add", channel, label] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            let subscriber = create_subscriber(&ch_external_id, label, pg_conn_pool).await?;
+            println!("subscriber {}, feed: {}", subscriber.subscriber_id, subscriber.feed_path);
+        },
+        ["subscriber", "list", channel] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            println!("{:<36}  {:<25}  {:>8}  {:>4}  label (requests/IPs over {} days)",
+                "id", "last access", "requests", "IPs", SUBSCRIBER_ACTIVITY_DAYS);
+            for subscriber in list_subscribers(&ch_external_id, pg_conn_pool).await?{
+                let status = if subscriber.revoked_at.is_some(){
+                    "  (revoked)"
+                } else if subscriber.likely_shared(){
+                    "  (likely shared)"
+                } else {
+                    ""
+                };
+                println!("{}  {:<25}  {:>8}  {:>4}  {}{}", subscriber.id,
+                    subscriber.last_access.map(|t| t.format("%Y-%m-%d %H:%M:%S UTC").to_string()).unwrap_or_else(|| "never".to_string()),
+                    subscriber.recent_requests, subscriber.recent_ips, subscriber.label, status);
+            }
+        },
+        ["subscriber", "revoke", subscriber_id] => {
+            let subscriber_id = Uuid::parse_str(subscriber_id)
+                .map_err(|_| AppError::Validation(format!("'{}' is not a subscriber id", subscriber_id)))?;
+            revoke_subscriber(&subscriber_id, pg_conn_pool).await?;
+            println!("revoked {}", subscriber_id);
+        },
+        ["variant", "add", channel, name] | ["variant", "add", channel, name, _] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            let title = args.get(4).copied().unwrap_or("");
+            let variant = create_variant(&ch_external_id, name, title, pg_conn_pool).await?;
+            rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            println!("variant {}, feed: {}", variant.variant_id, variant.feed_path);
+        },
+        ["variant", "list", channel] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            for variant in list_variants(&ch_external_id, pg_conn_pool).await?{
+                println!("{:<24} {:>4} eps  {:>4} with own audio  {}", variant.name, variant.episodes, variant.own_audio, variant.title);
+            }
+        },
+        ["variant", "remove", channel, name] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            delete_variant(&ch_external_id, name, s3, pg_conn_pool).await?;
+            rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            println!("removed {}", name);
+        },
+        ["variant", "episode", channel, name, episode_id] | ["variant", "episode", channel, name, episode_id, _] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            let item_id = Uuid::parse_str(episode_id)
+                .map_err(|_| AppError::Validation(format!("'{}' is not an episode id", episode_id)))?;
+            let audio = args.get(5).map(Path::new);
+            add_variant_episode(&ch_external_id, name, &item_id, audio, s3, pg_conn_pool).await?;
+            rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            println!("{} is in {}{}", item_id, name, if audio.is_some() { " with its own audio" } else { "" });
+        },
+        ["variant", "drop", channel, name, episode_id] => {
+            let ch_external_id = resolve_channel(channel, pg_conn_pool).await?;
+            let item_id = Uuid::parse_str(episode_id)
+                .map_err(|_| AppError::Validation(format!("'{}' is not an episode id", episode_id)))?;
+            remove_variant_episode(&ch_external_id, name, &item_id, s3, pg_conn_pool).await?;
+            rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            println!("{} is no longer in {}", item_id, name);
+        },
+        ["main-feed", episode_id, setting @ ("on" | "off")] => {
+            let item_id = Uuid::parse_str(episode_id)
+                .map_err(|_| AppError::Validation(format!("'{}' is not an episode id", episode_id)))?;
+            let ch_external_id = set_main_feed(&item_id, *setting == "on", pg_conn_pool).await?;
+            rebuild_feed(&ch_external_id, pg_conn_pool).await?;
+            println!("{} is {} the main feed", item_id, if *setting == "on" { "in" } else { "hidden from" });
+        },
+        ["check-storage"] => {
+            let report = check_storage(pg_conn_pool, s3).await?;
+            println!("{} objects, {} referenced, {} hosted elsewhere", report.objects, report.referenced, report.external);
+            for (key, owner) in &report.missing{
+                println!("missing   {}  ({})", key, owner);
+            }
+            for (key, last_modified) in &report.orphaned{
+                println!("orphaned  {}  (last modified {})", key, last_modified);
+            }
+            if !report.is_consistent(){
+                std::process::exit(3);
+            }
+        },
+        ["gc", flags @ ..] if flags.iter().all(|f| ["--delete-orphans", "--all-bucket", "--dry-run"].contains(f)) => {
+            let delete_orphans = flags.contains(&"--delete-orphans");
+            let all_bucket = flags.contains(&"--all-bucket");
+            let dry_run = flags.contains(&"--dry-run");
+            let outcome = collect_garbage(&config.storage_gc, delete_orphans, all_bucket, dry_run, pg_conn_pool, s3).await?;
+            let verb = if dry_run { "would delete" } else { "deleted" };
+            for key in &outcome.deleted_objects{
+                println!("{} object     {}", verb, key);
+            }
+            for path in &outcome.deleted_temp_files{
+                println!("{} temp file  {}", verb, path.display());
+            }
+            if outcome.expired_reservations > 0{
+                println!("dropped {} expired presigned uploads", outcome.expired_reservations);
+            }
+            for (key, owner) in &outcome.report.missing{
+                println!("missing  {}  ({})", key, owner);
+            }
+            for failure in &outcome.failures{
+                eprintln!("failed   {}", failure);
+            }
+            println!("{} orphaned objects, {} inside the {}h grace period, {} missing",
+                outcome.report.orphaned.len(), outcome.recent_orphans,
+                config.storage_gc.orphan_grace_hours, outcome.report.missing.len());
+            if !outcome.failures.is_empty(){
+                std::process::exit(1);
+            }
+        },
+        ["rewrite-media-urls"] | ["rewrite-media-urls", "--dry-run"] => {
+            let dry_run = args.len() == 2;
+            let rewrite = rewrite_media_urls(s3, dry_run, pg_conn_pool).await?;
+            let verb = if dry_run { "would rewrite" } else { "rewrote" };
+            println!("{} {} enclosures, {} artwork variants, {} images to {}",
+                verb, rewrite.enclosures, rewrite.artwork_variants, rewrite.images, s3.public_link);
+            if !dry_run{
+                println!("rebuilt feeds of {} channels", rewrite.channels.len());
+            }
+        },
+        ["export", path] | ["export", path, "--media"] => {
+            let include_media = args.len() == 3;
+            let manifest = export_archive(Path::new(path), include_media, pg_conn_pool, s3).await?;
+            for table in &manifest.tables{
+                println!("{}: {} rows", table.name, table.rows);
+            }
+            println!("feeds: {}, media objects: {}", manifest.feeds.len(), manifest.media.len());
+        },
+        ["restore", path] => {
+            let manifest = restore_archive(Path::new(path), pg_conn_pool, s3).await?;
+            for table in &manifest.tables{
+                println!("{}: {} rows", table.name, table.rows);
+            }
+            println!("media objects: {}", manifest.media.len());
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+    }
+    return Ok(());
+}
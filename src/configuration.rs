@@ -8,6 +8,8 @@ pub fn get_configuration() -> Result<Settings, ConfigError>{
         .add_source(File::new("real_configuration", FileFormat::Toml))
         .build()?
         .try_deserialize::<Settings>()?;
+    config.s3_bucket.validate()
+        .map_err(ConfigError::Message)?;
 
     return Ok(config); 
 }
@@ -16,10 +18,16 @@ pub fn get_configuration() -> Result<Settings, ConfigError>{
 pub struct Settings{
     pub in_production_mode: bool,
     pub application_port: String,
+    /// bootstrap login, accepted with any username only until the first operator is added
+    /// with `santigold-admin user create`; from then on only app_user passwords log in.
     pub admin_password: String,
     pub temp_dir: String,
     pub database: DatabaseSettings,
     pub s3_bucket: S3Bucket,
+    #[serde(default)]
+    pub storage_gc: StorageGcSettings,
+    #[serde(default)]
+    pub import: ImportSettings,
 }
 
 impl Settings{
@@ -52,12 +60,149 @@ pub struct S3Bucket{
     pub endpoint_url: String,
     pub access_key: String,
     pub secret_access_key: String,
+    /// folder every object goes under, e.g. "podcasts"; "" for the bucket root.
+    #[serde(default)]
+    pub key_prefix: String,
+    /// episode audio key below key_prefix. Placeholders: {channel_slug}, {year}, {month},
+    /// {ep_number}, {season}, {id}, {ext}; {id} is required to keep keys unique.
+    #[serde(default = "default_key_template")]
+    pub key_template: String,
+    /// CDN or custom domain serving the bucket, e.g. "https://media.example.com"; used for
+    /// enclosure and artwork URLs instead of full_link. `santigold-admin rewrite-media-urls`
+    /// updates existing rows after a change.
+    #[serde(default)]
+    pub public_base_url: Option<String>,
 }
 
+/// the original layout, `{uuid}.mp3` in the bucket root.
+fn default_key_template() -> String{
+    return "{id}.{ext}".to_string();
+}
+
+/// placeholders S3Bucket::key_template may use.
+pub const KEY_TEMPLATE_PLACEHOLDERS: &[&str] = &[
+    "channel_slug", "year", "month", "ep_number", "season", "id", "ext",
+];
+
 impl S3Bucket{
+    pub fn validate(&self) -> Result<(), String>{
+        if let Some(base) = &self.public_base_url{
+            if !(base.starts_with("https://") || base.starts_with("http://")){
+                return Err(format!("public_base_url '{}' must be http(s)", base));
+            }
+        }
+        return self.validate_key_template();
+    }
+
+    pub fn validate_key_template(&self) -> Result<(), String>{
+        let mut rest = self.key_template.as_str();
+        while let Some(start) = rest.find('{'){
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format!("key_template '{}' has an unclosed {{", self.key_template))?;
+            let name = &rest[start + 1..start + end];
+            if !KEY_TEMPLATE_PLACEHOLDERS.contains(&name){
+                return Err(format!("key_template '{}' has unknown placeholder {{{}}}", self.key_template, name));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if !self.key_template.contains("{id}"){
+            return Err(format!("key_template '{}' must contain {{id}}", self.key_template));
+        }
+        return Ok(());
+    }
+
     pub fn full_link(&self) -> String{
         let mut link = self.endpoint_url.clone().to_lowercase();
         link = link.replace("https://", &format!("https://{}.", self.bucket)); 
         return link;
     }
+
+    /// base of public media URLs: public_base_url if set, else full_link.
+    pub fn public_link(&self) -> String{
+        return match &self.public_base_url{
+            Some(base) => base.trim_end_matches('/').to_string(),
+            None => self.full_link(),
+        };
+    }
+}
+
+/// `[storage_gc]`, the scheduled storage reconcile. Off unless `enabled`.
+#[derive(serde::Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StorageGcSettings{
+    pub enabled: bool,
+    pub interval_minutes: u64,
+    /// files in temp_dir older than this are abandoned uploads.
+    pub temp_file_ttl_hours: u64,
+    /// delete bucket objects no row refers to. Off: only log them.
+    pub delete_orphans: bool,
+    /// let delete_orphans run without a key_prefix, over every object in the bucket.
+    pub all_bucket: bool,
+    /// orphans younger than this may belong to an upload still in progress.
+    pub orphan_grace_hours: u64,
+}
+
+impl Default for StorageGcSettings{
+    fn default() -> Self{
+        return StorageGcSettings{
+            enabled: false,
+            interval_minutes: 60,
+            temp_file_ttl_hours: 24,
+            delete_orphans: false,
+            all_bucket: false,
+            orphan_grace_hours: 24,
+        };
+    }
+}
+
+/// `[import]`, where /import may fetch feeds and enclosures from.
+#[derive(serde::Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ImportSettings{
+    /// hosts fetched even though they resolve to a loopback or private address, e.g.
+    /// ["localhost"] to import from a local stand-in. Empty: public hosts only.
+    pub allowed_hosts: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn bucket(key_template: &str) -> S3Bucket{
+        return S3Bucket{
+            region: "us-east-1".to_string(),
+            bucket: "fake-bucket".to_string(),
+            endpoint_url: "https://us-east-1.linodeobjects.com".to_string(),
+            access_key: String::new(),
+            secret_access_key: String::new(),
+            key_prefix: String::new(),
+            key_template: key_template.to_string(),
+            public_base_url: None,
+        };
+    }
+
+    #[test]
+    fn key_templates_with_known_placeholders_and_id_pass(){
+        for template in [default_key_template().as_str(), "{channel_slug}/{year}/{month}/{id}.{ext}",
+            "{channel_slug}/s{season}/{ep_number}-{id}.{ext}", "audio/{id}"]{
+            assert!(bucket(template).validate_key_template().is_ok(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn key_templates_need_id_and_known_closed_placeholders(){
+        for template in ["{channel_slug}/{ep_number}.{ext}", "{id}.{extension}", "{id}.{ext", "{ID}.mp3", ""]{
+            assert!(bucket(template).validate_key_template().is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn public_base_url_must_be_http(){
+        let mut s3_bucket = bucket("{id}.{ext}");
+        s3_bucket.public_base_url = Some("media.example.com".to_string());
+        assert!(s3_bucket.validate().is_err());
+        s3_bucket.public_base_url = Some("https://media.example.com/".to_string());
+        assert!(s3_bucket.validate().is_ok());
+        assert_eq!(s3_bucket.public_link(), "https://media.example.com");
+    }
 }
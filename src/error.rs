@@ -0,0 +1,220 @@
+use {
+    crate::{
+        HttpResponse, Uuid,
+    },
+    actix_web::{
+        ResponseError,
+        http::StatusCode,
+    },
+    serde::Serialize,
+    validator::{
+        ValidationErrors, ValidationErrorsKind,
+    },
+    std::fmt,
+};
+
+/// Application level errors. Every route returns these as a JSON body:
+/// `{"code": "...", "message": "...", "fields": [...]}`, `fields` only on InvalidFields.
+#[derive(Debug)]
+pub enum AppError{
+    /// S3 / temp file failures.
+    Storage(String),
+    Database(sqlx::Error),
+    /// malformed or inconsistent request data.
+    Validation(String),
+    /// payload failed the Channel/Item rules, one entry per bad field.
+    InvalidFields(Vec<FieldError>),
+    Unauthorized,
+    NotFound(String),
+    Internal(String),
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrorBody{
+    pub code: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FieldError{
+    /// dotted path, e.g. "channel.itunes_owner_email"
+    pub field: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl AppError{
+    pub fn code(&self) -> &'static str{
+        return match self{
+            AppError::Storage(_) => "storage_error",
+            AppError::Database(err) => match db_error_code(err).as_str(){
+                "23505" => "conflict",
+                "23503" => "invalid_reference",
+                "23502" | "23514" => "constraint_violation",
+                _ => "database_error",
+            },
+            AppError::Validation(_) | AppError::InvalidFields(_) => "validation_error",
+            AppError::Unauthorized => "unauthorized",
+            AppError::NotFound(_) => "not_found",
+            AppError::Internal(_) => "internal_error",
+        };
+    }
+
+    pub fn message(&self) -> String{
+        return match self{
+            AppError::Storage(msg)
+                | AppError::Validation(msg)
+                | AppError::NotFound(msg)
+                | AppError::Internal(msg) => msg.clone(),
+            AppError::Unauthorized => "invalid or missing session token".to_string(),
+            AppError::InvalidFields(fields) => format!("{} invalid field(s)", fields.len()),
+            AppError::Database(err) => {
+                let constraint = err.as_database_error()
+                    .and_then(|db_err| db_err.constraint())
+                    .unwrap_or("");
+                match constraint{
+                    "channel_external_id_key" => "channel external_id already exists",
+                    "channel_title_key" | "channel_lower_title_idx" => "channel title already exists",
+                    "item_pkey" => "episode id already exists",
+                    "item_channel_ep_number_idx" => "ep_number already used in this channel/season",
+                    "item_channel_id_fkey" => "channel_id does not match an existing channel",
+                    "channel_slug_key" => "slug already in use",
+                    "item_channel_guid_idx" => "guid already used in this channel",
+                    "channel_custom_path_key" => "custom_path already in use",
+                    "feed_variant_channel_id_name_key" => "variant name already used in this channel",
+                    "app_user_pkey" => "username already exists",
+                    "app_user_username_check" => "username must be 1-64 of a-z 0-9 _ . -",
+                    "" => "database error",
+                    _ => "request violates a database constraint",
+                }.to_string()
+            },
+        };
+    }
+}
+
+/// SQLSTATE of a database error, "" for anything else (io, pool, decode...).
+fn db_error_code(err: &sqlx::Error) -> String{
+    return err.as_database_error()
+        .and_then(|db_err| db_err.code())
+        .map(|code| code.to_string())
+        .unwrap_or_default();
+}
+
+impl fmt::Display for AppError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        return match self{
+            AppError::Database(err) => write!(f, "{}: {}", self.code(), err),
+            _ => write!(f, "{}: {}", self.code(), self.message()),
+        };
+    }
+}
+
+impl ResponseError for AppError{
+    fn status_code(&self) -> StatusCode{
+        return match self{
+            AppError::Storage(_) => StatusCode::BAD_GATEWAY,
+            AppError::Database(_) => match self.code(){
+                "conflict" => StatusCode::CONFLICT,
+                "invalid_reference" => StatusCode::UNPROCESSABLE_ENTITY,
+                "constraint_violation" => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            AppError::Validation(_) | AppError::InvalidFields(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+    }
+
+    fn error_response(&self) -> HttpResponse{
+        if self.status_code().is_server_error(){
+            log::error!("{}", self);
+        }
+        let fields = match self{
+            AppError::InvalidFields(fields) => fields.clone(),
+            _ => Vec::new(),
+        };
+        return HttpResponse::build(self.status_code())
+            .json(ErrorBody{
+                code: self.code(),
+                message: self.message(),
+                fields,
+            });
+    }
+}
+
+impl From<sqlx::Error> for AppError{
+    fn from(err: sqlx::Error) -> Self{
+        return AppError::Database(err);
+    }
+}
+
+impl From<ValidationErrors> for AppError{
+    fn from(errs: ValidationErrors) -> Self{
+        let mut fields = Vec::new();
+        flatten_validation_errors("", &errs, &mut fields);
+        fields.sort_by(|a, b| a.field.cmp(&b.field));
+        return AppError::InvalidFields(fields);
+    }
+}
+
+fn flatten_validation_errors(prefix: &str, errs: &ValidationErrors, out: &mut Vec<FieldError>){
+    for (field, kind) in errs.errors(){
+        let path = if prefix.is_empty(){
+            field.to_string()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        match kind{
+            ValidationErrorsKind::Field(field_errs) => {
+                for e in field_errs{
+                    let message = match &e.message{
+                        Some(msg) => msg.to_string(),
+                        None => default_validation_message(e),
+                    };
+                    out.push(FieldError{
+                        field: path.clone(),
+                        code: e.code.to_string(),
+                        message,
+                    });
+                }
+            },
+            ValidationErrorsKind::Struct(nested) => flatten_validation_errors(&path, nested, out),
+            ValidationErrorsKind::List(list) => {
+                for (i, nested) in list{
+                    flatten_validation_errors(&format!("{}[{}]", path, i), nested, out);
+                }
+            },
+        }
+    }
+}
+
+/// messages for the built-in validators, which don't set one.
+fn default_validation_message(e: &validator::ValidationError) -> String{
+    let param = |name: &str| e.params.get(name).map(|v| v.to_string());
+    return match e.code.as_ref(){
+        "length" => match (param("min"), param("max")){
+            (Some(min), Some(max)) => format!("length must be between {} and {}", min, max),
+            (Some(min), None) => format!("length must be at least {}", min),
+            (None, Some(max)) => format!("length must be at most {}", max),
+            _ => "invalid length".to_string(),
+        },
+        "range" => match (param("min"), param("max")){
+            (Some(min), Some(max)) => format!("must be between {} and {}", min, max),
+            (Some(min), None) => format!("must be at least {}", min),
+            (None, Some(max)) => format!("must be at most {}", max),
+            _ => "out of range".to_string(),
+        },
+        "email" => "must be an email address".to_string(),
+        "url" => "must be a URL".to_string(),
+        code => format!("failed '{}' check", code),
+    };
+}
+
+/// parse a client supplied UUID; `field` names it in the 400 message.
+pub fn parse_uuid(field: &str, value: &str) -> Result<Uuid, AppError>{
+    return Uuid::parse_str(value)
+        .map_err(|_| AppError::Validation(format!("{} is not a valid UUID: '{}'", field, value)));
+}
@@ -1,5 +1,10 @@
 mod routes;
+mod error;
+mod validation;
 mod configuration;
+mod archive;
+mod storage;
+mod admin;
 
 pub use {
     log,
@@ -47,48 +52,71 @@ pub use {
     },
     routes::{
         auth::*,
+        artwork::*,
         podcast::*,
-        health_check::*,
+        feed_formats::*,
+        feed_cache::*,
+        slug::*,
+        channel_move::*,
+        import::*,
+        tus::*,
+        presigned::*,
+        private_feed::*,
+        feed_variant::*,
+        health_check::{
+            health_check, health_check_xml,
+            health_check_xml_extended, health_check_xml_extended_post,
+        },
     },
+    error::*,
+    validation::*,
     configuration::*,
+    archive::*,
+    storage::*,
+    admin::*,
 };
 
 //TODO inital attempt @ avoiding config in persistent data pool was wrong.
 //Refactor. // Also don't need Arc<>; web::Data does the job.
-pub fn run(listener: TcpListener, db_conn_pool: PgPool, s3_client: S3, admin_pass: AdminPassword)
+pub fn run(listener: TcpListener, db_conn_pool: PgPool, s3_client: S3, admin_pass: AdminPassword,
+    import_settings: ImportSettings)
 -> Result::<Server, std::io::Error>{
     let admin_pass = web::Data::new(admin_pass.clone());
     let active_tokens = web::Data::new(RwLock::new(ActiveTokens(Vec::new())));
     log::info!("TRACE --------------------------------------- run 0");
-    let xmls = Arc::new(RwLock::new(Xml::initialize(db_conn_pool.clone())));
+    let feed_cache = web::Data::new(FeedCache::default());
+    feed_cache.clone().into_inner().warm_up(db_conn_pool.clone());
+    feed_cache.clone().into_inner().listen_for_rebuilds(db_conn_pool.clone());
     log::info!("TRACE --------------------------------------- run 1");
-    let xmls = web::Data::new(xmls);
     log::info!("TRACE --------------------------------------- run 2");
     let db_conn_pool = web::Data::new(db_conn_pool);
+    let delete_queue = web::Data::new(S3DeleteQueue::start(s3_client.clone()));
     let s3_client = web::Data::new(s3_client);
+    let tus_locks = web::Data::new(TusLocks::default());
+    let import_settings = web::Data::new(import_settings);
     log::info!("TRACE --------------------------------------- run 3");
     let json_config = web::JsonConfig::default()
         .limit(50096) // raise this max TODO.
         .content_type(|mime| mime == mime::APPLICATION_JSON)
         .error_handler(|err, _req|{
-            println!("Calling run--json_config--error");
-            actix_web::error::InternalError::from_response(
-                err, HttpResponse::Conflict().into()
-            ).into()
+            AppError::Validation(format!("invalid JSON body: {}", err)).into()
         });
     let multipart_form_config = MultipartFormConfig::default()
         .error_handler(|err, req|{
             log::info!("TRACE Multipart ----- Bad request. Headers: {:?}",
                        req.headers());
-            actix_web::error::InternalError::from_response(
-                err, HttpResponse::BadRequest().into()
-            ).into()
-
+            AppError::Validation(format!("invalid multipart body: {}", err)).into()
         });
     log::info!("TRACE --------------------------------------- run 4");
     let server = HttpServer::new(move ||{
         App::new()
-            .wrap(middleware::Logger::default())
+            // the default format, with subscriber tokens cut from the path and no Referer,
+            // which can hold a feed URL too.
+            .wrap(middleware::Logger::new(r#"%a "%{request_line}xi" %s %b "%{User-Agent}i" %T"#)
+                .custom_request_replace("request_line", |req| format!("{} {}{}{} {:?}",
+                    req.method(), redact_feed_token(req.path()),
+                    if req.query_string().is_empty(){ "" } else { "?" }, req.query_string(),
+                    req.version())))
             .wrap(Cors::permissive()) // TODO CRTITICAL: temp
             .route("/health_check", web::get().to(health_check))
             .route("/health_check_xml", web::get().to(health_check_xml))
@@ -96,16 +124,46 @@ pub fn run(listener: TcpListener, db_conn_pool: PgPool, s3_client: S3, admin_pas
             .route("/health_check_xml_extended_post", web::post().to(health_check_xml_extended_post))
             .route("/channels", web::get().to(channels))
             .route("/podcast/{ch_title}", web::get().to(podcast))
+            .route("/podcast/{ch_title}/variant/{variant}", web::get().to(variant_feed))
+            .route("/podcast/{ch_title}/{token}", web::get().to(subscriber_feed))
+            .route("/podcast/{ch_title}/{token}/media/{item_id}", web::get().to(subscriber_media))
+            .route("/podcast/{ch_title}/{token}/variant/{variant}", web::get().to(subscriber_variant_feed))
+            .route("/podcast/{ch_title}/{token}/variant/{variant}/media/{item_id}", web::get().to(subscriber_variant_media))
             .route("/upload_object", web::post().to(upload_object))
             .route("/upload_form", web::post().to(upload_form))
             .route("/upload", web::post().to(upload))
+            .route("/upload_artwork", web::post().to(upload_channel_artwork))
+            .route("/replace_episode_audio", web::post().to(replace_episode_audio))
+            .route("/presign_upload", web::post().to(presign_upload))
+            .route("/finalize_upload", web::post().to(finalize_upload))
+            .route("/channel_slug", web::post().to(update_channel_slug))
+            .route("/channel_move", web::post().to(move_channel))
+            .route("/feed_subscriber", web::post().to(add_feed_subscriber))
+            .route("/feed_subscriber/revoke", web::post().to(revoke_feed_subscriber))
+            .route("/feed_variant", web::post().to(add_feed_variant))
+            .route("/feed_variant/delete", web::post().to(delete_feed_variant))
+            .route("/feed_variant/episode", web::post().to(add_feed_variant_episode))
+            .route("/feed_variant/episode/remove", web::post().to(remove_feed_variant_episode))
+            .route("/feed_variant/main_feed", web::post().to(set_episode_main_feed))
+            .route("/import", web::post().to(import_feed))
             .route("/get_auth", web::post().to(generate_session_token))
+            .service(web::scope("/tus")
+                .wrap(middleware::DefaultHeaders::new().add(("Tus-Resumable", TUS_VERSION)))
+                .route("", web::method(actix_web::http::Method::OPTIONS).to(tus_options))
+                .route("", web::post().to(tus_create))
+                .route("/{upload_id}", web::head().to(tus_head))
+                .route("/{upload_id}", web::patch().to(tus_patch))
+                .route("/{upload_id}", web::delete().to(tus_delete)))
+            .default_service(web::to(custom_feed))
             .app_data(json_config.clone())
             .app_data(multipart_form_config.clone())
             .app_data(db_conn_pool.clone())
             .app_data(s3_client.clone())
-            .app_data(xmls.clone())
+            .app_data(delete_queue.clone())
+            .app_data(tus_locks.clone())
+            .app_data(feed_cache.clone())
             .app_data(admin_pass.clone())
+            .app_data(import_settings.clone())
             .app_data(active_tokens.clone())
     })
     .listen(listener)?
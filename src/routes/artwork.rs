@@ -0,0 +1,316 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpResponse,
+        ByteStream, ObjectCannedAcl,
+        ActiveTokens, is_valid_token,
+        MultipartForm, MultipartFormText,
+        MultipartFormTempFile,
+        AppError, parse_uuid,
+        S3, FeedCache, refresh_channel_feed,
+    },
+    serde::Serialize,
+    sqlx::{
+        PgPool, types::Uuid,
+    },
+    image::{
+        ColorType, DynamicImage,
+        ImageFormat, ImageReader,
+        codecs::jpeg::JpegEncoder,
+        imageops::FilterType,
+    },
+    std::{
+        io::Cursor,
+        path::Path,
+    },
+};
+
+/// square sizes produced from uploaded artwork, largest first. Sizes above the
+/// source are skipped rather than upscaled.
+pub const ARTWORK_SIZES: [u32; 4] = [3000, 1400, 600, 300];
+pub const ARTWORK_MIN_SIZE: u32 = 1400;
+pub const ARTWORK_MAX_SIZE: u32 = 3000;
+
+#[derive(MultipartForm)]
+pub struct ChannelArtworkUpload{
+    pub session_token: MultipartFormText<String>,
+    pub channel_id: MultipartFormText<String>,
+    pub image: MultipartFormTempFile,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ArtworkVariant{
+    pub size: u32,
+    pub object_key: String,
+    pub url: String,
+}
+
+/// decoded artwork that passed check_artwork().
+pub struct Artwork{
+    image: DynamicImage,
+    format: ImageFormat,
+}
+
+/// POST channel artwork; sets image_url/width/height to the largest variant.
+pub async fn upload_channel_artwork(
+    payload: MultipartForm<ChannelArtworkUpload>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&payload.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let ch_external_id = parse_uuid("channel_id", &payload.channel_id)?;
+    if sqlx::query!(r#" SELECT id FROM channel WHERE external_id = $1 "#, ch_external_id)
+        .fetch_optional(pg_conn_pool.get_ref())
+        .await?
+        .is_none(){
+        return Err(AppError::NotFound("channel does not exist".to_string()));
+    }
+
+    let variants = store_artwork(&ch_external_id, payload.image.file.path(), &s3, &pg_conn_pool).await?;
+    let largest = &variants[0];
+
+    sqlx::query!(r#"
+        UPDATE channel SET image_url = $1, image_width = $2, image_height = $2 WHERE external_id = $3
+        "#, largest.url, largest.size as i32, ch_external_id
+    ).execute(pg_conn_pool.get_ref())
+    .await?;
+
+    refresh_channel_feed(&ch_external_id.to_string(), &pg_conn_pool, &feed_cache).await?;
+
+    return Ok(HttpResponse::Ok().json(variants));
+}
+
+/// check, resize and upload artwork for a channel or episode, replacing any previous
+/// variants. Returned largest first.
+pub async fn store_artwork(
+    owner_id: &Uuid,
+    path: &Path,
+    s3: &S3,
+    pg_conn_pool: &PgPool,
+) -> Result<Vec<ArtworkVariant>, AppError>{
+    let path = path.to_path_buf();
+    let encoded = web::block(move || -> Result<_, AppError>{
+        let artwork = check_artwork(&path)?;
+        return resize_artwork(&artwork);
+    }).await
+    .map_err(|e| AppError::Internal(e.to_string()))??;
+
+    // new prefix per upload so podcatchers and CDNs don't keep serving the old image.
+    let upload_id = Uuid::new_v4().simple().to_string();
+    let mut variants = Vec::new();
+    for (size, ext, content_type, bytes) in encoded{
+        let object_key = s3.prefixed_key(&format!("artwork/{}/{}/{}.{}", owner_id, upload_id, size, ext));
+        if let Err(e) = put_public_object(&object_key, bytes, content_type, s3).await{
+            delete_objects(variants.iter().map(|v: &ArtworkVariant| v.object_key.as_str()), s3).await;
+            return Err(e);
+        }
+        variants.push(ArtworkVariant{
+            size,
+            url: s3.object_url(&object_key),
+            object_key,
+        });
+    }
+
+    let previous = sqlx::query!(
+        r#" SELECT object_key FROM artwork_variant WHERE owner_id = $1 "#, owner_id
+    ).fetch_all(pg_conn_pool)
+    .await?;
+
+    let mut tx = pg_conn_pool.begin().await?;
+    sqlx::query!(r#" DELETE FROM artwork_variant WHERE owner_id = $1 "#, owner_id)
+        .execute(&mut tx)
+        .await?;
+    for v in &variants{
+        sqlx::query!(r#"
+            INSERT INTO artwork_variant (owner_id, size, object_key, url) VALUES ($1, $2, $3, $4)
+            "#, owner_id, v.size as i32, v.object_key, v.url
+        ).execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    // the new variants are already live.
+    delete_objects(previous.iter().map(|old| old.object_key.as_str()), s3).await;
+
+    return Ok(variants);
+}
+
+/// drop all variants of an owner, e.g. episode artwork after a failed publish.
+pub async fn remove_artwork(owner_id: &Uuid, s3: &S3, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    let removed = sqlx::query!(
+        r#" DELETE FROM artwork_variant WHERE owner_id = $1 RETURNING object_key "#, owner_id
+    ).fetch_all(pg_conn_pool)
+    .await?;
+    delete_objects(removed.iter().map(|old| old.object_key.as_str()), s3).await;
+    return Ok(());
+}
+
+/// best effort; failures are logged and left for manual cleanup.
+async fn delete_objects<'a>(keys: impl Iterator<Item = &'a str>, s3: &S3){
+    for key in keys{
+        if let Err(e) = s3.client.delete_object()
+            .bucket(&s3.bucket)
+            .key(key)
+            .send()
+            .await{
+            log::error!("could not delete artwork {}. Err: {}", key, e);
+        }
+    }
+}
+
+/// JPEG or PNG, RGB, square, 1400-3000px.
+pub fn check_artwork(path: &Path) -> Result<Artwork, AppError>{
+    // the upload's temp file; unreadable means a bad or missing upload, not a storage fault.
+    let reader = ImageReader::open(path)
+        .map_err(|e| AppError::Validation(format!("artwork could not be read: {}", e)))?
+        .with_guessed_format()
+        .map_err(|e| AppError::Validation(format!("artwork could not be read: {}", e)))?;
+    let format = match reader.format(){
+        Some(f @ (ImageFormat::Jpeg | ImageFormat::Png)) => f,
+        _ => return Err(AppError::Validation("artwork must be a JPEG or PNG".to_string())),
+    };
+    let image = reader.decode()
+        .map_err(|e| AppError::Validation(format!("artwork could not be decoded: {}", e)))?;
+
+    let (width, height) = (image.width(), image.height());
+    if width != height{
+        return Err(AppError::Validation(
+            format!("artwork must be square, got {}x{}", width, height)));
+    }
+    if !(ARTWORK_MIN_SIZE..=ARTWORK_MAX_SIZE).contains(&width){
+        return Err(AppError::Validation(format!("artwork must be between {0}x{0} and {1}x{1}, got {2}x{2}",
+            ARTWORK_MIN_SIZE, ARTWORK_MAX_SIZE, width)));
+    }
+    match image.color(){
+        ColorType::Rgb8 | ColorType::Rgb16 => {},
+        other => return Err(AppError::Validation(
+            format!("artwork must be RGB without transparency, got {:?}", other))),
+    }
+
+    return Ok(Artwork{ image, format });
+}
+
+/// (size, extension, content type, bytes)
+type EncodedVariant = (u32, &'static str, &'static str, Vec<u8>);
+
+/// ARTWORK_SIZES up to the source's, largest first.
+fn variant_sizes(source_size: u32) -> Vec<u32>{
+    let mut sizes: Vec<u32> = ARTWORK_SIZES.iter().copied().filter(|s| *s <= source_size).collect();
+    // 1400 < source < 3000: keep the original as the largest variant.
+    if !sizes.contains(&source_size){
+        sizes.insert(0, source_size);
+    }
+    return sizes;
+}
+
+/// one encoded copy per size, largest first. Keeps the source format.
+fn resize_artwork(artwork: &Artwork) -> Result<Vec<EncodedVariant>, AppError>{
+    let source_size = artwork.image.width();
+    let mut encoded = Vec::new();
+    for size in variant_sizes(source_size){
+        let resized = if size == source_size{
+            artwork.image.to_rgb8()
+        } else {
+            artwork.image.resize_exact(size, size, FilterType::Lanczos3).to_rgb8()
+        };
+        let mut bytes = Vec::new();
+        let (ext, content_type) = match artwork.format{
+            ImageFormat::Png => {
+                resized.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+                    .map_err(|e| AppError::Internal(format!("could not encode artwork: {}", e)))?;
+                ("png", "image/png")
+            },
+            _ => {
+                JpegEncoder::new_with_quality(&mut bytes, 90).encode_image(&resized)
+                    .map_err(|e| AppError::Internal(format!("could not encode artwork: {}", e)))?;
+                ("jpg", "image/jpeg")
+            },
+        };
+        encoded.push((size, ext, content_type, bytes));
+    }
+    return Ok(encoded);
+}
+
+async fn put_public_object(key: &str, bytes: Vec<u8>, content_type: &str, s3: &S3) -> Result<(), AppError>{
+    return match s3.client.put_object()
+        .bucket(&s3.bucket)
+        .key(key)
+        .acl(ObjectCannedAcl::PublicRead)
+        .content_type(content_type)
+        .body(ByteStream::from(bytes))
+        .send()
+        .await{
+            Ok(_) => Ok(()),
+            Err(e) => {
+                log::error!("Failed to upload {} to S3. Err: {}", key, e);
+                Err(AppError::Storage("Failed to upload to S3.".to_string()))
+            }
+        };
+}
+
+#[cfg(test)]
+mod tests{
+    use {
+        super::*,
+        image::{
+            RgbImage, RgbaImage,
+        },
+    };
+
+    /// `image` saved to a temp file in `format`; returns its path.
+    fn saved(image: DynamicImage, format: ImageFormat) -> std::path::PathBuf{
+        let path = std::env::temp_dir().join(format!("artwork-{}", Uuid::new_v4()));
+        image.save_with_format(&path, format).unwrap();
+        return path;
+    }
+
+    fn check(image: DynamicImage, format: ImageFormat) -> Result<Artwork, AppError>{
+        let path = saved(image, format);
+        let checked = check_artwork(&path);
+        std::fs::remove_file(&path).unwrap();
+        return checked;
+    }
+
+    fn rgb(width: u32, height: u32) -> DynamicImage{
+        return DynamicImage::ImageRgb8(RgbImage::new(width, height));
+    }
+
+    #[test]
+    fn square_rgb_artwork_in_range_passes(){
+        for (size, format) in [(1400, ImageFormat::Jpeg), (3000, ImageFormat::Png)]{
+            let artwork = check(rgb(size, size), format).unwrap();
+            assert_eq!(artwork.image.width(), size);
+            assert_eq!(artwork.format, format);
+        }
+    }
+
+    #[test]
+    fn artwork_must_be_square_in_range_and_rgb(){
+        for image in [rgb(1400, 1500), rgb(1399, 1399), rgb(3001, 3001),
+            DynamicImage::ImageRgba8(RgbaImage::new(1400, 1400))]{
+            assert!(matches!(check(image, ImageFormat::Png), Err(AppError::Validation(_))));
+        }
+    }
+
+    #[test]
+    fn unreadable_artwork_is_a_validation_error(){
+        let path = std::env::temp_dir().join(format!("artwork-{}", Uuid::new_v4()));
+        assert!(matches!(check_artwork(&path), Err(AppError::Validation(_))));
+        for bytes in [&b"plain text"[..], &b"\x89PNG\r\n\x1a\n not really"[..]]{
+            std::fs::write(&path, bytes).unwrap();
+            assert!(matches!(check_artwork(&path), Err(AppError::Validation(_))));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn variants_skip_sizes_above_the_source(){
+        assert_eq!(variant_sizes(3000), vec![3000, 1400, 600, 300]);
+        assert_eq!(variant_sizes(1500), vec![1500, 1400, 600, 300]);
+        assert_eq!(variant_sizes(1400), vec![1400, 600, 300]);
+    }
+}
@@ -1,7 +1,17 @@
-use crate::{
-    Uuid, HttpResponse, 
-    web, ContentType,
-    RwLock,
+use {
+    crate::{
+        Uuid, HttpResponse, 
+        web, ContentType,
+        RwLock, AppError,
+    },
+    argon2::{
+        Argon2,
+        password_hash::{
+            PasswordHash, PasswordHasher, PasswordVerifier,
+            SaltString, rand_core::OsRng,
+        },
+    },
+    sqlx::PgPool,
 };
 
 #[derive(Clone, Debug)]
@@ -15,22 +25,37 @@ pub struct Authenticatee{
     pub password: String,
 }
 
-// strictly using admin password for now.
+// app_user rows only. admin_password is for bootstrapping: it works, with any username,
+// only while app_user is empty, so creating the first operator retires it.
 pub async fn generate_session_token(
     authenticatee: web::Json<Authenticatee>, 
     admin_password: web::Data<AdminPassword>,
     active_tokens: web::Data<RwLock<ActiveTokens>>,
-) -> HttpResponse{
+    pg_conn_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError>{
     let authenticatee = authenticatee.into_inner();
-    if admin_password.get_ref().0 == authenticatee.password{
+    let user = sqlx::query!(
+        r#" SELECT password_hash FROM app_user WHERE username = $1 "#, authenticatee.username
+    ).fetch_optional(pg_conn_pool.get_ref())
+    .await?;
+    let authenticated = match user{
+        Some(user) => verify_password(&authenticatee.password, &user.password_hash),
+        None => {
+            let operators = sqlx::query!(r#" SELECT COUNT(*) AS "count!" FROM app_user "#)
+                .fetch_one(pg_conn_pool.get_ref())
+                .await?;
+            operators.count == 0 && admin_password.get_ref().0 == authenticatee.password
+        },
+    };
+    if authenticated{
         let session_token = Uuid::new_v4().to_string();
         active_tokens.write().unwrap().0.push(session_token.clone());
-        return HttpResponse::Ok()
+        return Ok(HttpResponse::Ok()
             .content_type(ContentType::plaintext())
-            .body(session_token);
+            .body(session_token));
     }
     
-    return HttpResponse::Unauthorized().finish();
+    return Err(AppError::Unauthorized);
 }
 
 pub async fn is_valid_token(
@@ -44,3 +69,48 @@ pub async fn is_valid_token(
     }
     return false;
 }
+
+/// argon2id PHC string for app_user.password_hash.
+pub fn hash_password(password: &str) -> Result<String, AppError>{
+    let salt = SaltString::generate(&mut OsRng);
+    return Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AppError::Internal(format!("could not hash password: {}", e)));
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool{
+    return match PasswordHash::new(password_hash){
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(_) => false,
+    };
+}
+
+/// random password handed out by santigold-admin.
+pub fn generate_password() -> String{
+    return format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+}
+
+pub async fn create_user(username: &str, password: &str, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    let password_hash = hash_password(password)?;
+    sqlx::query!(r#" INSERT INTO app_user (username, password_hash) VALUES ($1, $2) "#,
+        username, password_hash
+    ).execute(pg_conn_pool)
+    .await?;
+    return Ok(());
+}
+
+/// replace a user's password. This does not end sessions: tokens issued before the rotation,
+/// including ones issued to whoever had the old password, stay valid until the server restarts.
+pub async fn rotate_password(username: &str, password: &str, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    let password_hash = hash_password(password)?;
+    let rotated = sqlx::query!(
+        r#" UPDATE app_user SET password_hash = $1, rotated_at = NOW() WHERE username = $2 "#,
+        password_hash, username
+    ).execute(pg_conn_pool)
+    .await?;
+    if rotated.rows_affected() == 0{
+        return Err(AppError::NotFound(format!("no user '{}'", username)));
+    }
+    return Ok(());
+}
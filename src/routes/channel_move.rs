@@ -0,0 +1,136 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpResponse,
+        ActiveTokens, is_valid_token,
+        AppError, parse_uuid,
+        FeedCache, refresh_channel_feed,
+    },
+    validator::Validate,
+    serde::{
+        Serialize, Deserialize,
+    },
+    chrono::{
+        DateTime, Utc,
+    },
+    sqlx::PgPool,
+};
+
+/// how long itunes:new-feed-url is announced before feed URLs start redirecting.
+pub const DEFAULT_MOVE_GRACE_DAYS: i32 = 30;
+
+fn default_grace_days() -> i32{
+    return DEFAULT_MOVE_GRACE_DAYS;
+}
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
+pub struct ChannelMoveForm{
+    pub session_token: String,
+    pub channel_id: String,
+    /// feed URL on the new host. None cancels a pending or completed move.
+    #[serde(default)]
+    #[validate(url, length(max = 2048))]
+    pub new_feed_url: Option<String>,
+    #[serde(default = "default_grace_days")]
+    #[validate(range(min = 0, max = 365))]
+    pub grace_days: i32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ChannelMoveStatus{
+    pub channel_id: String,
+    pub moved_to: Option<String>,
+    pub redirect_after: Option<DateTime<Utc>>,
+}
+
+/// a channel that is leaving this server.
+#[derive(Clone, Debug)]
+pub struct ChannelMove{
+    pub moved_to: String,
+    pub redirect_after: DateTime<Utc>,
+}
+
+impl ChannelMove{
+    /// None while podcatchers are still being told about the new feed.
+    pub fn redirect(&self) -> Option<&str>{
+        if Utc::now() >= self.redirect_after{
+            return Some(&self.moved_to);
+        }
+        return None;
+    }
+}
+
+/// POST mark a channel as moved to another host, or cancel the move. Every change is
+/// written to channel_move_audit.
+pub async fn move_channel(
+    form: web::Json<ChannelMoveForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    form.validate()?;
+    let ch_external_id = parse_uuid("channel_id", &form.channel_id)?;
+
+    let mut tx = pg_conn_pool.begin().await?;
+    let previous = match sqlx::query!(
+        r#" SELECT moved_to FROM channel WHERE external_id = $1 FOR UPDATE "#, ch_external_id
+    ).fetch_optional(&mut tx)
+    .await?{
+        Some(ch) => ch.moved_to,
+        None => return Err(AppError::NotFound("channel does not exist".to_string())),
+    };
+
+    let status = match &form.new_feed_url{
+        Some(new_feed_url) => {
+            let moved = sqlx::query!(r#"
+                UPDATE channel SET moved_to = $1, itunes_new_feed_url = $1,
+                redirect_after = NOW() + make_interval(days => $2)
+                WHERE external_id = $3
+                RETURNING redirect_after
+                "#, new_feed_url, form.grace_days, ch_external_id
+            ).fetch_one(&mut tx)
+            .await?;
+            sqlx::query!(r#"
+                INSERT INTO channel_move_audit (channel_id, action, moved_to, redirect_after, previous_moved_to)
+                VALUES ($1, 'moved', $2, $3, $4)
+                "#, ch_external_id, new_feed_url, moved.redirect_after, previous
+            ).execute(&mut tx)
+            .await?;
+            ChannelMoveStatus{
+                channel_id: ch_external_id.to_string(),
+                moved_to: Some(new_feed_url.clone()),
+                redirect_after: moved.redirect_after,
+            }
+        },
+        None => {
+            if previous.is_none(){
+                return Err(AppError::Validation("channel has not been moved".to_string()));
+            }
+            sqlx::query!(r#"
+                UPDATE channel SET moved_to = NULL, redirect_after = NULL, itunes_new_feed_url = ''
+                WHERE external_id = $1
+                "#, ch_external_id
+            ).execute(&mut tx)
+            .await?;
+            sqlx::query!(r#"
+                INSERT INTO channel_move_audit (channel_id, action, previous_moved_to)
+                VALUES ($1, 'cancelled', $2)
+                "#, ch_external_id, previous
+            ).execute(&mut tx)
+            .await?;
+            ChannelMoveStatus{
+                channel_id: ch_external_id.to_string(),
+                moved_to: None,
+                redirect_after: None,
+            }
+        },
+    };
+    tx.commit().await?;
+
+    refresh_channel_feed(&ch_external_id.to_string(), &pg_conn_pool, &feed_cache).await?;
+
+    return Ok(HttpResponse::Ok().json(status));
+}
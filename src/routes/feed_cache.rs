@@ -0,0 +1,619 @@
+use {
+    crate::{
+        web, HttpRequest, HttpResponse,
+        ContentType, AppError,
+        Arc, PgPool, Uuid,
+        FeedFormat, FeedBuffers, ChannelMove,
+        refresh_channel_feed,
+    },
+    sqlx::postgres::PgListener,
+    arc_swap::ArcSwap,
+    futures::StreamExt,
+    actix_web::http::header::{
+        self, Header, HttpDate,
+        IfNoneMatch, IfModifiedSince,
+    },
+    chrono::{
+        DateTime, Utc,
+    },
+    sha2::{
+        Digest, Sha256,
+    },
+    std::{
+        io::Write,
+        time::SystemTime,
+        collections::{
+            BTreeMap, HashMap,
+        },
+    },
+};
+
+/// LISTEN/NOTIFY channel carrying external_ids of feeds to re-render.
+pub const FEED_REBUILD_CHANNEL: &str = "feed_rebuild";
+
+/// ask every running server to re-render a channel's feed.
+pub async fn request_feed_rebuild(ch_external_id: &Uuid, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    sqlx::query!(r#" SELECT 1 AS "sent!" FROM (SELECT pg_notify($1, $2)) notify "#,
+        FEED_REBUILD_CHANNEL, ch_external_id.to_string()
+    ).fetch_one(pg_conn_pool)
+    .await?;
+    return Ok(());
+}
+
+/// a channel's rendered feeds.
+#[derive(Clone, Debug)]
+pub struct ChannelFeeds{
+    pub external_id: Uuid,
+    pub slug: String,
+    pub custom_path: Option<String>,
+    /// served per subscriber only, see subscriber_feed().
+    pub private: bool,
+    pub moved: Option<ChannelMove>,
+    pub feeds: FeedBuffers,
+    /// named variants' feeds, by name.
+    pub variants: BTreeMap<String, FeedBuffers>,
+}
+
+impl ChannelFeeds{
+    /// where to send podcatchers once a move's grace period is over.
+    pub fn redirect(&self) -> Option<&str>{
+        return self.moved.as_ref().and_then(|moved| moved.redirect());
+    }
+
+    pub fn get(&self, format: FeedFormat) -> &CachedFeed{
+        return self.feeds.get(format);
+    }
+
+    /// a variant's feed, None if the channel has no variant by that name.
+    pub fn get_variant(&self, name: &str, format: FeedFormat) -> Option<&CachedFeed>{
+        return self.variants.get(name).map(|feeds| feeds.get(format));
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Snapshot{
+    by_id: HashMap<Uuid, Arc<ChannelFeeds>>,
+    by_slug: HashMap<String, Uuid>,
+    by_custom_path: HashMap<String, Uuid>,
+}
+
+/// Rendered feeds keyed by channel id, slug and custom path. Readers load the current snapshot without
+/// locking; writers copy it, change the copy and swap it in, so a rebuild never blocks a poll.
+#[derive(Debug)]
+pub struct FeedCache{
+    snapshot: ArcSwap<Snapshot>,
+}
+
+impl Default for FeedCache{
+    fn default() -> Self{
+        return FeedCache{
+            snapshot: ArcSwap::from_pointee(Snapshot::default()),
+        };
+    }
+}
+
+impl FeedCache{
+    /// channels rendered at once during warm_up().
+    const WARMUP_CONCURRENCY: usize = 4;
+
+    /// render every channel in the background so startup doesn't wait on it. Until a channel
+    /// is warm its feed is rendered on demand; one that fails is logged and skipped.
+    pub fn warm_up(self: Arc<Self>, pg_conn_pool: PgPool){
+        tokio::spawn(async move {
+            let channels = match sqlx::query!(r#"SELECT external_id FROM channel"#)
+                .fetch_all(&pg_conn_pool)
+                .await{
+                Ok(channels) => channels,
+                Err(e) => {
+                    log::error!("feed warmup: could not list channels, feeds will render on demand. Err: {}", e);
+                    return;
+                },
+            };
+            let total = channels.len();
+            futures::stream::iter(channels)
+                .for_each_concurrent(Self::WARMUP_CONCURRENCY, |ch|{
+                    let feed_cache = self.clone();
+                    let pg_conn_pool = pg_conn_pool.clone();
+                    async move {
+                        let ch_external_id = ch.external_id.to_string();
+                        if let Err(e) = refresh_channel_feed(&ch_external_id, &pg_conn_pool, &feed_cache).await{
+                            log::error!("feed warmup: skipping channel {}. Err: {}", ch_external_id, e);
+                        }
+                    }
+                })
+                .await;
+            log::info!("feed warmup: {} of {} channels cached", self.len(), total);
+        });
+    }
+
+    /// re-render channels named in `feed_rebuild` notifications, sent by santigold-admin
+    /// through request_feed_rebuild(). Notifications sent while disconnected are lost.
+    pub fn listen_for_rebuilds(self: Arc<Self>, pg_conn_pool: PgPool){
+        tokio::spawn(async move {
+            let mut listener = loop{
+                match PgListener::connect_with(&pg_conn_pool).await{
+                    Ok(mut listener) => match listener.listen(FEED_REBUILD_CHANNEL).await{
+                        Ok(()) => break listener,
+                        Err(e) => log::error!("feed rebuild listener: could not LISTEN. Err: {}", e),
+                    },
+                    Err(e) => log::error!("feed rebuild listener: could not connect. Err: {}", e),
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            };
+            loop{
+                // recv() reconnects on its own after a lost connection.
+                let notification = match listener.recv().await{
+                    Ok(notification) => notification,
+                    Err(e) => {
+                        log::error!("feed rebuild listener: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                        continue;
+                    },
+                };
+                let ch_external_id = notification.payload();
+                match refresh_channel_feed(ch_external_id, &pg_conn_pool, &self).await{
+                    Ok(()) => log::info!("feed rebuild listener: rebuilt {}", ch_external_id),
+                    Err(e) => log::error!("feed rebuild listener: could not rebuild {}. Err: {}", ch_external_id, e),
+                }
+            }
+        });
+    }
+
+    pub fn get(&self, external_id: &Uuid) -> Option<Arc<ChannelFeeds>>{
+        return self.snapshot.load().by_id.get(external_id).cloned();
+    }
+
+    pub fn get_by_slug(&self, slug: &str) -> Option<Arc<ChannelFeeds>>{
+        let snapshot = self.snapshot.load();
+        return snapshot.by_slug.get(slug)
+            .and_then(|external_id| snapshot.by_id.get(external_id))
+            .cloned();
+    }
+
+    pub fn get_by_custom_path(&self, path: &str) -> Option<Arc<ChannelFeeds>>{
+        let snapshot = self.snapshot.load();
+        return snapshot.by_custom_path.get(path)
+            .and_then(|external_id| snapshot.by_id.get(external_id))
+            .cloned();
+    }
+
+    /// cache miss: render the channel from the DB if the slug belongs to one.
+    pub async fn load_by_slug(&self, slug: &str, pg_conn_pool: &PgPool)
+    -> Result<Option<Arc<ChannelFeeds>>, AppError>{
+        let ch = sqlx::query!(
+            r#" SELECT external_id FROM channel WHERE slug = $1 "#, slug
+        ).fetch_optional(pg_conn_pool)
+        .await?;
+        return self.load(ch.map(|ch| ch.external_id), pg_conn_pool).await;
+    }
+
+    /// cache miss on a custom path.
+    pub async fn load_by_custom_path(&self, path: &str, pg_conn_pool: &PgPool)
+    -> Result<Option<Arc<ChannelFeeds>>, AppError>{
+        let ch = sqlx::query!(
+            r#" SELECT external_id FROM channel WHERE custom_path = $1 "#, path
+        ).fetch_optional(pg_conn_pool)
+        .await?;
+        return self.load(ch.map(|ch| ch.external_id), pg_conn_pool).await;
+    }
+
+    async fn load(&self, ch: Option<Uuid>, pg_conn_pool: &PgPool)
+    -> Result<Option<Arc<ChannelFeeds>>, AppError>{
+        let external_id = match ch{
+            Some(external_id) => external_id,
+            None => return Ok(None),
+        };
+        refresh_channel_feed(&external_id.to_string(), pg_conn_pool, self).await?;
+        return Ok(self.get(&external_id));
+    }
+
+    /// add or replace a channel. Formats whose content didn't change keep their Last-Modified,
+    /// and a slug or custom path the channel no longer has is dropped.
+    pub fn insert(&self, entry: ChannelFeeds){
+        self.snapshot.rcu(|current|{
+            let mut next = Snapshot::clone(current);
+            let mut entry = entry.clone();
+            if let Some(previous) = next.by_id.get(&entry.external_id){
+                entry.feeds = entry.feeds.keep_last_modified(&previous.feeds);
+                for (name, feeds) in entry.variants.iter_mut(){
+                    if let Some(previous) = previous.variants.get(name){
+                        *feeds = feeds.clone().keep_last_modified(previous);
+                    }
+                }
+                if previous.slug != entry.slug{
+                    next.by_slug.remove(&previous.slug);
+                }
+                if let Some(path) = &previous.custom_path{
+                    next.by_custom_path.remove(path);
+                }
+            }
+            next.by_slug.insert(entry.slug.clone(), entry.external_id);
+            if let Some(path) = &entry.custom_path{
+                next.by_custom_path.insert(path.clone(), entry.external_id);
+            }
+            next.by_id.insert(entry.external_id, Arc::new(entry));
+            return next;
+        });
+    }
+
+    pub fn remove(&self, external_id: &Uuid){
+        self.snapshot.rcu(|current|{
+            let mut next = Snapshot::clone(current);
+            if let Some(previous) = next.by_id.remove(external_id){
+                next.by_slug.remove(&previous.slug);
+                if let Some(path) = &previous.custom_path{
+                    next.by_custom_path.remove(path);
+                }
+            }
+            return next;
+        });
+    }
+
+    pub fn len(&self) -> usize{
+        return self.snapshot.load().by_id.len();
+    }
+
+    pub fn is_empty(&self) -> bool{
+        return self.len() == 0;
+    }
+}
+
+/// one rendered feed, with everything needed to answer a poll without touching the body:
+/// content hash, modification time and pre-compressed copies.
+#[derive(Clone, Debug, Default)]
+pub struct CachedFeed{
+    pub body: web::Bytes,
+    pub gzip: web::Bytes,
+    pub brotli: web::Bytes,
+    /// hex sha256 of body, unquoted.
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Encoding{
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl CachedFeed{
+    /// hash and compress off the async runtime; brotli at max quality is slow on long feeds.
+    pub async fn build(body: String) -> Result<Self, AppError>{
+        return web::block(move || -> Result<Self, AppError>{
+            let etag = format!("{:x}", Sha256::digest(body.as_bytes()));
+
+            let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+            gzip.write_all(body.as_bytes())
+                .map_err(|e| AppError::Internal(format!("gzip failed: {}", e)))?;
+            let gzip = gzip.finish()
+                .map_err(|e| AppError::Internal(format!("gzip failed: {}", e)))?;
+
+            let mut brotli = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut brotli, 4096, 11, 22);
+                writer.write_all(body.as_bytes())
+                    .map_err(|e| AppError::Internal(format!("brotli failed: {}", e)))?;
+            }
+
+            // whole seconds, HTTP dates have no finer resolution.
+            let now = DateTime::from_timestamp(Utc::now().timestamp(), 0).unwrap_or_default();
+            return Ok(CachedFeed{
+                body: body.into(),
+                gzip: gzip.into(),
+                brotli: brotli.into(),
+                etag,
+                last_modified: now,
+            });
+        }).await
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    }
+
+    /// copy with `from` replaced by `to` in the body, for per-request variants of a cached feed.
+    /// Compressed with fast gzip only, brotli at max quality is too slow to do per request.
+    pub fn personalize(&self, from: &str, to: &str) -> Result<Self, AppError>{
+        let body = String::from_utf8_lossy(&self.body).replace(from, to);
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(body.as_bytes())
+            .map_err(|e| AppError::Internal(format!("gzip failed: {}", e)))?;
+        let gzip = gzip.finish()
+            .map_err(|e| AppError::Internal(format!("gzip failed: {}", e)))?;
+        return Ok(CachedFeed{
+            etag: format!("{:x}", Sha256::digest(body.as_bytes())),
+            body: body.into(),
+            gzip: gzip.into(),
+            brotli: web::Bytes::new(),
+            last_modified: self.last_modified,
+        });
+    }
+
+    /// regenerating an unchanged feed must not look like an update to podcatchers.
+    pub fn keep_last_modified(mut self, previous: &CachedFeed) -> Self{
+        if self.etag == previous.etag{
+            self.last_modified = previous.last_modified;
+        }
+        return self;
+    }
+
+    /// 304 for a matching If-None-Match / If-Modified-Since, otherwise the body in the best
+    /// encoding the client accepts.
+    pub fn respond(&self, req: &HttpRequest, content_type: ContentType) -> HttpResponse{
+        let encoding = preferred_encoding(req, !self.brotli.is_empty());
+        let (etag, body) = match encoding{
+            Encoding::Brotli => (format!("\"{}-br\"", self.etag), &self.brotli),
+            Encoding::Gzip => (format!("\"{}-gzip\"", self.etag), &self.gzip),
+            Encoding::Identity => (format!("\"{}\"", self.etag), &self.body),
+        };
+        let last_modified = HttpDate::from(SystemTime::from(self.last_modified));
+
+        let fresh = self.is_fresh(req);
+        let mut response = if fresh{
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        response
+            .insert_header((header::ETAG, etag))
+            .insert_header((header::LAST_MODIFIED, last_modified.to_string()))
+            .insert_header((header::VARY, "Accept, Accept-Encoding"));
+        if fresh{
+            return response.finish();
+        }
+        if encoding != Encoding::Identity{
+            response.insert_header((header::CONTENT_ENCODING, match encoding{
+                Encoding::Brotli => "br",
+                _ => "gzip",
+            }));
+        }
+        return response
+            .content_type(content_type)
+            .body(body.clone());
+    }
+
+    /// If-None-Match wins over If-Modified-Since (RFC 9110 13.2.2).
+    fn is_fresh(&self, req: &HttpRequest) -> bool{
+        if req.headers().contains_key(header::IF_NONE_MATCH){
+            return match IfNoneMatch::parse(req){
+                Ok(IfNoneMatch::Any) => true,
+                Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag|{
+                    let tag = tag.tag();
+                    let tag = tag.strip_suffix("-br")
+                        .or_else(|| tag.strip_suffix("-gzip"))
+                        .unwrap_or(tag);
+                    tag == self.etag
+                }),
+                Err(_) => false,
+            };
+        }
+        return match IfModifiedSince::parse(req){
+            Ok(IfModifiedSince(since)) => {
+                let since = DateTime::<Utc>::from(SystemTime::from(since));
+                self.last_modified <= since
+            },
+            Err(_) => false,
+        };
+    }
+}
+
+/// br (if there is a brotli copy) > gzip > identity among what Accept-Encoding allows (q > 0).
+fn preferred_encoding(req: &HttpRequest, brotli_available: bool) -> Encoding{
+    let accept_encoding = match req.headers().get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok()){
+        Some(value) => value.to_lowercase(),
+        None => return Encoding::Identity,
+    };
+    let mut brotli = None;
+    let mut gzip = None;
+    let mut any = None;
+    for coding in accept_encoding.split(','){
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let accepted = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .all(|q| q.trim().parse::<f32>().map(|q| q > 0.0).unwrap_or(false));
+        match name{
+            "br" => brotli = Some(accepted),
+            "gzip" | "x-gzip" => gzip = Some(accepted),
+            "*" => any = Some(accepted),
+            _ => {},
+        }
+    }
+    if brotli_available && brotli.or(any).unwrap_or(false){
+        return Encoding::Brotli;
+    }
+    if gzip.or(any).unwrap_or(false){
+        return Encoding::Gzip;
+    }
+    return Encoding::Identity;
+}
+
+#[cfg(test)]
+mod tests{
+    use {
+        super::*,
+        actix_web::test::TestRequest,
+    };
+
+    fn cached(body: &str) -> CachedFeed{
+        return CachedFeed{
+            body: web::Bytes::from(body.to_string()),
+            gzip: web::Bytes::from_static(b"gzip"),
+            brotli: web::Bytes::from_static(b"brotli"),
+            etag: format!("{:x}", Sha256::digest(body.as_bytes())),
+            last_modified: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        };
+    }
+
+    fn header_value(response: &HttpResponse, name: header::HeaderName) -> Option<String>{
+        return response.headers().get(name).map(|value| value.to_str().unwrap().to_string());
+    }
+
+    #[actix_web::test]
+    async fn build_hashes_and_compresses_the_body(){
+        let feed = CachedFeed::build("<rss/>".to_string()).await.unwrap();
+        assert_eq!(feed.etag, format!("{:x}", Sha256::digest(b"<rss/>")));
+        assert_eq!(feed.last_modified.timestamp_subsec_nanos(), 0);
+
+        let mut gunzipped = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&feed.gzip[..]), &mut gunzipped).unwrap();
+        assert_eq!(gunzipped, "<rss/>");
+        let mut unbrotlied = String::new();
+        std::io::Read::read_to_string(&mut brotli::Decompressor::new(&feed.brotli[..], 4096), &mut unbrotlied).unwrap();
+        assert_eq!(unbrotlied, "<rss/>");
+    }
+
+    #[test]
+    fn encoding_follows_accept_encoding(){
+        for (accept_encoding, brotli_available, expected) in [
+            (None, true, Encoding::Identity),
+            (Some("gzip, deflate, br"), true, Encoding::Brotli),
+            (Some("gzip, deflate, br"), false, Encoding::Gzip),
+            (Some("br;q=0, gzip"), true, Encoding::Gzip),
+            (Some("GZIP;q=0.5"), true, Encoding::Gzip),
+            (Some("x-gzip"), true, Encoding::Gzip),
+            (Some("gzip;q=0"), true, Encoding::Identity),
+            (Some("*"), true, Encoding::Brotli),
+            (Some("*;q=0"), true, Encoding::Identity),
+            (Some("deflate"), true, Encoding::Identity),
+        ]{
+            let mut req = TestRequest::default();
+            if let Some(accept_encoding) = accept_encoding{
+                req = req.insert_header((header::ACCEPT_ENCODING, accept_encoding));
+            }
+            assert_eq!(preferred_encoding(&req.to_http_request(), brotli_available), expected, "{:?}", accept_encoding);
+        }
+    }
+
+    #[test]
+    fn responses_carry_validators_and_the_chosen_encoding(){
+        let feed = cached("<rss/>");
+        let response = feed.respond(&TestRequest::default().to_http_request(), ContentType::xml());
+        assert_eq!(response.status(), 200);
+        assert_eq!(header_value(&response, header::ETAG), Some(format!("\"{}\"", feed.etag)));
+        assert_eq!(header_value(&response, header::LAST_MODIFIED).as_deref(), Some("Tue, 14 Nov 2023 22:13:20 GMT"));
+        assert_eq!(header_value(&response, header::CONTENT_ENCODING), None);
+
+        let response = feed.respond(&TestRequest::default()
+            .insert_header((header::ACCEPT_ENCODING, "gzip, br"))
+            .to_http_request(), ContentType::xml());
+        assert_eq!(header_value(&response, header::ETAG), Some(format!("\"{}-br\"", feed.etag)));
+        assert_eq!(header_value(&response, header::CONTENT_ENCODING).as_deref(), Some("br"));
+        assert_eq!(header_value(&response, header::VARY).as_deref(), Some("Accept, Accept-Encoding"));
+    }
+
+    #[test]
+    fn conditional_requests_get_304(){
+        let feed = cached("<rss/>");
+        let status = |name: header::HeaderName, value: String|{
+            let req = TestRequest::default().insert_header((name, value)).to_http_request();
+            return feed.respond(&req, ContentType::xml()).status().as_u16();
+        };
+        // any encoding's tag matches, the content is the same.
+        assert_eq!(status(header::IF_NONE_MATCH, format!("\"{}\"", feed.etag)), 304);
+        assert_eq!(status(header::IF_NONE_MATCH, format!("\"{}-gzip\"", feed.etag)), 304);
+        assert_eq!(status(header::IF_NONE_MATCH, format!("\"other\", \"{}-br\"", feed.etag)), 304);
+        assert_eq!(status(header::IF_NONE_MATCH, "*".to_string()), 304);
+        assert_eq!(status(header::IF_NONE_MATCH, "\"other\"".to_string()), 200);
+
+        assert_eq!(status(header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:20 GMT".to_string()), 304);
+        assert_eq!(status(header::IF_MODIFIED_SINCE, "Wed, 15 Nov 2023 00:00:00 GMT".to_string()), 304);
+        assert_eq!(status(header::IF_MODIFIED_SINCE, "Tue, 14 Nov 2023 22:13:19 GMT".to_string()), 200);
+        assert_eq!(status(header::IF_MODIFIED_SINCE, "yesterday".to_string()), 200);
+
+        // If-None-Match wins when both are sent.
+        let req = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .insert_header((header::IF_MODIFIED_SINCE, "Wed, 15 Nov 2023 00:00:00 GMT"))
+            .to_http_request();
+        assert_eq!(feed.respond(&req, ContentType::xml()).status(), 200);
+    }
+
+    #[test]
+    fn unchanged_content_keeps_its_last_modified(){
+        let previous = cached("<rss/>");
+        let mut rebuilt = cached("<rss/>");
+        rebuilt.last_modified = DateTime::from_timestamp(1_800_000_000, 0).unwrap();
+        assert_eq!(rebuilt.keep_last_modified(&previous).last_modified, previous.last_modified);
+
+        let mut changed = cached("<rss><item/></rss>");
+        changed.last_modified = DateTime::from_timestamp(1_800_000_000, 0).unwrap();
+        assert_eq!(changed.keep_last_modified(&previous).last_modified.timestamp(), 1_800_000_000);
+    }
+
+    fn channel(external_id: Uuid, slug: &str, custom_path: Option<&str>, rss: &str) -> ChannelFeeds{
+        return ChannelFeeds{
+            external_id,
+            slug: slug.to_string(),
+            custom_path: custom_path.map(str::to_string),
+            private: false,
+            moved: None,
+            feeds: FeedBuffers{ rss: cached(rss), ..Default::default() },
+            variants: BTreeMap::new(),
+        };
+    }
+
+    #[test]
+    fn channels_are_found_by_id_slug_and_custom_path(){
+        let feed_cache = FeedCache::default();
+        assert!(feed_cache.is_empty());
+        let (show, other) = (Uuid::new_v4(), Uuid::new_v4());
+        feed_cache.insert(channel(show, "show", Some("feeds/show"), "<rss/>"));
+        feed_cache.insert(channel(other, "other", None, "<rss/>"));
+
+        assert_eq!(feed_cache.len(), 2);
+        assert_eq!(feed_cache.get(&show).unwrap().slug, "show");
+        assert_eq!(feed_cache.get_by_slug("show").unwrap().external_id, show);
+        assert_eq!(feed_cache.get_by_slug("other").unwrap().external_id, other);
+        assert_eq!(feed_cache.get_by_custom_path("feeds/show").unwrap().external_id, show);
+        assert!(feed_cache.get_by_slug("missing").is_none());
+        assert!(feed_cache.get_by_custom_path("feeds/other").is_none());
+
+        feed_cache.remove(&show);
+        assert_eq!(feed_cache.len(), 1);
+        assert!(feed_cache.get(&show).is_none());
+        assert!(feed_cache.get_by_slug("show").is_none());
+        assert!(feed_cache.get_by_custom_path("feeds/show").is_none());
+        assert!(feed_cache.get_by_slug("other").is_some());
+    }
+
+    #[test]
+    fn reinserting_replaces_the_snapshot_and_drops_old_keys(){
+        let feed_cache = FeedCache::default();
+        let show = Uuid::new_v4();
+        feed_cache.insert(channel(show, "show", Some("feeds/show"), "<rss/>"));
+        let before = feed_cache.get_by_slug("show").unwrap();
+
+        let mut renamed = channel(show, "new-show", None, "<rss/>");
+        renamed.feeds.rss.last_modified = DateTime::from_timestamp(1_800_000_000, 0).unwrap();
+        feed_cache.insert(renamed);
+
+        assert_eq!(feed_cache.len(), 1);
+        assert!(feed_cache.get_by_slug("show").is_none());
+        assert!(feed_cache.get_by_custom_path("feeds/show").is_none());
+        let after = feed_cache.get_by_slug("new-show").unwrap();
+        // same content, so podcatchers don't see an update.
+        assert_eq!(after.feeds.rss.last_modified, before.feeds.rss.last_modified);
+        // readers holding the old entry keep it unchanged.
+        assert_eq!(before.slug, "show");
+        assert_eq!(before.custom_path.as_deref(), Some("feeds/show"));
+
+        let mut changed = channel(show, "new-show", None, "<rss><item/></rss>");
+        changed.feeds.rss.last_modified = DateTime::from_timestamp(1_800_000_000, 0).unwrap();
+        feed_cache.insert(changed);
+        assert_eq!(feed_cache.get(&show).unwrap().feeds.rss.last_modified.timestamp(), 1_800_000_000);
+    }
+
+    #[test]
+    fn variants_are_looked_up_by_name_and_format(){
+        let mut show = channel(Uuid::new_v4(), "show", None, "<rss/>");
+        show.variants.insert("ad-free".to_string(), FeedBuffers{
+            rss: cached("<rss>ad-free</rss>"),
+            atom: cached("<feed>ad-free</feed>"),
+            ..Default::default()
+        });
+        assert_eq!(&show.get_variant("ad-free", FeedFormat::Rss).unwrap().body[..], b"<rss>ad-free</rss>");
+        assert_eq!(&show.get_variant("ad-free", FeedFormat::Atom).unwrap().body[..], b"<feed>ad-free</feed>");
+        assert!(show.get_variant("bonus", FeedFormat::Rss).is_none());
+        assert_eq!(&show.get(FeedFormat::Rss).body[..], b"<rss/>");
+    }
+}
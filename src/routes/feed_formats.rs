@@ -0,0 +1,341 @@
+use {
+    crate::{
+        HttpRequest, ContentType,
+        AppError, Channel, Item, CachedFeed,
+        xml_escape, parse_pub_date,
+    },
+    actix_web::http::header::{
+        Accept, Header,
+    },
+    serde::Serialize,
+    chrono::Utc,
+};
+
+/// output format of /podcast/{ch_title}.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FeedFormat{
+    Rss,
+    Atom,
+    Json,
+}
+
+impl FeedFormat{
+    /// a `.atom`/`.json`/`.rss` suffix on the slug wins, then the Accept header. RSS otherwise.
+    pub fn from_request<'a>(ch_slug: &'a str, req: &HttpRequest) -> (&'a str, Self){
+        for (suffix, format) in [(".atom", Self::Atom), (".json", Self::Json), (".rss", Self::Rss)]{
+            if let Some(slug) = ch_slug.strip_suffix(suffix){
+                return (slug, format);
+            }
+        }
+        return (ch_slug, Self::from_accept(req));
+    }
+
+    /// first format the Accept header ranks, RSS if none.
+    pub fn from_accept(req: &HttpRequest) -> Self{
+        let accept = match Accept::parse(req){
+            Ok(accept) => accept,
+            Err(_) => return Self::Rss,
+        };
+        for mime in accept.ranked(){
+            match mime.essence_str(){
+                "application/atom+xml" => return Self::Atom,
+                "application/feed+json" | "application/json" => return Self::Json,
+                "application/rss+xml" | "application/xml" | "text/xml" => return Self::Rss,
+                _ => {},
+            }
+        }
+        return Self::Rss;
+    }
+
+    pub fn content_type(&self) -> ContentType{
+        return match self{
+            Self::Rss => ContentType::xml(),
+            Self::Atom => ContentType("application/atom+xml; charset=utf-8".parse().unwrap()),
+            Self::Json => ContentType("application/feed+json; charset=utf-8".parse().unwrap()),
+        };
+    }
+}
+
+/// one channel rendered in every supported format.
+#[derive(Clone, Debug, Default)]
+pub struct FeedBuffers{
+    pub rss: CachedFeed,
+    pub atom: CachedFeed,
+    pub json: CachedFeed,
+}
+
+impl FeedBuffers{
+    pub fn get(&self, format: FeedFormat) -> &CachedFeed{
+        return match format{
+            FeedFormat::Rss => &self.rss,
+            FeedFormat::Atom => &self.atom,
+            FeedFormat::Json => &self.json,
+        };
+    }
+
+    /// CachedFeed::keep_last_modified() for each format.
+    pub fn keep_last_modified(self, previous: &FeedBuffers) -> Self{
+        return FeedBuffers{
+            rss: self.rss.keep_last_modified(&previous.rss),
+            atom: self.atom.keep_last_modified(&previous.atom),
+            json: self.json.keep_last_modified(&previous.json),
+        };
+    }
+}
+
+/// pub_date as RFC 3339, None if unparseable.
+fn rfc3339(pub_date: &str) -> Option<String>{
+    return parse_pub_date(pub_date).map(|date| date.to_rfc3339());
+}
+
+/// newest item date, else the channel's last build date, else now. Atom requires one.
+fn feed_updated(channel: &Channel, items: &[Item]) -> String{
+    return items.iter()
+        .filter_map(|item| parse_pub_date(&item.pub_date))
+        .max()
+        .or_else(|| parse_pub_date(&channel.last_build_date))
+        .map(|date| date.to_rfc3339())
+        .unwrap_or_else(|| Utc::now().to_rfc3339());
+}
+
+/// imported GUIDs are kept when they are already IRIs, Atom ids must be.
+fn atom_id(item: &Item) -> String{
+    return match &item.guid{
+        Some(guid) if guid.contains(':') && !guid.contains(char::is_whitespace) => guid.clone(),
+        _ => format!("urn:uuid:{}", item.id),
+    };
+}
+
+/// Atom 1.0 (RFC 4287). Items in the same order as the RSS feed.
+pub fn render_atom(channel: &Channel, items: &[Item]) -> String{
+    let updated = feed_updated(channel, items);
+    let mut atom = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xml:lang="{}">
+    <id>urn:uuid:{}</id>
+    <title>{}</title>
+    <subtitle>{}</subtitle>
+    <updated>{}</updated>
+    <link rel="alternate" href="{}"/>
+    <author>
+        <name>{}</name>
+        <email>{}</email>
+    </author>
+    <logo>{}</logo>
+    <generator uri="https://github.com/L19579/L19_Santigold">L19_Santigold</generator>
+"#, xml_escape(&channel.language), channel.external_id, xml_escape(&channel.title),
+    xml_escape(&channel.description), updated, xml_escape(&channel.c_link),
+    xml_escape(&channel.itunes_owner_name), xml_escape(&channel.itunes_owner_email),
+    xml_escape(&channel.image_url));
+
+    for c in channel.category_pairs(){
+        atom.push_str(&format!("    <category term=\"{}\"/>\n", xml_escape(&c.category)));
+    }
+
+    for item in items{
+        let published = rfc3339(&item.pub_date);
+        let published_xml = match &published{
+            Some(date) => format!("<published>{}</published>", date),
+            None => String::new(),
+        };
+        let category = match item.category.is_empty(){
+            true => String::new(),
+            false => format!(r#"<category term="{}"/>"#, xml_escape(&item.category)),
+        };
+        atom.push_str(&format!(r#"    <entry>
+        <id>{}</id>
+        <title>{}</title>
+        <updated>{}</updated>
+        {}
+        <author><name>{}</name></author>
+        <link rel="alternate" href="{}"/>
+        <link rel="enclosure" href="{}" type="{}" length="{}"/>
+        {}
+        <summary>{}</summary>
+        <content type="html">{}</content>
+    </entry>
+"#, xml_escape(&atom_id(item)), xml_escape(&item.title), published.as_deref().unwrap_or(&updated), published_xml,
+        xml_escape(&item.author), xml_escape(&item.i_link), xml_escape(&item.enclosure_url),
+        xml_escape(&item.enclosure_type), xml_escape(&item.enclosure_length), category,
+        xml_escape(&item.description), xml_escape(&item.content_encoded)));
+    }
+
+    atom.push_str("</feed>\n");
+    return atom;
+}
+
+#[derive(Serialize)]
+struct JsonFeed<'a>{
+    version: &'static str,
+    title: &'a str,
+    home_page_url: &'a str,
+    description: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    icon: &'a str,
+    language: &'a str,
+    authors: Vec<JsonFeedAuthor<'a>>,
+    items: Vec<JsonFeedItem<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor<'a>{
+    name: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem<'a>{
+    id: &'a str,
+    url: &'a str,
+    title: &'a str,
+    summary: &'a str,
+    content_html: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_published: Option<String>,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    image: &'a str,
+    authors: Vec<JsonFeedAuthor<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<&'a str>,
+    attachments: Vec<JsonFeedAttachment<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAttachment<'a>{
+    url: &'a str,
+    mime_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size_in_bytes: Option<u64>,
+}
+
+/// JSON Feed 1.1 (https://www.jsonfeed.org/version/1.1/).
+pub fn render_json_feed(channel: &Channel, items: &[Item]) -> Result<String, AppError>{
+    let feed = JsonFeed{
+        version: "https://jsonfeed.org/version/1.1",
+        title: &channel.title,
+        home_page_url: &channel.c_link,
+        description: &channel.description,
+        icon: &channel.image_url,
+        language: &channel.language,
+        authors: vec![JsonFeedAuthor{ name: &channel.itunes_owner_name }],
+        items: items.iter().map(|item| JsonFeedItem{
+            id: item.guid(),
+            url: &item.i_link,
+            title: &item.title,
+            summary: &item.description,
+            content_html: &item.content_encoded,
+            date_published: rfc3339(&item.pub_date),
+            image: &item.itunes_image,
+            authors: vec![JsonFeedAuthor{ name: &item.author }],
+            tags: match item.category.is_empty(){
+                true => Vec::new(),
+                false => vec![item.category.as_str()],
+            },
+            attachments: vec![JsonFeedAttachment{
+                url: &item.enclosure_url,
+                mime_type: &item.enclosure_type,
+                size_in_bytes: item.enclosure_length.parse().ok(),
+            }],
+        }).collect(),
+    };
+    return serde_json::to_string_pretty(&feed)
+        .map_err(|e| AppError::Internal(format!("could not render JSON Feed: {}", e)));
+}
+
+#[cfg(test)]
+mod tests{
+    use {
+        super::*,
+        actix_web::test::TestRequest,
+        serde_json::json,
+    };
+
+    fn channel() -> Channel{
+        return serde_json::from_value(json!({
+            "id": 1, "external_id": "6f0c4bde-0a7e-4c5e-9f4e-3b1f7f6c9a10", "title": "Arts & Crafts",
+            "category": "Arts", "description": "Making <things>", "managing_editor": "ed@example.com (Ed)",
+            "generator": "", "image_url": "https://example.com/art.png", "image_title": "art",
+            "image_link": "https://example.com", "image_width": 1400, "image_height": 1400,
+            "language": "en", "last_build_date": "Mon, 02 Jan 2023 10:00:00 +0000",
+            "pub_date": "Mon, 02 Jan 2023 10:00:00 +0000", "c_link": "https://example.com",
+            "itunes_new_feed_url": "", "itunes_explicit": false, "itunes_owner_name": "Ed",
+            "itunes_owner_email": "ed@example.com", "sy_update_period": "weekly", "sy_update_frequency": "1",
+        })).unwrap();
+    }
+
+    fn item(id: &str, pub_date: &str, guid: Option<&str>) -> Item{
+        return serde_json::from_value(json!({
+            "id": id, "channel_id": "6f0c4bde-0a7e-4c5e-9f4e-3b1f7f6c9a10", "ep_number": 1,
+            "title": "One & only", "author": "ed@example.com (Ed)", "category": "", "description": "d",
+            "content_encoded": "<p>hi</p>", "enclosure_url": "https://example.com/1.mp3",
+            "enclosure_type": "audio/mpeg", "enclosure_length": "1234", "i_link": "https://example.com/1",
+            "pub_date": pub_date, "itunes_subtitle": "", "itunes_image": "", "itunes_duration": "NONE",
+            "guid": guid,
+        })).unwrap();
+    }
+
+    #[test]
+    fn suffix_then_accept_picks_the_format(){
+        let plain = TestRequest::default().to_http_request();
+        assert_eq!(FeedFormat::from_request("show.atom", &plain), ("show", FeedFormat::Atom));
+        assert_eq!(FeedFormat::from_request("show.json", &plain), ("show", FeedFormat::Json));
+        assert_eq!(FeedFormat::from_request("show.rss", &plain), ("show", FeedFormat::Rss));
+        assert_eq!(FeedFormat::from_request("show", &plain), ("show", FeedFormat::Rss));
+
+        for (accept, format) in [
+            ("application/atom+xml", FeedFormat::Atom),
+            ("application/feed+json", FeedFormat::Json),
+            ("text/html, application/json;q=0.5, application/atom+xml;q=0.9", FeedFormat::Atom),
+            ("text/html", FeedFormat::Rss),
+        ]{
+            let req = TestRequest::default().insert_header(("Accept", accept)).to_http_request();
+            assert_eq!(FeedFormat::from_request("show", &req), ("show", format), "{}", accept);
+            // the suffix still wins.
+            assert_eq!(FeedFormat::from_request("show.rss", &req).1, FeedFormat::Rss);
+        }
+    }
+
+    #[test]
+    fn atom_is_well_formed_with_escaped_text_and_iri_ids(){
+        let items = [
+            item("11111111-1111-1111-1111-111111111111", "Tue, 03 Jan 2023 10:00:00 +0000", Some("tag:example.com,2023:1")),
+            item("22222222-2222-2222-2222-222222222222", "not a date", Some("not an iri")),
+        ];
+        let atom = render_atom(&channel(), &items);
+        let doc = roxmltree::Document::parse(&atom).unwrap();
+        let text = |node: roxmltree::Node, name: &str| node.children()
+            .find(|n| n.tag_name().name() == name)
+            .and_then(|n| n.text())
+            .map(|t| t.to_string());
+
+        let feed = doc.root_element();
+        assert_eq!(text(feed, "title").as_deref(), Some("Arts & Crafts"));
+        assert_eq!(text(feed, "id").as_deref(), Some("urn:uuid:6f0c4bde-0a7e-4c5e-9f4e-3b1f7f6c9a10"));
+        assert_eq!(text(feed, "updated").as_deref(), Some("2023-01-03T10:00:00+00:00"));
+
+        let entries: Vec<_> = feed.children().filter(|n| n.tag_name().name() == "entry").collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(text(entries[0], "id").as_deref(), Some("tag:example.com,2023:1"));
+        assert_eq!(text(entries[1], "id").as_deref(), Some("urn:uuid:22222222-2222-2222-2222-222222222222"));
+        // undated entries borrow the feed's updated, Atom requires one.
+        assert_eq!(text(entries[1], "updated").as_deref(), Some("2023-01-03T10:00:00+00:00"));
+        assert!(text(entries[1], "published").is_none());
+        let enclosure = entries[0].children()
+            .find(|n| n.attribute("rel") == Some("enclosure"))
+            .unwrap();
+        assert_eq!(enclosure.attribute("length"), Some("1234"));
+    }
+
+    #[test]
+    fn json_feed_follows_1_1(){
+        let items = [item("11111111-1111-1111-1111-111111111111", "Tue, 03 Jan 2023 10:00:00 +0000", None)];
+        let feed: serde_json::Value = serde_json::from_str(&render_json_feed(&channel(), &items).unwrap()).unwrap();
+        assert_eq!(feed["version"], "https://jsonfeed.org/version/1.1");
+        assert_eq!(feed["title"], "Arts & Crafts");
+        let item = &feed["items"][0];
+        assert_eq!(item["id"], "11111111-1111-1111-1111-111111111111");
+        assert_eq!(item["date_published"], "2023-01-03T10:00:00+00:00");
+        assert_eq!(item["attachments"][0]["size_in_bytes"], 1234);
+        assert_eq!(item["attachments"][0]["mime_type"], "audio/mpeg");
+        assert!(item.get("tags").is_none());
+        assert!(item.get("image").is_none());
+    }
+}
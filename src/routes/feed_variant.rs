@@ -0,0 +1,371 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpRequest, HttpResponse,
+        ActiveTokens, is_valid_token,
+        MultipartForm, MultipartFormText, MultipartFormTempFile,
+        AppError, parse_uuid, validate_slug,
+        S3, upload_file, media_acl, delete_from_s3_bucket,
+        FeedCache, FeedFormat, FeedLookup, lookup_feed,
+        legacy_slug, refresh_channel_feed,
+    },
+    validator::Validate,
+    serde::{
+        Serialize, Deserialize,
+    },
+    sqlx::{
+        PgPool, types::Uuid,
+    },
+    std::path::Path,
+};
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
+pub struct FeedVariantForm{
+    pub session_token: String,
+    pub channel_id: String,
+    pub name: String,
+    /// replaces the channel title in the variant's feed; "" keeps it.
+    #[serde(default)]
+    #[validate(length(max = 255))]
+    pub title: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeleteFeedVariantForm{
+    pub session_token: String,
+    pub channel_id: String,
+    pub name: String,
+}
+
+#[derive(MultipartForm)]
+pub struct VariantEpisodeForm{
+    pub session_token: MultipartFormText<String>,
+    pub channel_id: MultipartFormText<String>,
+    pub name: MultipartFormText<String>,
+    pub episode_id: MultipartFormText<String>,
+    /// this cut's audio; without it the variant uses the episode's own.
+    pub audio: Option<MultipartFormTempFile>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemoveVariantEpisodeForm{
+    pub session_token: String,
+    pub channel_id: String,
+    pub name: String,
+    pub episode_id: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MainFeedForm{
+    pub session_token: String,
+    pub episode_id: String,
+    /// false: the episode is only in the variants it was added to.
+    pub main_feed: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct CreatedFeedVariant{
+    pub variant_id: String,
+    /// feed path, relative to the server.
+    pub feed_path: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct VariantSummary{
+    pub id: Uuid,
+    pub name: String,
+    pub title: String,
+    pub episodes: i64,
+    /// episodes with their own audio in this variant.
+    pub own_audio: i64,
+}
+
+/// key for an episode's audio in a variant: the episode's key with the variant name before
+/// the extension, "{id}.mp3" becomes "{id}.ad-free.mp3".
+pub fn variant_object_key(item_object_key: Option<&str>, item_id: &Uuid, variant: &str, s3: &S3) -> String{
+    return match item_object_key{
+        Some(key) => match key.rsplit_once('.'){
+            Some((stem, ext)) if !ext.contains('/') => format!("{}.{}.{}", stem, variant, ext),
+            _ => format!("{}.{}", key, variant),
+        },
+        None => s3.prefixed_key(&format!("{}.{}.mp3", item_id, variant)),
+    };
+}
+
+async fn find_variant(ch_external_id: &Uuid, name: &str, pg_conn_pool: &PgPool) -> Result<Uuid, AppError>{
+    let variant = sqlx::query!(
+        r#" SELECT id FROM feed_variant WHERE channel_id = $1 AND name = $2 "#, ch_external_id, name
+    ).fetch_optional(pg_conn_pool)
+    .await?;
+    return match variant{
+        Some(variant) => Ok(variant.id),
+        None => Err(AppError::NotFound(format!("no variant '{}' in this channel", name))),
+    };
+}
+
+/// objects no row points at anymore; what can't be removed now is left to gc.
+async fn remove_objects(keys: &[String], s3: &S3){
+    for key in keys{
+        if let Err(e) = delete_from_s3_bucket(key, s3).await{
+            log::error!("feed variant: could not remove {}, leaving it to gc. Err: {}", key, e);
+        }
+    }
+}
+
+pub async fn create_variant(ch_external_id: &Uuid, name: &str, title: &str, pg_conn_pool: &PgPool) -> Result<CreatedFeedVariant, AppError>{
+    if name.is_empty() || validate_slug(name).is_err(){
+        return Err(AppError::Validation(format!("variant name '{}' must be lowercase letters and digits separated by single dashes", name)));
+    }
+    let ch = match sqlx::query!(
+        r#" SELECT slug FROM channel WHERE external_id = $1 "#, ch_external_id
+    ).fetch_optional(pg_conn_pool)
+    .await?{
+        Some(ch) => ch,
+        None => return Err(AppError::NotFound("channel does not exist".to_string())),
+    };
+    let variant_id = Uuid::new_v4();
+    sqlx::query!(
+        r#" INSERT INTO feed_variant (id, channel_id, name, title) VALUES ($1, $2, $3, $4) "#,
+        variant_id, ch_external_id, name, title
+    ).execute(pg_conn_pool)
+    .await?;
+    return Ok(CreatedFeedVariant{
+        variant_id: variant_id.to_string(),
+        feed_path: format!("/podcast/{}/variant/{}", ch.slug, name),
+    });
+}
+
+pub async fn list_variants(ch_external_id: &Uuid, pg_conn_pool: &PgPool) -> Result<Vec<VariantSummary>, AppError>{
+    let variants = sqlx::query_as!(VariantSummary, r#"
+        SELECT v.id, v.name, v.title,
+        COUNT(iv.item_id) AS "episodes!",
+        COUNT(iv.object_key) AS "own_audio!"
+        FROM feed_variant v LEFT JOIN item_variant iv ON iv.variant_id = v.id
+        WHERE v.channel_id = $1
+        GROUP BY v.id ORDER BY v.name
+        "#, ch_external_id
+    ).fetch_all(pg_conn_pool)
+    .await?;
+    return Ok(variants);
+}
+
+/// drop a variant and the audio uploaded for it. The caller refreshes the feed.
+pub async fn delete_variant(ch_external_id: &Uuid, name: &str, s3: &S3, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    let variant_id = find_variant(ch_external_id, name, pg_conn_pool).await?;
+    let mut tx = pg_conn_pool.begin().await?;
+    let keys: Vec<String> = sqlx::query!(
+        r#" SELECT object_key AS "object_key!" FROM item_variant WHERE variant_id = $1 AND object_key IS NOT NULL "#, variant_id
+    ).fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .map(|ep| ep.object_key)
+    .collect();
+    sqlx::query!(r#" DELETE FROM feed_variant WHERE id = $1 "#, variant_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    remove_objects(&keys, s3).await;
+    return Ok(());
+}
+
+/// put an episode in a variant, with its own `audio` or the episode's. Adding it again
+/// replaces what the variant had for it. The caller refreshes the feed.
+pub async fn add_variant_episode(
+    ch_external_id: &Uuid,
+    name: &str,
+    item_id: &Uuid,
+    audio: Option<&Path>,
+    s3: &S3,
+    pg_conn_pool: &PgPool,
+) -> Result<(), AppError>{
+    let variant_id = find_variant(ch_external_id, name, pg_conn_pool).await?;
+    let ep = match sqlx::query!(
+        r#" SELECT object_key FROM item WHERE id = $1 AND channel_id = $2 "#, item_id, ch_external_id
+    ).fetch_optional(pg_conn_pool)
+    .await?{
+        Some(ep) => ep,
+        None => return Err(AppError::NotFound(format!("no episode {} in this channel", item_id))),
+    };
+    let previous = sqlx::query!(
+        r#" SELECT object_key FROM item_variant WHERE item_id = $1 AND variant_id = $2 "#, item_id, variant_id
+    ).fetch_optional(pg_conn_pool)
+    .await?
+    .and_then(|row| row.object_key);
+
+    let (object_key, enclosure_url, enclosure_length, enclosure_sha256) = match audio{
+        Some(audio) => {
+            let object_key = variant_object_key(ep.object_key.as_deref(), item_id, name, s3);
+            let acl = media_acl(&ch_external_id.to_string(), pg_conn_pool).await?;
+            let checksums = upload_file(&object_key, audio, "audio/mpeg", acl, s3).await?;
+            (Some(object_key.clone()), Some(s3.object_url(&object_key)), Some(checksums.size.to_string()), Some(checksums.sha256))
+        },
+        None => (None, None, None, None),
+    };
+    let stored = sqlx::query!(r#"
+        INSERT INTO item_variant (item_id, variant_id, enclosure_url, enclosure_type, enclosure_length, enclosure_sha256, object_key)
+        VALUES ($1, $2, $3, CASE WHEN $3::TEXT IS NULL THEN NULL ELSE 'audio/mpeg' END, $4, $5, $6)
+        ON CONFLICT (item_id, variant_id) DO UPDATE SET
+        enclosure_url = EXCLUDED.enclosure_url, enclosure_type = EXCLUDED.enclosure_type,
+        enclosure_length = EXCLUDED.enclosure_length, enclosure_sha256 = EXCLUDED.enclosure_sha256,
+        object_key = EXCLUDED.object_key
+        "#, item_id, variant_id, enclosure_url, enclosure_length, enclosure_sha256, object_key
+    ).execute(pg_conn_pool)
+    .await;
+    // an unchanged key is the row's either way.
+    let unreferenced = match &stored{
+        Ok(_) => previous.filter(|key| object_key.as_ref() != Some(key)),
+        Err(_) => object_key.filter(|key| previous.as_ref() != Some(key)),
+    };
+    if let Some(key) = unreferenced{
+        remove_objects(&[key], s3).await;
+    }
+    stored?;
+    return Ok(());
+}
+
+/// take an episode out of a variant, with the audio uploaded for it. The caller refreshes the feed.
+pub async fn remove_variant_episode(ch_external_id: &Uuid, name: &str, item_id: &Uuid, s3: &S3, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    let variant_id = find_variant(ch_external_id, name, pg_conn_pool).await?;
+    let removed = match sqlx::query!(
+        r#" DELETE FROM item_variant WHERE item_id = $1 AND variant_id = $2 RETURNING object_key "#, item_id, variant_id
+    ).fetch_optional(pg_conn_pool)
+    .await?{
+        Some(removed) => removed,
+        None => return Err(AppError::NotFound(format!("episode {} is not in '{}'", item_id, name))),
+    };
+    if let Some(key) = removed.object_key{
+        remove_objects(&[key], s3).await;
+    }
+    return Ok(());
+}
+
+/// include an episode in the channel's main feed or not. Returns the channel's external_id.
+pub async fn set_main_feed(item_id: &Uuid, main_feed: bool, pg_conn_pool: &PgPool) -> Result<Uuid, AppError>{
+    return match sqlx::query!(
+        r#" UPDATE item SET main_feed = $1 WHERE id = $2 RETURNING channel_id "#, main_feed, item_id
+    ).fetch_optional(pg_conn_pool)
+    .await?{
+        Some(ep) => Ok(ep.channel_id),
+        None => Err(AppError::NotFound(format!("no episode {}", item_id))),
+    };
+}
+
+/// GET a channel's feed variant. Format selection and conditional GETs as podcast().
+/// Private channels have no public variants; subscribers get them from subscriber_variant_feed().
+pub async fn variant_feed(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    let (ch_slug, variant_segment) = path.into_inner();
+    let (name, format) = FeedFormat::from_request(&variant_segment, &req);
+    let suffix = &variant_segment[name.len()..];
+    let slug = legacy_slug(&ch_slug);
+    let channel_feeds = match lookup_feed(&slug, &pg_conn_pool, &feed_cache).await?{
+        FeedLookup::Found(channel_feeds) => channel_feeds,
+        FeedLookup::Renamed(current) => return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, format!("/podcast/{}/variant/{}{}", current, name, suffix)))
+            .finish()),
+        FeedLookup::Missing => return Err(AppError::NotFound(format!("no feed for '{}'", slug))),
+    };
+    if channel_feeds.private{
+        return Err(AppError::NotFound(format!("no feed for '{}'", slug)));
+    }
+    if let Some(moved_to) = channel_feeds.redirect(){
+        return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, moved_to))
+            .finish());
+    }
+    return match channel_feeds.get_variant(name, format){
+        Some(feed) => Ok(feed.respond(&req, format.content_type())),
+        None => Err(AppError::NotFound(format!("no variant '{}' of '{}'", name, slug))),
+    };
+}
+
+/// POST add a named feed variant to a channel.
+pub async fn add_feed_variant(
+    form: web::Json<FeedVariantForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    form.validate()?;
+    let ch_external_id = parse_uuid("channel_id", &form.channel_id)?;
+    let variant = create_variant(&ch_external_id, &form.name, &form.title, &pg_conn_pool).await?;
+    refresh_channel_feed(&form.channel_id, &pg_conn_pool, &feed_cache).await?;
+    return Ok(HttpResponse::Ok().json(variant));
+}
+
+/// POST delete a feed variant and the audio uploaded for it.
+pub async fn delete_feed_variant(
+    form: web::Json<DeleteFeedVariantForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let ch_external_id = parse_uuid("channel_id", &form.channel_id)?;
+    delete_variant(&ch_external_id, &form.name, &s3, &pg_conn_pool).await?;
+    refresh_channel_feed(&form.channel_id, &pg_conn_pool, &feed_cache).await?;
+    return Ok(HttpResponse::NoContent().finish());
+}
+
+/// POST (multipart) put an episode in a variant, optionally with its own audio.
+pub async fn add_feed_variant_episode(
+    payload: MultipartForm<VariantEpisodeForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&payload.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let ch_external_id = parse_uuid("channel_id", &payload.channel_id)?;
+    let item_id = parse_uuid("episode_id", &payload.episode_id)?;
+    let audio = payload.audio.as_ref().map(|audio| audio.file.path());
+    add_variant_episode(&ch_external_id, &payload.name, &item_id, audio, &s3, &pg_conn_pool).await?;
+    refresh_channel_feed(&payload.channel_id, &pg_conn_pool, &feed_cache).await?;
+    return Ok(HttpResponse::NoContent().finish());
+}
+
+/// POST take an episode out of a variant.
+pub async fn remove_feed_variant_episode(
+    form: web::Json<RemoveVariantEpisodeForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let ch_external_id = parse_uuid("channel_id", &form.channel_id)?;
+    let item_id = parse_uuid("episode_id", &form.episode_id)?;
+    remove_variant_episode(&ch_external_id, &form.name, &item_id, &s3, &pg_conn_pool).await?;
+    refresh_channel_feed(&form.channel_id, &pg_conn_pool, &feed_cache).await?;
+    return Ok(HttpResponse::NoContent().finish());
+}
+
+/// POST show or hide an episode in the channel's main feed.
+pub async fn set_episode_main_feed(
+    form: web::Json<MainFeedForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let item_id = parse_uuid("episode_id", &form.episode_id)?;
+    let ch_external_id = set_main_feed(&item_id, form.main_feed, &pg_conn_pool).await?;
+    refresh_channel_feed(&ch_external_id.to_string(), &pg_conn_pool, &feed_cache).await?;
+    return Ok(HttpResponse::NoContent().finish());
+}
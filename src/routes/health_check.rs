@@ -97,7 +97,7 @@ pub fn json_to_xml(json_str: &str) -> String{
 
         converted_body.push_str(&item_xml);
     }
-    converted_body.push_str(&format!("</rss>"));
+    converted_body.push_str("</rss>");
 
     return converted_body;
 }
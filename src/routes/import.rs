@@ -0,0 +1,724 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpResponse,
+        ActiveTokens, is_valid_token,
+        MultipartForm, MultipartFormText,
+        MultipartFormTempFile,
+        AppError, S3, S3DeleteQueue, ObjectCannedAcl,
+        Channel, ChannelCategory, Item,
+        FeedCache, refresh_channel_feed,
+        insert_channel, insert_item,
+        upload_file, rollback_s3_upload, ImportSettings,
+        parse_pub_date, validate_channel_categories,
+        validate_itunes_duration, validate_slug, slugify,
+        TUS_MAX_SIZE,
+    },
+    validator::Validate,
+    serde::Serialize,
+    chrono::Utc,
+    roxmltree::{
+        Document, Node, ParsingOptions,
+    },
+    sqlx::{
+        PgPool, types::Uuid,
+    },
+    std::{
+        collections::{
+            HashMap, HashSet,
+        },
+        io::Write,
+        net::{
+            IpAddr, SocketAddr, ToSocketAddrs,
+        },
+        path::Path,
+        time::Duration,
+    },
+};
+
+const ITUNES_NS: &str = "http://www.itunes.com/dtds/podcast-1.0.dtd";
+const CONTENT_NS: &str = "http://purl.org/rss/1.0/modules/content/";
+const SY_NS: &str = "http://purl.org/rss/1.0/modules/syndication/";
+
+/// feeds larger than this are rejected before parsing.
+pub const IMPORT_MAX_FEED_BYTES: usize = 20 * 1024 * 1024;
+/// enclosures are capped like direct uploads.
+pub const IMPORT_MAX_ENCLOSURE_BYTES: u64 = TUS_MAX_SIZE;
+const IMPORT_CONNECT_TIMEOUT_SECS: u64 = 10;
+/// a download fails once the remote host sends nothing for this long.
+const IMPORT_READ_TIMEOUT_SECS: u64 = 60;
+const IMPORT_MAX_REDIRECTS: usize = 5;
+
+#[derive(MultipartForm)]
+pub struct ImportForm{
+    pub session_token: MultipartFormText<String>,
+    /// the feed file; or
+    pub feed: Option<MultipartFormTempFile>,
+    /// where to fetch it from, http(s) only.
+    pub feed_url: Option<MultipartFormText<String>>,
+    /// copy each enclosure into our bucket instead of linking to the old host.
+    pub download_enclosures: Option<MultipartFormText<bool>>,
+    /// overrides the slug derived from the title.
+    pub slug: Option<MultipartFormText<String>>,
+    /// Apple category to use when the feed has none we recognise.
+    pub category: Option<MultipartFormText<String>>,
+}
+
+/// a parsed feed, validated as a whole so errors read "items[3].author".
+#[derive(Validate, Clone, Debug)]
+pub struct ImportedFeed{
+    #[validate(nested)]
+    pub channel: Channel,
+    #[validate(nested)]
+    pub items: Vec<Item>,
+    /// itunes:category values outside the Apple taxonomy, or past the third.
+    pub skipped_categories: Vec<String>,
+    /// <item>s without an enclosure aren't episodes.
+    pub skipped_items: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportSummary{
+    pub channel_id: String,
+    pub slug: String,
+    pub items: usize,
+    pub skipped_items: usize,
+    pub enclosures_uploaded: usize,
+    pub skipped_categories: Vec<String>,
+}
+
+/// POST import a show from an RSS/iTunes feed. Channel and items are stored in one
+/// transaction; downloaded enclosures are removed again if it fails.
+pub async fn import_feed(
+    payload: MultipartForm<ImportForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+    delete_queue: web::Data<S3DeleteQueue>,
+    import_settings: web::Data<ImportSettings>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&payload.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+
+    let xml = match (&payload.feed, &payload.feed_url){
+        (Some(feed), _) => {
+            if feed.size > IMPORT_MAX_FEED_BYTES{
+                return Err(AppError::Validation("feed file is too large".to_string()));
+            }
+            std::fs::read_to_string(feed.file.path())
+                .map_err(|e| AppError::Validation(format!("feed is not readable UTF-8 text: {}", e)))?
+        },
+        (None, Some(feed_url)) => fetch_feed(feed_url, &import_settings.allowed_hosts).await?,
+        (None, None) => return Err(AppError::Validation("send a feed file or a feed_url".to_string())),
+    };
+
+    let mut imported = parse_feed(&xml)?;
+    if let Some(slug) = &payload.slug{
+        validate_slug(slug).map_err(|_| AppError::Validation(format!("'{}' is not a valid slug", slug.as_str())))?;
+        imported.channel.slug = slug.to_string();
+    }
+    if let Some(category) = &payload.category{
+        if imported.channel.categories.is_empty(){
+            imported.channel.category = category.to_string();
+        }
+    }
+    for item in imported.items.iter_mut(){
+        item.id = Uuid::new_v4().to_string();
+    }
+    imported.validate()?;
+
+    let download = payload.download_enclosures.as_ref().map(|d| d.0).unwrap_or(false);
+    let mut uploaded = Vec::new();
+    if download{
+        // insert_channel would derive the same slug; keys need it before the channel exists.
+        if imported.channel.slug.is_empty(){
+            imported.channel.slug = slugify(&imported.channel.title);
+        }
+        for item in imported.items.iter_mut(){
+            match copy_enclosure(item, &imported.channel.slug, &import_settings.allowed_hosts, &s3).await{
+                Ok(object_key) => uploaded.push(object_key),
+                Err(e) => {
+                    rollback_uploads(&uploaded, &s3, &delete_queue).await;
+                    return Err(e);
+                },
+            }
+        }
+    }
+
+    let ch_external_id = match store_import(&mut imported, &pg_conn_pool).await{
+        Ok(ch_external_id) => ch_external_id,
+        Err(e) => {
+            rollback_uploads(&uploaded, &s3, &delete_queue).await;
+            return Err(e);
+        },
+    };
+    refresh_channel_feed(&ch_external_id.to_string(), &pg_conn_pool, &feed_cache).await?;
+
+    return Ok(HttpResponse::Ok().json(ImportSummary{
+        channel_id: ch_external_id.to_string(),
+        slug: imported.channel.slug,
+        items: imported.items.len(),
+        skipped_items: imported.skipped_items,
+        enclosures_uploaded: uploaded.len(),
+        skipped_categories: imported.skipped_categories,
+    }));
+}
+
+async fn rollback_uploads(object_keys: &[String], s3: &S3, delete_queue: &S3DeleteQueue){
+    for object_key in object_keys{
+        rollback_s3_upload(object_key, s3, delete_queue).await;
+    }
+}
+
+async fn store_import(imported: &mut ImportedFeed, pg_conn_pool: &PgPool) -> Result<Uuid, AppError>{
+    let mut tx = pg_conn_pool.begin().await?;
+    let ch_external_id = match insert_channel(&mut tx, &mut imported.channel).await?{
+        Some(ch_external_id) => ch_external_id,
+        None => return Err(AppError::Validation(
+            format!("a channel titled '{}' already exists", imported.channel.title))),
+    };
+    for item in imported.items.iter_mut(){
+        item.channel_id = ch_external_id.to_string();
+        insert_item(&mut tx, item).await?;
+    }
+    tx.commit().await?;
+    return Ok(ch_external_id);
+}
+
+async fn fetch_feed(feed_url: &str, allowed_hosts: &[String]) -> Result<String, AppError>{
+    let mut response = fetch_public(feed_url, allowed_hosts).await
+        .map_err(|e| AppError::Validation(format!("could not fetch feed: {}", e)))?;
+    if response.content_length().unwrap_or(0) > IMPORT_MAX_FEED_BYTES as u64{
+        return Err(AppError::Validation("feed is too large".to_string()));
+    }
+    let mut bytes = Vec::new();
+    while let Some(chunk) = response.chunk().await
+        .map_err(|e| AppError::Validation(format!("could not fetch feed: {}", e)))?{
+        if bytes.len() + chunk.len() > IMPORT_MAX_FEED_BYTES{
+            return Err(AppError::Validation("feed is too large".to_string()));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    return String::from_utf8(bytes)
+        .map_err(|_| AppError::Validation("feed is not UTF-8".to_string()));
+}
+
+/// GET a url on the public internet. Redirects are followed here so every hop's host
+/// is checked. The check resolves the name separately from reqwest, so a host that
+/// answers differently the second time (DNS rebinding) can still reach an internal
+/// address; block that with an egress firewall where it matters. Hosts in allowed_hosts
+/// (`[import] allowed_hosts`) skip the check.
+async fn fetch_public(url: &str, allowed_hosts: &[String]) -> Result<reqwest::Response, String>{
+    let client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(IMPORT_CONNECT_TIMEOUT_SECS))
+        .read_timeout(Duration::from_secs(IMPORT_READ_TIMEOUT_SECS))
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .map_err(|e| e.to_string())?;
+    let mut url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    for _ in 0..=IMPORT_MAX_REDIRECTS{
+        check_public_host(&url, allowed_hosts).await?;
+        let response = client.get(url.clone()).send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_redirection(){
+            return response.error_for_status().map_err(|e| e.to_string());
+        }
+        let location = response.headers().get(reqwest::header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| format!("{} redirected without a Location", url))?;
+        url = url.join(location).map_err(|e| e.to_string())?;
+    }
+    return Err("too many redirects".to_string());
+}
+
+/// rejects urls that aren't http(s) or whose host resolves to a non-public address,
+/// unless the host is one of allowed_hosts.
+async fn check_public_host(url: &reqwest::Url, allowed_hosts: &[String]) -> Result<(), String>{
+    if url.scheme() != "http" && url.scheme() != "https"{
+        return Err(format!("{} is not http(s)", url));
+    }
+    // IPv6 literals keep their brackets in host_str.
+    let host = url.host_str().unwrap_or("").trim_start_matches('[').trim_end_matches(']').to_string();
+    if allowed_hosts.iter().any(|allowed| allowed.trim_start_matches('[').trim_end_matches(']').eq_ignore_ascii_case(&host)){
+        return Ok(());
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let lookup = host.clone();
+    let addrs: Vec<SocketAddr> = web::block(move|| (lookup.as_str(), port).to_socket_addrs().map(|addrs| addrs.collect()))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("could not resolve {}: {}", host, e))?;
+    if addrs.is_empty() || !addrs.iter().all(|addr| is_public_ip(addr.ip())){
+        return Err(format!("{} is not a public host", host));
+    }
+    return Ok(());
+}
+
+fn is_public_ip(ip: IpAddr) -> bool{
+    return match ip{
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local()
+                || ip.is_multicast() || ip.is_broadcast() || ip.is_documentation()
+                // 0.0.0.0/8 and carrier-grade NAT 100.64.0.0/10.
+                || octets[0] == 0 || (octets[0] == 100 && octets[1] & 0xc0 == 64))
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped(){
+            Some(ip) => is_public_ip(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast()
+                    // unique local fc00::/7 and link-local fe80::/10.
+                    || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+            },
+        },
+    };
+}
+
+/// media types an enclosure may be stored as, with the extension its key gets.
+const ENCLOSURE_FORMATS: &[(&str, &str)] = &[
+    ("audio/mpeg", "mp3"), ("audio/mp4", "m4a"), ("audio/x-m4a", "m4a"), ("audio/aac", "aac"),
+    ("audio/ogg", "ogg"), ("audio/opus", "opus"), ("audio/wav", "wav"), ("audio/x-wav", "wav"),
+    ("audio/flac", "flac"), ("video/mp4", "mp4"), ("video/x-m4v", "m4v"), ("video/quicktime", "mov"),
+];
+
+/// (content type, extension) from the enclosure's type, else from its URL's extension;
+/// mp3 when neither is known, as parse_feed assumes for a missing type.
+fn enclosure_format(enclosure_type: &str, enclosure_url: &str) -> (&'static str, &'static str){
+    let enclosure_type = enclosure_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    if let Some(format) = ENCLOSURE_FORMATS.iter().find(|(content_type, _)| *content_type == enclosure_type){
+        return *format;
+    }
+    let path = reqwest::Url::parse(enclosure_url).map(|url| url.path().to_lowercase()).unwrap_or_default();
+    let ext = Path::new(&path).extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    return *ENCLOSURE_FORMATS.iter().find(|(_, e)| *e == ext).unwrap_or(&ENCLOSURE_FORMATS[0]);
+}
+
+/// download an enclosure to temp_dir and upload it under a key_template key. Returns the key.
+async fn copy_enclosure(item: &mut Item, channel_slug: &str, allowed_hosts: &[String], s3: &web::Data<S3>
+) -> Result<String, AppError>{
+    let temp_file = format!("{}/import-{}", s3.temp_dir, item.id);
+    let (content_type, ext) = enclosure_format(&item.enclosure_type, &item.enclosure_url);
+    let object_key = s3.episode_key(channel_slug, item, ext);
+    let stored = match download_enclosure(&item.enclosure_url, &temp_file, allowed_hosts).await{
+        // imports start out as new, public channels.
+        Ok(size) => upload_file(&object_key, Path::new(&temp_file), content_type, ObjectCannedAcl::PublicRead, s3).await
+            .map(|checksums| (size, checksums)),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = std::fs::remove_file(&temp_file){
+        if e.kind() != std::io::ErrorKind::NotFound{
+            log::info!("import: could not remove {}. Err: {}", temp_file, e);
+        }
+    }
+    let (size, checksums) = stored?;
+
+    item.enclosure_url = s3.object_url(&object_key);
+    item.enclosure_type = content_type.to_string();
+    item.enclosure_length = size.to_string();
+    item.enclosure_sha256 = Some(checksums.sha256);
+    item.object_key = Some(object_key.clone());
+    return Ok(object_key);
+}
+
+/// stream an enclosure into temp_file, at most IMPORT_MAX_ENCLOSURE_BYTES. Returns its size.
+async fn download_enclosure(enclosure_url: &str, temp_file: &str, allowed_hosts: &[String]) -> Result<u64, AppError>{
+    let download_error = |e: String| AppError::Storage(format!("could not download {}: {}", enclosure_url, e));
+    let too_large = || AppError::Validation(format!("{} is larger than {} bytes", enclosure_url, IMPORT_MAX_ENCLOSURE_BYTES));
+
+    let mut response = fetch_public(enclosure_url, allowed_hosts).await.map_err(download_error)?;
+    if response.content_length().unwrap_or(0) > IMPORT_MAX_ENCLOSURE_BYTES{
+        return Err(too_large());
+    }
+    let path = temp_file.to_string();
+    let mut file = web::block(move|| std::fs::File::create(path))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::Storage(format!("could not create temp file: {}", e)))?;
+    let mut size = 0;
+    while let Some(chunk) = response.chunk().await.map_err(|e| download_error(e.to_string()))?{
+        size += chunk.len() as u64;
+        if size > IMPORT_MAX_ENCLOSURE_BYTES{
+            return Err(too_large());
+        }
+        file = web::block(move|| file.write_all(&chunk).map(|_| file))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+            .map_err(|e| AppError::Storage(format!("could not write temp file: {}", e)))?;
+    }
+    return Ok(size);
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, ns: Option<&str>, name: &str) -> Option<Node<'a, 'input>>{
+    return node.children().find(|n| n.is_element()
+        && n.tag_name().name() == name && n.tag_name().namespace() == ns);
+}
+
+/// trimmed text of a child element, "" if missing.
+fn text(node: Node, ns: Option<&str>, name: &str) -> String{
+    return child(node, ns, name)
+        .map(|n| n.children().filter_map(|t| t.text()).collect::<String>().trim().to_string())
+        .unwrap_or_default();
+}
+
+fn attr(node: Node, ns: Option<&str>, name: &str, attribute: &str) -> String{
+    return child(node, ns, name)
+        .and_then(|n| n.attribute(attribute))
+        .unwrap_or("")
+        .trim()
+        .to_string();
+}
+
+fn or(value: String, fallback: impl FnOnce() -> String) -> String{
+    if value.is_empty(){
+        return fallback();
+    }
+    return value;
+}
+
+/// "bob@example.com (Bob)" -> "bob@example.com".
+fn email_in(value: &str) -> Option<String>{
+    return value.split_whitespace()
+        .map(|word| word.trim_matches(|c| "()<>,;".contains(c)))
+        .find(|word| word.contains('@'))
+        .map(|email| email.to_string());
+}
+
+fn truncate(value: String, max_chars: usize) -> String{
+    if value.chars().count() <= max_chars{
+        return value;
+    }
+    return value.chars().take(max_chars).collect();
+}
+
+/// map an RSS 2.0 / iTunes feed onto Channel and Item. Nothing is validated here beyond
+/// what's needed to map; run ImportedFeed::validate() before storing.
+pub fn parse_feed(xml: &str) -> Result<ImportedFeed, AppError>{
+    let options = ParsingOptions{ allow_dtd: true, ..ParsingOptions::default() };
+    let doc = Document::parse_with_options(xml, options)
+        .map_err(|e| AppError::Validation(format!("feed is not valid XML: {}", e)))?;
+    let rss = doc.root_element();
+    let ch = match child(rss, None, "channel"){
+        Some(ch) if rss.tag_name().name() == "rss" => ch,
+        _ => return Err(AppError::Validation("not an RSS feed: no <rss><channel>".to_string())),
+    };
+    let itunes = Some(ITUNES_NS);
+
+    let now = Utc::now().to_rfc2822();
+    let title = text(ch, None, "title");
+    let c_link = text(ch, None, "link");
+    let owner = child(ch, itunes, "owner");
+    let owner_email = owner.map(|o| text(o, itunes, "email")).unwrap_or_default();
+    let managing_editor = email_in(&text(ch, None, "managingEditor"))
+        .unwrap_or_else(|| owner_email.clone());
+    let image = child(ch, None, "image");
+    let image_url = or(image.map(|i| text(i, None, "url")).unwrap_or_default(),
+        || attr(ch, itunes, "image", "href"));
+    let pub_date = or(text(ch, None, "pubDate"), || now.clone());
+
+    let mut categories = Vec::new();
+    let mut skipped_categories = Vec::new();
+    for c in ch.children().filter(|n| n.is_element()
+        && n.tag_name().name() == "category" && n.tag_name().namespace() == itunes){
+        let pair = ChannelCategory{
+            category: c.attribute("text").unwrap_or("").to_string(),
+            subcategory: child(c, itunes, "category").and_then(|s| s.attribute("text")).map(|s| s.to_string()),
+        };
+        let mut candidate = categories.clone();
+        candidate.push(pair.clone());
+        if validate_channel_categories(&candidate).is_ok(){
+            categories = candidate;
+        } else {
+            skipped_categories.push(match &pair.subcategory{
+                Some(sub) => format!("{} > {}", pair.category, sub),
+                None => pair.category,
+            });
+        }
+    }
+    let itunes_type = text(ch, itunes, "type");
+
+    let channel = Channel{
+        id: 0,
+        external_id: String::new(),
+        category: categories.first().map(|c| c.category.clone()).unwrap_or_default(),
+        description: truncate(or(or(text(ch, None, "description"),
+            || text(ch, itunes, "summary")), || title.clone()), 4000),
+        generator: truncate(text(ch, None, "generator"), 255),
+        image_title: or(image.map(|i| text(i, None, "title")).unwrap_or_default(), || title.clone()),
+        image_link: or(image.map(|i| text(i, None, "link")).unwrap_or_default(), || c_link.clone()),
+        image_width: image.and_then(|i| text(i, None, "width").parse().ok()).unwrap_or(1400),
+        image_height: image.and_then(|i| text(i, None, "height").parse().ok()).unwrap_or(1400),
+        image_url,
+        language: or(text(ch, None, "language").to_lowercase(), || "en".to_string()),
+        last_build_date: or(text(ch, None, "lastBuildDate"), || pub_date.clone()),
+        pub_date,
+        // this server is the new home.
+        itunes_new_feed_url: String::new(),
+        itunes_explicit: matches!(text(ch, itunes, "explicit").to_lowercase().as_str(), "yes" | "true" | "explicit"),
+        itunes_owner_name: or(owner.map(|o| text(o, itunes, "name")).unwrap_or_default(),
+            || or(text(ch, itunes, "author"), || title.clone())),
+        itunes_owner_email: or(owner_email, || managing_editor.clone()),
+        managing_editor,
+        sy_update_period: or(text(ch, Some(SY_NS), "updatePeriod"), || "weekly".to_string()),
+        sy_update_frequency: or(text(ch, Some(SY_NS), "updateFrequency"), || "1".to_string()),
+        itunes_type: match itunes_type.as_str(){
+            "serial" => itunes_type,
+            _ => "episodic".to_string(),
+        },
+        max_items: None,
+        categories,
+        slug: String::new(),
+        custom_path: None,
+        title,
+        c_link,
+    };
+
+    let mut items = Vec::new();
+    let mut skipped_items = 0;
+    for it in ch.children().filter(|n| n.is_element() && n.tag_name().name() == "item"){
+        let enclosure_url = attr(it, None, "enclosure", "url");
+        if enclosure_url.is_empty(){
+            skipped_items += 1;
+            continue;
+        }
+        let title = text(it, None, "title");
+        let duration = text(it, itunes, "duration");
+        let guid = text(it, None, "guid");
+        items.push(Item{
+            id: String::new(),
+            channel_id: String::new(),
+            ep_number: text(it, itunes, "episode").parse().unwrap_or(0),
+            author: email_in(&text(it, None, "author")).unwrap_or_else(|| channel.managing_editor.clone()),
+            category: truncate(text(it, None, "category"), 255),
+            description: truncate(or(or(text(it, None, "description"),
+                || text(it, itunes, "summary")), || title.clone()), 4000),
+            content_encoded: text(it, Some(CONTENT_NS), "encoded"),
+            enclosure_type: or(attr(it, None, "enclosure", "type"), || "audio/mpeg".to_string()),
+            enclosure_length: or(attr(it, None, "enclosure", "length"), || "0".to_string()),
+            i_link: or(text(it, None, "link"), || enclosure_url.clone()),
+            enclosure_url,
+            pub_date: or(text(it, None, "pubDate"), || channel.pub_date.clone()),
+            itunes_subtitle: truncate(text(it, itunes, "subtitle"), 255),
+            itunes_image: attr(it, itunes, "image", "href"),
+            itunes_duration: match validate_itunes_duration(&duration){
+                Ok(()) if !duration.is_empty() => duration,
+                _ => "NONE".to_string(),
+            },
+            season: text(it, itunes, "season").parse().ok().filter(|s: &i32| *s > 0),
+            guid: match guid.is_empty(){
+                true => None,
+                false => Some(guid),
+            },
+            enclosure_sha256: None,
+            object_key: None,
+            title,
+        });
+    }
+    assign_episode_numbers(&mut items);
+
+    return Ok(ImportedFeed{ channel, items, skipped_categories, skipped_items });
+}
+
+/// keep itunes:episode where it's unique within its season; number the rest oldest first
+/// with the lowest free numbers.
+fn assign_episode_numbers(items: &mut [Item]){
+    let mut used = HashMap::<Option<i32>, HashSet<i32>>::new();
+    for item in items.iter_mut(){
+        if item.ep_number > 0 && !used.entry(item.season).or_default().insert(item.ep_number){
+            item.ep_number = 0;
+        }
+    }
+
+    // feeds list newest first; unparseable dates keep that order at the end.
+    let mut order: Vec<usize> = (0..items.len()).rev().collect();
+    order.sort_by_key(|i|{
+        let pub_date = parse_pub_date(&items[*i].pub_date);
+        return (pub_date.is_none(), pub_date);
+    });
+    for i in order{
+        if items[i].ep_number > 0{
+            continue;
+        }
+        let used = used.entry(items[i].season).or_default();
+        let mut next = 1;
+        while used.contains(&next){
+            next += 1;
+        }
+        used.insert(next);
+        items[i].ep_number = next;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn feed(items: &str) -> String{
+        return format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <rss version="2.0" xmlns:itunes="{}" xmlns:content="{}">
+            <channel>
+                <title>Art Show</title>
+                <link>https://example.com</link>
+                <description>About art.</description>
+                <language>en-US</language>
+                <pubDate>Mon, 02 Jan 2023 10:00:00 +0000</pubDate>
+                <itunes:owner><itunes:name>Owner</itunes:name><itunes:email>owner@example.com</itunes:email></itunes:owner>
+                <itunes:image href="https://example.com/art.png"/>
+                <itunes:category text="Arts"><itunes:category text="Design"/></itunes:category>
+                <itunes:category text="Not A Category"/>
+                <itunes:type>serial</itunes:type>
+                {}
+            </channel>
+            </rss>"#, ITUNES_NS, CONTENT_NS, items);
+    }
+
+    fn item(title: &str, pub_date: &str, extra: &str) -> String{
+        return format!(r#"<item>
+                <title>{}</title>
+                <pubDate>{}</pubDate>
+                <enclosure url="https://example.com/{}.mp3" type="audio/mpeg" length="1234"/>
+                {}
+            </item>"#, title, pub_date, title, extra);
+    }
+
+    fn numbers(items: &[Item]) -> Vec<(String, i32)>{
+        return items.iter().map(|item| (item.title.clone(), item.ep_number)).collect();
+    }
+
+    #[test]
+    fn parse_feed_maps_channel_and_items(){
+        let xml = feed(&format!("{}<item><title>no audio</title></item>",
+            item("one", "Tue, 03 Jan 2023 10:00:00 +0000",
+                "<guid>ep-1</guid><itunes:duration>12:34</itunes:duration><itunes:season>2</itunes:season>\
+                <author>host@example.com (Host)</author>")));
+        let imported = parse_feed(&xml).unwrap();
+
+        let ch = &imported.channel;
+        assert_eq!(ch.title, "Art Show");
+        assert_eq!(ch.language, "en-us");
+        assert_eq!(ch.image_url, "https://example.com/art.png");
+        assert_eq!(ch.itunes_owner_email, "owner@example.com");
+        assert_eq!(ch.managing_editor, "owner@example.com");
+        assert_eq!(ch.itunes_type, "serial");
+        assert_eq!(ch.category, "Arts");
+        assert_eq!(ch.categories.len(), 1);
+        assert_eq!(ch.categories[0].subcategory.as_deref(), Some("Design"));
+        assert_eq!(imported.skipped_categories, vec!["Not A Category".to_string()]);
+
+        assert_eq!(imported.skipped_items, 1);
+        assert_eq!(imported.items.len(), 1);
+        let ep = &imported.items[0];
+        assert_eq!(ep.enclosure_url, "https://example.com/one.mp3");
+        assert_eq!(ep.enclosure_length, "1234");
+        assert_eq!(ep.author, "host@example.com");
+        assert_eq!(ep.guid.as_deref(), Some("ep-1"));
+        assert_eq!(ep.itunes_duration, "12:34");
+        assert_eq!(ep.season, Some(2));
+        assert_eq!(ep.ep_number, 1);
+    }
+
+    #[test]
+    fn internal_addresses_are_not_public(){
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0",
+            "100.64.0.1", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1"]{
+            assert!(!is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:93.184.216.34"]{
+            assert!(is_public_ip(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[actix_web::test]
+    async fn fetches_refuse_internal_hosts(){
+        for url in ["http://127.0.0.1:9000/feed.xml", "http://[::1]/feed.xml", "http://localhost/feed.xml", "file:///etc/passwd"]{
+            assert!(fetch_public(url, &[]).await.is_err(), "{}", url);
+        }
+    }
+
+    #[actix_web::test]
+    async fn allowed_hosts_skip_the_public_check(){
+        let allowed_hosts = vec!["LocalHost".to_string(), "[::1]".to_string()];
+        for url in ["http://localhost:9000/feed.xml", "http://[::1]/feed.xml"]{
+            let url = reqwest::Url::parse(url).unwrap();
+            assert!(check_public_host(&url, &allowed_hosts).await.is_ok(), "{}", url);
+            assert!(check_public_host(&url, &[]).await.is_err(), "{}", url);
+        }
+        for url in ["http://127.0.0.1/feed.xml", "file://localhost/etc/passwd"]{
+            let url = reqwest::Url::parse(url).unwrap();
+            assert!(check_public_host(&url, &allowed_hosts).await.is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn enclosure_formats_follow_type_then_url(){
+        assert_eq!(enclosure_format("audio/mpeg", "https://example.com/a.m4a"), ("audio/mpeg", "mp3"));
+        assert_eq!(enclosure_format("Audio/MP4; codecs=mp4a", "https://example.com/a"), ("audio/mp4", "m4a"));
+        assert_eq!(enclosure_format("video/mp4", "https://example.com/a"), ("video/mp4", "mp4"));
+        assert_eq!(enclosure_format("", "https://example.com/a.OGG?x=1"), ("audio/ogg", "ogg"));
+        assert_eq!(enclosure_format("application/octet-stream", "https://example.com/a.m4a"), ("audio/mp4", "m4a"));
+        assert_eq!(enclosure_format("", "https://example.com/a"), ("audio/mpeg", "mp3"));
+    }
+
+    #[test]
+    fn parse_feed_rejects_non_rss(){
+        assert!(parse_feed("<feed></feed>").is_err());
+        assert!(parse_feed("not xml").is_err());
+    }
+
+    #[test]
+    fn parse_feed_falls_back_for_missing_fields(){
+        let xml = feed("<item><title>bare</title><enclosure url=\"https://example.com/bare.mp3\"/></item>");
+        let ep = &parse_feed(&xml).unwrap().items[0];
+        assert_eq!(ep.enclosure_type, "audio/mpeg");
+        assert_eq!(ep.enclosure_length, "0");
+        assert_eq!(ep.i_link, "https://example.com/bare.mp3");
+        assert_eq!(ep.pub_date, "Mon, 02 Jan 2023 10:00:00 +0000");
+        assert_eq!(ep.itunes_duration, "NONE");
+        assert_eq!(ep.guid, None);
+    }
+
+    #[test]
+    fn episode_numbers_follow_dates_with_undated_last(){
+        // newest first, as feeds list them; two dates don't parse.
+        let xml = feed(&[
+            item("undated-newer", "someday", ""),
+            item("march", "Wed, 01 Mar 2023 10:00:00 +0000", ""),
+            item("undated-older", "", ""),
+            item("january", "Sun, 01 Jan 2023 10:00:00 +0000", ""),
+            item("february", "2023-02-01T10:00:00+00:00", ""),
+        ].concat());
+        let mut items = parse_feed(&xml).unwrap().items;
+        // "" falls back to the channel's pubDate; make it unparseable too.
+        items[2].pub_date = "unknown".to_string();
+        for item in items.iter_mut(){
+            item.ep_number = 0;
+        }
+        assign_episode_numbers(&mut items);
+        assert_eq!(numbers(&items), vec![
+            ("undated-newer".to_string(), 5),
+            ("march".to_string(), 3),
+            ("undated-older".to_string(), 4),
+            ("january".to_string(), 1),
+            ("february".to_string(), 2),
+        ]);
+    }
+
+    #[test]
+    fn episode_numbers_keep_unique_ones_and_fill_gaps(){
+        let xml = feed(&[
+            item("c", "Wed, 01 Mar 2023 10:00:00 +0000", "<itunes:episode>2</itunes:episode>"),
+            item("b", "Wed, 01 Feb 2023 10:00:00 +0000", "<itunes:episode>2</itunes:episode>"),
+            item("a", "Sun, 01 Jan 2023 10:00:00 +0000", ""),
+            item("s2", "Sun, 01 Jan 2023 10:00:00 +0000", "<itunes:season>2</itunes:season>"),
+        ].concat());
+        let items = parse_feed(&xml).unwrap().items;
+        // c keeps 2; b's duplicate and a are numbered oldest first from 1; seasons count apart.
+        assert_eq!(numbers(&items), vec![
+            ("c".to_string(), 2),
+            ("b".to_string(), 3),
+            ("a".to_string(), 1),
+            ("s2".to_string(), 1),
+        ]);
+    }
+}
@@ -1,3 +1,13 @@
 pub mod auth;
+pub mod artwork;
 pub mod podcast;
+pub mod feed_formats;
+pub mod feed_cache;
+pub mod slug;
+pub mod channel_move;
+pub mod import;
+pub mod tus;
+pub mod presigned;
+pub mod private_feed;
+pub mod feed_variant;
 pub mod health_check;
@@ -1,31 +1,56 @@
 use {
     crate::{
-        Arc, RwLock,
+        RwLock,
         web, HttpResponse,
         ContentType, S3Client,
-        Multipart, ByteStream,
-        ObjectCannedAcl, ActiveTokens,
+        Multipart, ActiveTokens,
         is_valid_token, 
         MultipartForm,
         /* MultipartCollect, */
         MultipartFormJson,
-        MultipartFormTempFile,
+        MultipartFormTempFile, MultipartFormText,
+        AppError, parse_uuid,
+        validate_apple_category, validate_language,
+        validate_date, validate_optional_url,
+        validate_itunes_type, validate_itunes_duration,
+        validate_sy_update_period, validate_sy_update_frequency,
+        validate_channel_categories, validate_rss_email,
+        store_artwork, remove_artwork,
+        HttpRequest, FeedFormat, FeedBuffers,
+        render_atom, render_json_feed, CachedFeed,
+        FeedCache, ChannelFeeds,
+        slugify, legacy_slug, slug_redirect,
+        validate_slug, validate_custom_path,
+        ChannelMove, Arc,
+        SUBSCRIBER_MEDIA_BASE, media_acl, ObjectCannedAcl,
+        Settings, Credentials, Config, Region,
+        ObjectChecksums, upload_file,
     },
+    validator::Validate,
     serde::{
         Serialize, Deserialize,
     },
+    chrono::{
+        DateTime, FixedOffset, Utc,
+    },
     futures::{
         StreamExt, TryStreamExt,
     },
     sqlx::{
-        PgPool, types::Uuid,
+        PgPool, Postgres, Transaction,
+        types::Uuid,
+    },
+    tokio::sync::mpsc::{
+        unbounded_channel, UnboundedSender,
     },
     std::{
         fs,
         result::Result,
         io::Write,
         path::Path,
-        fmt::Display,
+        collections::{
+            BTreeMap, HashMap, HashSet,
+        },
     },
 
    
@@ -35,62 +60,171 @@ use {
 pub struct PodcastDataV2{
     pub podcast_data: MultipartFormJson<PodcastData>,
     pub audio: MultipartFormTempFile,
+    /// optional episode artwork, same rules as channel artwork.
+    pub artwork: Option<MultipartFormTempFile>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(MultipartForm)]
+pub struct ReplaceAudioForm{
+    pub session_token: MultipartFormText<String>,
+    pub episode_id: MultipartFormText<String>,
+    pub audio: MultipartFormTempFile,
+}
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
 pub struct PodcastData{
+    #[validate(nested)]
     pub channel: Channel,
+    #[validate(nested)]
     pub item: Item,
     pub session_token: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
 pub struct Channel{
     pub id: i32,
     //#[serde(deserialize_with = "deserialize_uuid")]
     pub external_id: String,
+    #[validate(length(min = 1, max = 255))]
     pub title: String,
+    #[validate(custom(function = "validate_apple_category"))]
     pub category: String,
+    #[validate(length(min = 1, max = 4000))]
     pub description: String,
+    #[validate(custom(function = "validate_rss_email"))]
     pub managing_editor: String,
+    #[validate(length(max = 255))]
     pub generator: String,
+    #[validate(url, length(max = 2048))]
     pub image_url: String,
+    #[validate(length(max = 255))]
     pub image_title : String,
+    #[validate(url, length(max = 2048))]
     pub image_link: String,
+    #[validate(range(min = 1, max = 3000))]
     pub image_width : i32,
+    #[validate(range(min = 1, max = 3000))]
     pub image_height: i32,
+    #[validate(custom(function = "validate_language"))]
     pub language: String,
+    #[validate(custom(function = "validate_date"))]
     pub last_build_date: String,
+    #[validate(custom(function = "validate_date"))]
     pub pub_date: String,
+    #[validate(url, length(max = 2048))]
     pub c_link: String,
     // optional
+    #[validate(custom(function = "validate_optional_url"), length(max = 2048))]
     pub itunes_new_feed_url: String,
     pub itunes_explicit: bool,
+    #[validate(length(min = 1, max = 255))]
     pub itunes_owner_name: String,
+    // itunes:email takes a bare address.
+    #[validate(email)]
     pub itunes_owner_email: String,
+    #[validate(custom(function = "validate_sy_update_period"))]
     pub sy_update_period: String,
+    #[validate(custom(function = "validate_sy_update_frequency"))]
     pub sy_update_frequency: String,
+    // "episodic" or "serial"
+    #[serde(default = "default_itunes_type")]
+    #[validate(custom(function = "validate_itunes_type"))]
+    pub itunes_type: String,
+    // None == no limit
+    #[serde(default)]
+    #[validate(range(min = 1))]
+    pub max_items: Option<i32>,
+    // itunes:category pairs. Empty == just `category`.
+    #[serde(default)]
+    #[validate(custom(function = "validate_channel_categories"))]
+    pub categories: Vec<ChannelCategory>,
+    // feed lives at /podcast/{slug}. "" == derived from the title on create.
+    #[serde(default)]
+    #[validate(custom(function = "validate_slug"))]
+    pub slug: String,
+    // additional URL path the feed is served at, e.g. "/feeds/my-show.xml".
+    #[serde(default)]
+    #[validate(custom(function = "validate_custom_path"))]
+    pub custom_path: Option<String>,
+}
+
+fn default_itunes_type() -> String{
+    return "episodic".to_string();
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+impl Channel{
+    /// categories as stored and rendered: the explicit list, or the single `category`.
+    pub fn category_pairs(&self) -> Vec<ChannelCategory>{
+        if self.categories.is_empty(){
+            return vec![ChannelCategory{
+                category: self.category.clone(),
+                subcategory: None,
+            }];
+        }
+        return self.categories.clone();
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChannelCategory{
+    pub category: String,
+    pub subcategory: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
 pub struct Item{
     pub id: String,
     pub channel_id: String,
+    #[validate(range(min = 0))]
     pub ep_number: i32,
+    #[validate(length(min = 1, max = 255))]
     pub title: String,
+    #[validate(custom(function = "validate_rss_email"))]
     pub author: String,
+    #[validate(length(max = 255))]
     pub category: String,
+    #[validate(length(min = 1, max = 4000))]
     pub description: String,
+    #[validate(length(max = 10000))]
     pub content_encoded: String,
+    // set by the server on upload.
+    #[validate(url, length(max = 2048))]
     pub enclosure_url: String,
+    #[validate(length(max = 255))]
     pub enclosure_type: String,
+    #[validate(length(max = 32))]
     pub enclosure_length: String,
+    #[validate(url, length(max = 2048))]
     pub i_link: String,
+    #[validate(custom(function = "validate_date"))]
     pub pub_date: String,
     //optional; maybe not. Podcatchers weirdly reliant on itune tags
+    #[validate(length(max = 255))]
     pub itunes_subtitle: String,
+    #[validate(custom(function = "validate_optional_url"), length(max = 2048))]
     pub itunes_image: String,
+    #[validate(custom(function = "validate_itunes_duration"))]
     pub itunes_duration: String,
+    #[serde(default)]
+    #[validate(range(min = 1))]
+    pub season: Option<i32>,
+    // original <guid> of imported episodes; `id` is used when None.
+    #[serde(default)]
+    #[validate(length(min = 1, max = 2048))]
+    pub guid: Option<String>,
+    // set by the server on upload; None for audio it never read, e.g. presigned uploads.
+    #[serde(default)]
+    pub enclosure_sha256: Option<String>,
+    // bucket key of the enclosure, set by the server; None when hosted elsewhere.
+    #[serde(default)]
+    pub object_key: Option<String>,
+}
+
+impl Item{
+    /// <guid> as published.
+    pub fn guid(&self) -> &str{
+        return self.guid.as_deref().unwrap_or(&self.id);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone,Debug)]
@@ -104,7 +238,75 @@ pub struct S3{
     pub client: S3Client,
     pub bucket: String,
     pub full_link: String,
+    /// where media is served from; full_link unless public_base_url is set.
+    pub public_link: String,
     pub temp_dir: String,
+    /// S3Bucket::key_prefix without surrounding slashes.
+    pub key_prefix: String,
+    pub key_template: String,
+}
+
+impl S3{
+    pub fn from_settings(settings: &Settings) -> Self{
+        let s3_config = &settings.s3_bucket;
+        let s3_credentials = Credentials::from_keys(
+            &s3_config.access_key, &s3_config.secret_access_key, None);
+        let s3_conf = Config::builder()
+            .credentials_provider(s3_credentials)
+            .endpoint_url(&s3_config.endpoint_url)
+            .region(Region::new(s3_config.region.to_string()))
+            .build();
+        return S3{
+            client: S3Client::from_conf(s3_conf),
+            bucket: s3_config.bucket.to_string(),
+            full_link: s3_config.full_link(),
+            public_link: s3_config.public_link(),
+            temp_dir: settings.temp_dir.clone(),
+            key_prefix: s3_config.key_prefix.trim_matches('/').to_string(),
+            key_template: s3_config.key_template.clone(),
+        };
+    }
+
+    /// `key` below key_prefix.
+    pub fn prefixed_key(&self, key: &str) -> String{
+        return match self.key_prefix.is_empty(){
+            true => key.to_string(),
+            false => format!("{}/{}", self.key_prefix, key),
+        };
+    }
+
+    /// what listings are limited to, so objects of other tools sharing the bucket are left alone.
+    pub fn list_prefix(&self) -> Option<String>{
+        return match self.key_prefix.is_empty(){
+            true => None,
+            false => Some(format!("{}/", self.key_prefix)),
+        };
+    }
+
+    pub fn object_url(&self, key: &str) -> String{
+        return format!("{}/{}", self.public_link, key);
+    }
+
+    /// key for an episode's audio from key_template. Placeholders that come out empty,
+    /// like {season} without one, drop their path segment.
+    pub fn episode_key(&self, channel_slug: &str, ep: &Item, ext: &str) -> String{
+        let published = parse_pub_date(&ep.pub_date)
+            .map(|date| date.with_timezone(&Utc))
+            .unwrap_or_else(Utc::now);
+        let key = self.key_template
+            .replace("{channel_slug}", channel_slug)
+            .replace("{year}", &published.format("%Y").to_string())
+            .replace("{month}", &published.format("%m").to_string())
+            .replace("{ep_number}", &ep.ep_number.to_string())
+            .replace("{season}", &ep.season.map(|season| season.to_string()).unwrap_or_default())
+            .replace("{id}", &ep.id)
+            .replace("{ext}", ext);
+        let key = key.split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>()
+            .join("/");
+        return self.prefixed_key(&key);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone,Debug)]
@@ -118,111 +320,89 @@ pub struct XmlRequestForm{
     pub external_id: String,
 }
 
-#[derive(Serialize, Deserialize, Clone,Debug)]
-pub struct Xml{
-    pub external_ids: Vec<String>,
-    pub titles: Vec<String>,
-    pub buffers: Vec<String>
-}
-
-impl Xml {
-    fn add_channel(&mut self, ch_id: String, ch_title: String){
-        self.external_ids.push(ch_id);
-        self.titles.push(ch_title);
-        self.buffers.push(String::new());
-    }
-
-    pub fn initialize(pg_conn_pool: PgPool) -> Self{
-
-        let xml = Arc::new(RwLock::new(Xml{
-            external_ids: Vec::new(),
-            titles: Vec::new(),
-            buffers: Vec::new(),
-        })); 
-
-        {
-            let channels = futures::executor::block_on(
-                sqlx::query!(r#"SELECT * FROM channel"#).fetch_all(&pg_conn_pool))
-                .unwrap();
-            let xml = xml.clone();
-            let pg_conn_pool = pg_conn_pool.clone();
-
-            tokio::spawn( async move {
-                for ch in channels.iter(){
-                    let buffer = refresh_xml_buffer(&ch.external_id.to_string(), &pg_conn_pool).await.unwrap();              
-                    xml.write().unwrap().buffers.push(buffer);
-                    xml.write().unwrap().external_ids.push(ch.external_id.to_string());
-                    xml.write().unwrap().titles.push(ch.title.clone());
-                }     
-            });
-        } 
-        
-        while Arc::strong_count(&xml) > 1{ // wait til tokio::spawn is through with xml
-            std::thread::sleep(std::time::Duration::from_secs(5));
-        }
-
-        return Arc::try_unwrap(xml).unwrap().into_inner().unwrap();
-    } 
-
-    pub fn get_vec_pos(&self, requested_id: &str) -> Option<usize> {
-        for (i, id) in self.external_ids.iter().enumerate() {
-            if requested_id == id{
-                return Some(i);
-            }
-        }
-        return None;
+/// GET RSS feed - d. `.atom`/`.json` suffix or the Accept header selects Atom or JSON Feed.
+/// Conditional GETs are answered with 304.
+pub async fn podcast(
+    req: HttpRequest,
+    ch_slug: web::Path<String>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    let (slug, format) = FeedFormat::from_request(&ch_slug, &req);
+    let suffix = &ch_slug[slug.len()..];
+    // old links: "My Show" and "my-show" both reach the channel slugged "my-show".
+    let slug = legacy_slug(slug);
+    let channel_feeds = match lookup_feed(&slug, &pg_conn_pool, &feed_cache).await?{
+        FeedLookup::Found(channel_feeds) => channel_feeds,
+        FeedLookup::Renamed(current) => return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, format!("/podcast/{}{}", current, suffix)))
+            .finish()),
+        FeedLookup::Missing => return Err(AppError::NotFound(format!("no feed for '{}'", slug))),
+    };
+    // only reachable through a subscriber's URL.
+    if channel_feeds.private{
+        return Err(AppError::NotFound(format!("no feed for '{}'", slug)));
     }
 
-    pub fn get_vec_pos_by_title(&self, requested_title: &str) -> Option<usize> {
-        let requested_title = requested_title.to_lowercase();
-        for (i, title) in self.titles.iter().enumerate() {
-            if requested_title == title.to_lowercase(){
-                return Some(i);
-            }
-        }
-        return None;
+    if let Some(moved_to) = channel_feeds.redirect(){
+        return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, moved_to))
+            .finish());
     }
+
+    return Ok(channel_feeds.get(format).respond(&req, format.content_type()));
 }
 
-/// GET RSS feed - d
-pub async fn podcast(
-    ch_title: web::Path<String>,
-    xml: web::Data<Arc<RwLock<Xml>>>
-) -> HttpResponse{
-    let xml = xml.read().unwrap();
-    //if let Some(i) = xml.get_vec_pos(&form.external_id){
-    let ch_title = ch_title.replace("-", " "); 
-    if let Some(i) = xml.get_vec_pos_by_title(&ch_title){
-        return HttpResponse::Ok()
-            .content_type(ContentType::xml())
-            .body(xml.buffers[i].clone())
-    }
-
-    return HttpResponse::BadRequest()
-        .content_type(ContentType::plaintext())
-        .body("invalid xml request");
+/// what a feed slug leads to.
+pub enum FeedLookup{
+    Found(Arc<ChannelFeeds>),
+    /// a retired slug; the channel's current one.
+    Renamed(String),
+    Missing,
+}
+
+/// cached feeds for a slug, rendered on a miss. Retired slugs are looked up in the history.
+pub(crate) async fn lookup_feed(slug: &str, pg_conn_pool: &PgPool, feed_cache: &FeedCache) -> Result<FeedLookup, AppError>{
+    if let Some(channel_feeds) = feed_cache.get_by_slug(slug){
+        return Ok(FeedLookup::Found(channel_feeds));
+    }
+    if let Some(channel_feeds) = feed_cache.load_by_slug(slug, pg_conn_pool).await?{
+        return Ok(FeedLookup::Found(channel_feeds));
+    }
+    return Ok(match slug_redirect(slug, pg_conn_pool).await?{
+        Some(current) => FeedLookup::Renamed(current),
+        None => FeedLookup::Missing,
+    });
 }
 
 /// GET channels data - d
-pub async fn channels(pg_conn_pool: web::Data<PgPool>) -> HttpResponse{
-    let channels: Vec<_> =  match sqlx::query!(
+pub async fn channels(pg_conn_pool: web::Data<PgPool>) -> Result<HttpResponse, AppError>{
+    let channels: Vec<_> = sqlx::query!(
         r#" SELECT * FROM channel "#
     )
     .fetch_all(pg_conn_pool.get_ref())
-    .await{
-        Ok(chs) => chs,
-        Err(_) => Vec::new()
-    };
+    .await?;
 
-    if channels.len() < 1 {
-        return HttpResponse::NoContent()
+    if channels.is_empty() {
+        return Ok(HttpResponse::NoContent()
             .content_type(ContentType::plaintext())
-            .body("No channels in DB");
+            .body("No channels in DB"));
+    }
+
+    let mut categories = HashMap::<Uuid, Vec<ChannelCategory>>::new();
+    for c in sqlx::query!(r#" SELECT * FROM channel_category ORDER BY channel_id, position "#)
+        .fetch_all(pg_conn_pool.get_ref())
+        .await?{
+        categories.entry(c.channel_id).or_default().push(ChannelCategory{
+            category: c.category,
+            subcategory: c.subcategory,
+        });
     }
 
     let mut response_ser_json = String::new();
     channels.into_iter().for_each(|c|{ 
         let ch = Channel {
+            categories: categories.remove(&c.external_id).unwrap_or_default(),
             id: c.id,
             external_id: c.external_id.to_string(),
             title: c.title,
@@ -245,7 +425,10 @@ pub async fn channels(pg_conn_pool: web::Data<PgPool>) -> HttpResponse{
             itunes_owner_email: c.itunes_owner_email,
             sy_update_period: c.sy_update_period,
             sy_update_frequency: c.sy_update_frequency,
-        
+            itunes_type: c.itunes_type,
+            max_items: c.max_items,
+            slug: c.slug,
+            custom_path: c.custom_path,
         };
             
         let serialized_c = serde_json::ser::to_string(&ch).unwrap();
@@ -256,28 +439,26 @@ pub async fn channels(pg_conn_pool: web::Data<PgPool>) -> HttpResponse{
         response_ser_json = format!("{{{}}}", response_ser_json);
     }
 
-    return HttpResponse::Ok()
+    return Ok(HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(response_ser_json);
+        .body(response_ser_json));
 }
 
 /// GET episode metadata - d
 pub async fn episode(
     episode: web::Json<ItemAbbreviated>,
     pg_conn_pool: web::Data<PgPool>,
-) -> HttpResponse{
+) -> Result<HttpResponse, AppError>{
     let res = match sqlx::query!(
         r#"SELECT * FROM item WHERE id = $1"#,
-        Uuid::parse_str(&episode.into_inner().id).unwrap()
+        parse_uuid("id", &episode.into_inner().id)?
     ).fetch_optional(pg_conn_pool.get_ref())
-    .await
-    .unwrap(){
+    .await?{
         Some(e) => {
             e
         },
         None => {
-            return HttpResponse::NoContent()
-                .body("No channels in DB");
+            return Err(AppError::NotFound("episode does not exist".to_string()));
         }
     };
 
@@ -299,81 +480,157 @@ pub async fn episode(
         itunes_subtitle: res.itunes_subtitle,
         itunes_image: res.itunes_image,
         itunes_duration: res.itunes_duration,
+        season: res.season,
+        guid: res.guid,
+        enclosure_sha256: res.enclosure_sha256,
+        object_key: res.object_key,
     };
 
     let mut response_ser_json = serde_json::ser::to_string(&ep).unwrap(); 
     response_ser_json = format!("{{{}}}", response_ser_json);
 
-    return HttpResponse::Ok()
+    return Ok(HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(response_ser_json);
+        .body(response_ser_json));
 }
 
 /// POST modify episode metadata - d
 pub async fn edit_episode(
     updated_ep: web::Json<Item>,
     pg_conn_pool: &web::Data<PgPool>,
-    s3: &web::Data<S3>
-) -> HttpResponse{
+    s3: &web::Data<S3>,
+    feed_cache: &FeedCache,
+) -> Result<HttpResponse, AppError>{
     let ep = updated_ep.into_inner();
+    ep.validate()?;
+    let ep_id = parse_uuid("id", &ep.id)?;
+    let channel_id = parse_uuid("channel_id", &ep.channel_id)?;
 
-    if !(episode_exists(&Uuid::parse_str(&ep.id).unwrap(), &pg_conn_pool, &s3).await) { // TODO refactor
-        return HttpResponse::BadRequest()
-            .content_type(ContentType::plaintext())
-            .body("episode does not exist");
-    }
-
-    match sqlx::query!(r#"
-        UPDATE item SET channel_id = $1, ep_number = $2, title = $3, author = $4, description = $5,
-        content_encoded = $6, enclosure_url = $7, enclosure_type = $8, enclosure_length = $9, i_link = $10, 
-        pub_date = $11, itunes_subtitle = $12, itunes_image = $13, itunes_duration = $14 WHERE id = $15
-        "#, Uuid::parse_str(&ep.channel_id).unwrap(), ep.ep_number, ep.author, ep.category, ep.description,
-        ep.content_encoded, ep.enclosure_url, ep.enclosure_type, ep.enclosure_length, ep.i_link, 
-        ep.pub_date, ep.itunes_subtitle, ep.itunes_image, ep.itunes_duration,
-        Uuid::parse_str(&ep.id).unwrap()
-    ).execute(pg_conn_pool.get_ref()).await{
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(_) => HttpResponse::InternalServerError()
-            .content_type(ContentType::plaintext())
-            .body("Failed to edit DB"),
+    if !(episode_exists(&ep_id, pg_conn_pool, s3).await?) { // TODO refactor
+        return Err(AppError::NotFound("episode does not exist".to_string()));
     }
+    let old_channel_id = sqlx::query!(
+        r#" SELECT channel_id FROM item WHERE id = $1 "#, ep_id
+    ).fetch_one(pg_conn_pool.get_ref()).await?.channel_id;
+
+    sqlx::query!(r#"
+        UPDATE item SET channel_id = $1, ep_number = $2, title = $3, author = $4, category = $5, 
+        description = $6, content_encoded = $7, enclosure_url = $8, enclosure_type = $9, enclosure_length = $10,
+        i_link = $11, pub_date = $12, itunes_subtitle = $13, itunes_image = $14, itunes_duration = $15,
+        season = $16 WHERE id = $17
+        "#, channel_id, ep.ep_number, ep.title, ep.author, ep.category, 
+        ep.description, ep.content_encoded, ep.enclosure_url, ep.enclosure_type, ep.enclosure_length, 
+        ep.i_link, ep.pub_date, ep.itunes_subtitle, ep.itunes_image, ep.itunes_duration, ep.season,
+        ep_id
+    ).execute(pg_conn_pool.get_ref()).await?;
+
+    refresh_channel_feed(&channel_id.to_string(), pg_conn_pool, feed_cache).await?;
+    if old_channel_id != channel_id{
+        refresh_channel_feed(&old_channel_id.to_string(), pg_conn_pool, feed_cache).await?;
+    }
+    return Ok(HttpResponse::Ok().finish());
 }
 
-pub async fn replace_episode_audio() -> HttpResponse{
-    todo!() 
+/// POST (multipart) swap an episode's audio for a new file; the rest of the episode, its guid
+/// included, stays. The new audio gets its own key, so nothing keeps serving the old file
+/// under the old URL, and the old object is removed once the row points at the new one.
+pub async fn replace_episode_audio(
+    payload: MultipartForm<ReplaceAudioForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+    delete_queue: web::Data<S3DeleteQueue>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&payload.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let ep_id = parse_uuid("episode_id", &payload.episode_id)?;
+    let mut ep = match sqlx::query_as!(Item, r#"
+        SELECT id::TEXT AS "id!", channel_id::TEXT AS "channel_id!", ep_number, title, author, category,
+        description, content_encoded, enclosure_url, enclosure_type, enclosure_length, i_link, pub_date,
+        itunes_subtitle, itunes_image, itunes_duration, season, guid, enclosure_sha256, object_key
+        FROM item WHERE id = $1
+        "#, ep_id
+    ).fetch_optional(pg_conn_pool.get_ref())
+    .await?{
+        Some(ep) => ep,
+        None => return Err(AppError::NotFound("episode does not exist".to_string())),
+    };
+    let channel_id = parse_uuid("channel_id", &ep.channel_id)?;
+    let slug = sqlx::query!(
+        r#" SELECT slug FROM channel WHERE external_id = $1 "#, channel_id
+    ).fetch_one(pg_conn_pool.get_ref())
+    .await?
+    .slug;
+
+    let mut key_ep = ep.clone();
+    key_ep.id = Uuid::new_v4().to_string();
+    let object_key = s3.episode_key(&slug, &key_ep, "mp3");
+    let acl = media_acl(&ep.channel_id, &pg_conn_pool).await?;
+    let checksums = upload_file(&object_key, payload.audio.file.path(), "audio/mpeg", acl, &s3).await?;
+
+    let previous_key = ep.object_key.replace(object_key.clone());
+    ep.enclosure_url = s3.object_url(&object_key);
+    ep.enclosure_type = "audio/mpeg".to_string();
+    ep.enclosure_length = checksums.size.to_string();
+    ep.enclosure_sha256 = Some(checksums.sha256);
+    if let Err(e) = sqlx::query!(r#"
+        UPDATE item SET enclosure_url = $1, enclosure_type = $2, enclosure_length = $3,
+        enclosure_sha256 = $4, object_key = $5 WHERE id = $6
+        "#, ep.enclosure_url, ep.enclosure_type, ep.enclosure_length, ep.enclosure_sha256, ep.object_key, ep_id
+    ).execute(pg_conn_pool.get_ref())
+    .await{
+        rollback_s3_upload(&object_key, &s3, &delete_queue).await;
+        return Err(e.into());
+    }
+    if let Some(previous_key) = previous_key{
+        if delete_from_s3_bucket(&previous_key, &s3).await.is_err(){
+            delete_queue.push(&previous_key);
+        }
+    }
+
+    refresh_channel_feed(&ep.channel_id, &pg_conn_pool, &feed_cache).await?;
+    return Ok(HttpResponse::Ok().json(ep));
 }
 
 /// POST modify channel metadata - d
 pub async fn edit_channel(
     updated_ch: web::Json<Channel>,
     pg_conn_pool: &web::Data<PgPool>,
-) -> HttpResponse {
-    let ch = updated_ch.into_inner();
-    if!(channel_exists(&ch.title, &pg_conn_pool).await) {
-        return HttpResponse::BadRequest()
-            .content_type(ContentType::plaintext())
-            .body("channel does not exist");
+    feed_cache: &FeedCache,
+) -> Result<HttpResponse, AppError> {
+    let mut ch = updated_ch.into_inner();
+    ch.validate()?;
+    let external_id = parse_uuid("external_id", &ch.external_id)?;
+    if !(channel_exists(&external_id, pg_conn_pool).await?) {
+        return Err(AppError::NotFound("channel does not exist".to_string()));
     };
+    let categories = ch.category_pairs();
+    ch.category = categories[0].category.clone();
 
-    match sqlx::query!(r#"
+    let mut tx = pg_conn_pool.begin().await?;
+    sqlx::query!(r#"
             UPDATE channel SET title = $1, category = $2, description = $3,  
             managing_editor = $4, generator = $5, image_url = $6, image_title = $7, 
             image_link = $8, image_width = $9, image_height = $10, language = $11, 
             last_build_date = $12, pub_date = $13, c_link = $14, itunes_new_feed_url = $15, 
             itunes_explicit = $16, itunes_owner_name = $17, itunes_owner_email = $18, 
-            sy_update_period = $19, sy_update_frequency = $20 WHERE external_id = $21
+            sy_update_period = $19, sy_update_frequency = $20, itunes_type = $21, 
+            max_items = $22 WHERE external_id = $23
         "#, ch.title, ch.category, ch.description, ch.managing_editor, ch.generator, 
         ch.image_url, ch.image_title, ch.image_link, ch.image_width, ch.image_height,
         ch.language, ch.last_build_date, ch.pub_date, ch.c_link, ch.itunes_new_feed_url,
         ch.itunes_explicit, ch.itunes_owner_name, 
         ch.itunes_owner_email, ch.sy_update_period,
-        ch.sy_update_frequency, Uuid::parse_str(&ch.external_id).unwrap()
-    ).execute(pg_conn_pool.get_ref()).await{
-        Ok(_) => HttpResponse::Ok().finish(),
-        Err(_) => HttpResponse::InternalServerError()
-            .content_type(ContentType::plaintext())
-            .body("Failed to edit DB"),
-    }
+        ch.sy_update_frequency, ch.itunes_type, ch.max_items, 
+        external_id
+    ).execute(&mut tx).await?;
+    store_channel_categories(&mut tx, &external_id, &categories).await?;
+    tx.commit().await?;
+
+    refresh_channel_feed(&external_id.to_string(), pg_conn_pool, feed_cache).await?;
+    return Ok(HttpResponse::Ok().finish());
 }
 
 /// POST multipart upload. Note: new id is assigned for episodes by default.
@@ -382,107 +639,179 @@ pub async fn upload(
     payload: MultipartForm::<PodcastDataV2>, 
     active_tokens: web::Data<RwLock<ActiveTokens>>,
     pg_conn_pool: web::Data<PgPool>,
-    xml: web::Data<Arc<RwLock<Xml>>>,
+    feed_cache: web::Data<FeedCache>,
     s3: web::Data<S3>,
-) -> HttpResponse{
-    let mut podcast_data = payload.podcast_data.clone();
+    delete_queue: web::Data<S3DeleteQueue>,
+) -> Result<HttpResponse, AppError>{
+    let podcast_data = payload.podcast_data.clone();
 
     if !is_valid_token(&podcast_data.session_token, active_tokens).await{
-        return HttpResponse::Unauthorized().finish();
+        return Err(AppError::Unauthorized);
     } 
 
-    let mut ch = podcast_data.channel.clone();
-    let ep = &mut podcast_data.item;
+    let files = EpisodeFiles{
+        id: Uuid::new_v4(),
+        audio: Some(payload.audio.file.path()),
+        audio_size: payload.audio.size as u64,
+        artwork: payload.artwork.as_ref().map(|artwork| artwork.file.path()),
+        object_key: None,
+    };
+    publish_episode(podcast_data, files, &pg_conn_pool, &feed_cache, &s3, &delete_queue).await?;
 
-    if ch.external_id != ep.channel_id{
-        return HttpResponse::BadRequest()
-            .content_type(ContentType::plaintext())
-            .body(format!("channel_id and episode do not match"));
+    return Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body("upload successful!"));
+}
+
+/// bucket key for an episode's audio: key_template filled in with the slug its channel
+/// has, or will get when the upload creates it.
+pub(crate) async fn episode_object_key(
+    podcast_data: &PodcastData,
+    ep_id: &Uuid,
+    s3: &S3,
+    pg_conn_pool: &PgPool,
+) -> Result<String, AppError>{
+    let ch = &podcast_data.channel;
+    let stored_slug = match Uuid::parse_str(&ch.external_id){
+        Ok(external_id) => sqlx::query!(
+            r#" SELECT slug FROM channel WHERE external_id = $1 "#, external_id
+        ).fetch_optional(pg_conn_pool)
+        .await?
+        .map(|row| row.slug),
+        Err(_) => None,
+    };
+    let slug = match stored_slug{
+        Some(slug) => slug,
+        None if !ch.slug.is_empty() => ch.slug.clone(),
+        None => slugify(&ch.title),
+    };
+    let mut ep = podcast_data.item.clone();
+    ep.id = ep_id.to_string();
+    return Ok(s3.episode_key(&slug, &ep, "mp3"));
+}
+
+/// the id, enclosure fields and validation `publish_episode` applies, without storing anything.
+pub(crate) fn prepare_episode(
+    podcast_data: &mut PodcastData,
+    ep_id: &Uuid,
+    audio_size: u64,
+    object_key: &str,
+    s3: &S3,
+) -> Result<(), AppError>{
+    let ep = &mut podcast_data.item;
+    if podcast_data.channel.external_id != ep.channel_id{
+        return Err(AppError::Validation("channel_id and episode do not match".to_string()));
     }
 
-    ep.id = Uuid::new_v4().to_string();
-    ep.enclosure_url = format!("{}/{}.mp3", &s3.full_link, &ep.id);
+    ep.id = ep_id.to_string();
+    ep.enclosure_url = s3.object_url(object_key);
     ep.enclosure_type = "audio/mpeg".to_string();
-    ep.enclosure_length = payload.audio.size.to_string();
+    ep.enclosure_length = audio_size.to_string();
+    ep.enclosure_sha256 = None;
+    ep.object_key = Some(object_key.to_string());
+    podcast_data.validate()?;
+    return Ok(());
+}
 
-    match upload_to_s3_bucket_v2(&ep.id, &payload.audio.file.path(), &s3)
-        .await{
-            Ok(_) => {},
+/// an episode's media, waiting to be published.
+pub struct EpisodeFiles<'a>{
+    pub id: Uuid,
+    /// None: already in the bucket under `object_key`, e.g. a presigned upload.
+    pub audio: Option<&'a Path>,
+    pub audio_size: u64,
+    pub artwork: Option<&'a Path>,
+    /// where the audio is or goes; from key_template when None.
+    pub object_key: Option<&'a str>,
+}
+
+/// publish one episode from audio (and artwork) on disk: store artwork, upload the audio,
+/// insert the rows and refresh the feed. What was stored is removed again on failure.
+/// Returns the new episode id.
+pub(crate) async fn publish_episode(
+    mut podcast_data: PodcastData,
+    files: EpisodeFiles<'_>,
+    pg_conn_pool: &PgPool,
+    feed_cache: &FeedCache,
+    s3: &web::Data<S3>,
+    delete_queue: &S3DeleteQueue,
+) -> Result<Uuid, AppError>{
+    let EpisodeFiles{ id: ep_uuid, audio, audio_size, artwork, object_key } = files;
+    let object_key = match object_key{
+        Some(object_key) => object_key.to_string(),
+        None => episode_object_key(&podcast_data, &ep_uuid, s3, pg_conn_pool).await?,
+    };
+    prepare_episode(&mut podcast_data, &ep_uuid, audio_size, &object_key, s3)?;
+
+    // artwork first: it's the part most likely to be rejected, before the audio is sent.
+    if let Some(artwork) = artwork{
+        let variants = store_artwork(&ep_uuid, artwork, s3, pg_conn_pool).await?;
+        podcast_data.item.itunes_image = variants[0].url.clone();
+    }
+
+    if let Some(audio) = audio{
+        let acl = media_acl(&podcast_data.channel.external_id, pg_conn_pool).await?;
+        match upload_to_s3_bucket_v2(&object_key, audio, acl, s3).await{
+            Ok(checksums) => podcast_data.item.enclosure_sha256 = Some(checksums.sha256),
             Err(e) => {
-                log::info!("Error -- podcast::upload(): upload_to_s3() unsuccessful. Err: {}", e);
-                return HttpResponse::InternalServerError()
-                    .content_type(ContentType::plaintext())
-                    .body(e);
+                log::info!("Error -- podcast::publish_episode(): upload_to_s3() unsuccessful. Err: {}", e);
+                rollback_episode_artwork(&ep_uuid, artwork.is_some(), s3, pg_conn_pool).await;
+                return Err(e);
             },
-        };
+        }
+    }
 
-    ch.external_id = match store_to_db(&mut podcast_data, &pg_conn_pool, &xml).await{
+    let ch_external_id = match store_to_db(&mut podcast_data, pg_conn_pool).await{
         Ok(ext_id) => ext_id,
         Err(e) => {
-            log::info!("Error -- podcast::upload(): store_to_db() unsuccessful. Err: {}", e);
-            _ = delete_from_s3_bucket(&podcast_data.item.id, &s3).await.unwrap(); // fails are silent.
-            return HttpResponse::InternalServerError()
-                .content_type(ContentType::plaintext())
-                .body(e);
+            log::info!("Error -- podcast::publish_episode(): store_to_db() unsuccessful. Err: {}", e);
+            // audio we didn't upload stays, so the caller can retry.
+            if audio.is_some(){
+                rollback_s3_upload(&object_key, s3, delete_queue).await;
+            }
+            rollback_episode_artwork(&ep_uuid, artwork.is_some(), s3, pg_conn_pool).await;
+            return Err(e);
         }
     }; 
    
-    /* std::thread::sleep(std::time::Duration::from_secs(10)); */
-    let xml_pos = match xml.read().unwrap().get_vec_pos(&ch.external_id){
-        Some(pos) => pos,
-        None => {
-            return HttpResponse::InternalServerError()
-                .content_type(ContentType::plaintext())
-                .body("could not find xml buffer ---- no vec pos --- 439");
-        }
-    };
-        
-    xml.write().unwrap().buffers[xml_pos] = 
-        match refresh_xml_buffer(&ch.external_id, &pg_conn_pool).await{
-            Ok(b) => b,
-            Err(err) => {
-                let e = format!("could not refresh_xml_buffer; Err: {}", err);
-                log::info!("{}", e);
-                return HttpResponse::InternalServerError()
-                    .content_type(ContentType::plaintext())
-                    .body(e);
-            }
-        };
-
-    return HttpResponse::Ok()
-        .content_type(ContentType::plaintext())
-        .body("upload successful!")
+    refresh_channel_feed(&ch_external_id, pg_conn_pool, feed_cache).await?;
+    return Ok(ep_uuid);
 }
 
 /// POST media file, return media file id and file size
-pub async fn upload_object(mut payload: Multipart, s3: web::Data<S3>) -> HttpResponse{
+pub async fn upload_object(mut payload: Multipart, s3: web::Data<S3>) -> Result<HttpResponse, AppError>{
     let temp_dir = s3.get_ref().temp_dir.clone();
     let file_id = Uuid::new_v4().to_string();
     let temp_file = format!("{}/{}", temp_dir, file_id);
 
-    while let Ok(Some(mut field)) = payload.try_next().await{
+    while let Some(mut field) = payload.try_next().await
+        .map_err(|e| AppError::Validation(format!("malformed multipart body: {}", e)))?{
         let temp_file = temp_file.clone();
         let mut file = web::block(move|| std::fs::File::create(temp_file)) 
             .await
-            .unwrap().unwrap(); //review TODO
-        while let  Some(chunk) = field.next().await{
-            let data = chunk.unwrap();
+            .map_err(|e| AppError::Internal(e.to_string()))?
+            .map_err(|e| AppError::Storage(format!("could not create temp file: {}", e)))?;
+        while let Some(chunk) = field.next().await{
+            let data = chunk
+                .map_err(|e| AppError::Validation(format!("upload interrupted: {}", e)))?;
             file = web::block(move|| file.write_all(&data).map(|_| file) )
                 .await
-                .unwrap().unwrap() // review TODO
+                .map_err(|e| AppError::Internal(e.to_string()))?
+                .map_err(|e| AppError::Storage(format!("could not write temp file: {}", e)))?;
         }
     }
 
     let response = UploadObjectResponse{
         file_id,
-        file_size: fs::metadata(&temp_file).unwrap().len(),
+        file_size: fs::metadata(&temp_file)
+            .map_err(|_| AppError::Validation("no file in request".to_string()))?
+            .len(),
     };
    
     let response = serde_json::ser::to_string(&response).unwrap();
 
-    return HttpResponse::Ok()
+    return Ok(HttpResponse::Ok()
         .content_type(ContentType::json())
-        .body(response);
+        .body(response));
 }
 
 /// POST Channel/Episode - near // linode
@@ -491,152 +820,146 @@ pub async fn upload_form(
     s3: web::Data<S3>,
     active_tokens: web::Data<RwLock<ActiveTokens>>,
     pg_conn_pool: web::Data<PgPool>,
-    xml: web::Data<Arc<RwLock<Xml>>>
-) -> HttpResponse {
+    feed_cache: web::Data<FeedCache>,
+    delete_queue: web::Data<S3DeleteQueue>,
+) -> Result<HttpResponse, AppError> {
 
     if !is_valid_token(&podcast_data.session_token, active_tokens).await{
-        return HttpResponse::Unauthorized().finish();
+        return Err(AppError::Unauthorized);
     } 
 
-    let podcast_data = &mut podcast_data.into_inner();
-    let ch = &podcast_data.channel;
+    let podcast_data = podcast_data.into_inner();
 
+    // file_id from upload_object() becomes the episode id; must not escape temp_dir.
+    let ep_uuid = parse_uuid("item.id", &podcast_data.item.id)?;
     let file_path = format!("{}/{}", s3.temp_dir, &podcast_data.item.id);
-    if !Path::new(&file_path).exists(){
-        return HttpResponse::BadRequest()
-            .content_type(ContentType::plaintext())
-            .body("at least 1 file_id does not exist");
-    } 
-
-    let ep = &mut podcast_data.item;
+    let file_size = match fs::metadata(&file_path){
+        Ok(meta) => meta.len(),
+        Err(_) => {
+            return Err(AppError::Validation("at least 1 file_id does not exist".to_string()));
+        },
+    };
 
-    if ch.external_id != ep.channel_id{
-        return HttpResponse::BadRequest()
-            .content_type(ContentType::plaintext())
-            .body("ch.external_id != ep.channel_id. \n");
+    let files = EpisodeFiles{
+        id: ep_uuid,
+        audio: Some(Path::new(&file_path)),
+        audio_size: file_size,
+        artwork: None,
+        object_key: None,
+    };
+    let published = publish_episode(podcast_data, files, &pg_conn_pool, &feed_cache, &s3, &delete_queue).await;
+    // uploaded or rejected, the file is done with; a retry starts at upload_object().
+    if let Err(e) = fs::remove_file(&file_path){
+        log::error!("upload_form: could not remove {}. Err: {}", file_path, e);
     }
-    ep.enclosure_url = format!("{}/{}.mp3", &s3.full_link, &ep.id);
-    ep.enclosure_type = "audio/mpeg".to_string();
-    ep.enclosure_length = fs::metadata(&file_path).unwrap().len().to_string();
+    published?;
 
-
-    upload_to_s3_bucket(&[&podcast_data.item.id], &s3).await.unwrap();
-    podcast_data.channel.external_id = store_to_db(podcast_data, &pg_conn_pool, &xml)
-        .await.unwrap();
-    let ch_external_id = podcast_data.channel.external_id.clone();
-    let xml_pos = match xml.read().unwrap().get_vec_pos(&ch_external_id){
-        Some(pos) => pos,
-        None => {
-            return HttpResponse::InternalServerError()
-                .content_type(ContentType::plaintext())
-                .body("could not find xml buffer");
-        },
-    };
-    let mut xml = xml.write().unwrap();
-    xml.buffers[xml_pos] = refresh_xml_buffer(&ch_external_id, pg_conn_pool.get_ref()).await.unwrap();
-    HttpResponse::Ok()
+    return Ok(HttpResponse::Ok()
         .content_type(ContentType::plaintext())
-        .body("upload complete")
+        .body("upload complete"));
 }
 
-async fn upload_to_s3_bucket_v2(ep_id: &str, path: &Path, s3: &web::Data<S3>) -> Result<(), &'static str>{
-    let s3 = s3.get_ref();
-    let path = match path.to_str(){
-        Some(p) => p,
-        None => {
-            return Err("invalid unicode or non existent path.")
-        },
-    };
-
-    let stream = ByteStream::from_path(path)
-        .await
-        .unwrap();
-    
-    return match s3.client.put_object()
-        .bucket(&s3.bucket)
-        .key(format!("{}.mp3", ep_id))
-        .acl(ObjectCannedAcl::PublicRead)
-        .content_type("application/mp3")
-        .body(stream)
-        .send()
-        .await{
-            Ok(_) => Ok(()),
-            Err(_) => {
-                let e = "Failed to upload to S3.";
-                log::error!("{}", e);
-                Err(e)
-            }
-        };
+/// upload an episode's audio to `object_key`, multipart when large, verified once stored.
+/// `acl` from media_acl(), private channels' audio isn't public-read.
+pub(crate) async fn upload_to_s3_bucket_v2(object_key: &str, path: &Path, acl: ObjectCannedAcl, s3: &web::Data<S3>) -> Result<ObjectChecksums, AppError>{
+    return upload_file(object_key, path, "application/mp3", acl, s3.get_ref()).await;
 }
 
-async fn delete_from_s3_bucket(ep_id: &str, s3: &web::Data<S3>) -> Result<(), &'static str>{
+pub(crate) async fn delete_from_s3_bucket(object_key: &str, s3: &S3) -> Result<(), AppError>{
     return match s3.client.delete_object()
         .bucket(&s3.bucket)
-        .key(format!("{}.mp3", ep_id))
+        .key(object_key)
         .send()
         .await{
             Ok(_) => Ok(()),
-            Err(_) => {
-                let e = "Failed to delete from S3.";
-                log::error!("{}", e);
-                Err(e)
+            Err(e) => {
+                log::error!("Failed to delete from S3. Err: {}", e);
+                Err(AppError::Storage("Failed to delete from S3.".to_string()))
             }
         };
 }
 
-// TODO: partial upload if some succeed. CRITICAL - leaves good uploads on server if all fail.
-// Note impl Display for test. // UPDATE: addressed in upload(). Marked for removal.
-/// upload to s3, failure control not implemented
-async fn upload_to_s3_bucket(file_ids: &[impl Display], s3: &web::Data<S3>) -> Result<(), &'static str>{
-    let s3 = s3.get_ref();
-    for file_id in file_ids{
-        let stream = ByteStream::from_path(&format!("{}/{}", s3.temp_dir, file_id))
-            .await
-            .unwrap();
-        let upload_ok = match s3.client.put_object()
-            .bucket(&s3.bucket)
-            .key(format!("{}.mp3", file_id))
-            .acl(ObjectCannedAcl::PublicRead)
-            .content_type("application/mp3")
-            .body(stream)
-            .send()
-            .await{
-                Ok(_) => true,
-                Err(_) => false,
-            };
-        if !upload_ok{
-            return Err("failed to upload to S3. Error not logged");
+/// Failed S3 deletes from upload rollbacks. Each key is retried in the background
+/// with backoff; anything still failing after the last attempt is logged for manual cleanup.
+#[derive(Clone, Debug)]
+pub struct S3DeleteQueue(pub UnboundedSender<String>);
+
+impl S3DeleteQueue{
+    const MAX_ATTEMPTS: u32 = 8;
+
+    pub fn start(s3: S3) -> Self{
+        let (sender, mut receiver) = unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(object_key) = receiver.recv().await{
+                let s3 = s3.clone();
+                tokio::spawn(async move {
+                    for attempt in 1..=Self::MAX_ATTEMPTS{
+                        // 2s, 4s, 8s ... ~4min
+                        tokio::time::sleep(std::time::Duration::from_secs(1 << attempt)).await;
+                        if delete_from_s3_bucket(&object_key, &s3).await.is_ok(){
+                            log::info!("S3DeleteQueue: removed {} on attempt {}", object_key, attempt);
+                            return;
+                        }
+                    }
+                    log::error!("S3DeleteQueue: giving up on {} after {} attempts. Remove manually.",
+                        object_key, Self::MAX_ATTEMPTS);
+                });
+            }
+        });
+        return S3DeleteQueue(sender);
+    }
+
+    pub fn push(&self, object_key: &str){
+        if self.0.send(object_key.to_string()).is_err(){
+            log::error!("S3DeleteQueue: worker is gone, {} left in bucket", object_key);
         }
     }
-    return Ok(());
 }
 
-/// check that channel exists in db and on linode. - d
-async fn channel_exists(ch_title: &str, pg_conn_pool: &web::Data<PgPool>
-)-> bool{
-    match sqlx::query!(
-        r#" SELECT id FROM channel WHERE LOWER(title) = $1 "#, ch_title.to_lowercase()
-    ).fetch_optional(pg_conn_pool.get_ref())
-        .await
-        .unwrap(){
-            Some(_) => true,
-            None => false,
+/// remove an uploaded object after a failed publish. Queued for retry if the delete fails.
+pub(crate) async fn rollback_s3_upload(object_key: &str, s3: &S3, delete_queue: &S3DeleteQueue){
+    if delete_from_s3_bucket(object_key, s3).await.is_err(){
+        log::info!("rollback_s3_upload: queueing {} for retry", object_key);
+        delete_queue.push(object_key);
+    }
+}
+
+async fn rollback_episode_artwork(ep_id: &Uuid, uploaded: bool, s3: &S3, pg_conn_pool: &PgPool){
+    if !uploaded{
+        return;
+    }
+    if let Err(e) = remove_artwork(ep_id, s3, pg_conn_pool).await{
+        log::error!("rollback_episode_artwork: could not remove artwork of {}. Err: {}", ep_id, e);
     }
 }
 
+/// check that channel exists in db, by external_id since the title may be what's being edited. - d
+async fn channel_exists(external_id: &Uuid, pg_conn_pool: &web::Data<PgPool>
+)-> Result<bool, AppError>{
+    return Ok(sqlx::query!(
+        r#" SELECT id FROM channel WHERE external_id = $1 "#, external_id
+    ).fetch_optional(pg_conn_pool.get_ref())
+        .await?
+        .is_some());
+}
+
 /// check that episode exists in db and on linode. - d / messy
 async fn episode_exists(
     ep_id: &Uuid, 
     pg_conn_pool: &web::Data<PgPool>,
     s3: &web::Data<S3>
-) -> bool{
-    _ = match sqlx::query!(
-        r#" SELECT ep_number FROM item WHERE id = $1 "#, ep_id
+) -> Result<bool, AppError>{
+    let object_key = match sqlx::query!(
+        r#" SELECT object_key FROM item WHERE id = $1 "#, ep_id
     ).fetch_optional(pg_conn_pool.get_ref())
-        .await
-        .unwrap(){
-            Some(_) => (),
-            None => { return false; },
+        .await?{
+            Some(row) => row.object_key,
+            None => return Ok(false),
+    };
+    // hosted elsewhere, nothing of ours to look for.
+    let object_key = match object_key{
+        Some(object_key) => object_key,
+        None => return Ok(true),
     };
     
     let (s3_client, s3_bucket) = (
@@ -644,87 +967,220 @@ async fn episode_exists(
         s3.get_ref().bucket.clone(),
     );
 
-    match s3_client
+    return Ok(s3_client
         .get_object_acl()
-        .key(format!("{}.mp3", ep_id.to_string()))
+        .key(object_key)
         .bucket(s3_bucket)
         .send()
-        .await{
-            Ok(_) => true,
-            Err (_) => false,
-    }
+        .await
+        .is_ok());
 }
 
 /// store episode data in db - d
+/// channel insert (if new) and item insert share a transaction; nothing is kept on failure.
 async fn store_to_db(
-    podcast_data: &mut PodcastData, 
-    pg_conn_pool: &web::Data<PgPool>,
-    xml: &web::Data<Arc<RwLock<Xml>>>,
-)-> Result<String, &'static str>{
-    //TODO temp, this should rarely fail. Error is worth attention when it does.
-    //Work on error handling.
-    let ch = podcast_data.channel.clone(); // redo.
+    podcast_data: &mut PodcastData,
+    pg_conn_pool: &PgPool,
+)-> Result<String, AppError>{
+    let mut ch = podcast_data.channel.clone(); // redo.
     let ep = &mut podcast_data.item;
 
-    if !(channel_exists(&ch.title, &pg_conn_pool).await){
-        let new_external_id = Uuid::new_v4();
-        sqlx::query!(r#"
+    let mut tx = pg_conn_pool.begin().await?;
+    if let Some(new_external_id) = insert_channel(&mut tx, &mut ch).await?{
+        ep.channel_id = new_external_id.to_string();
+    }
+    insert_item(&mut tx, ep).await?;
+    tx.commit().await?;
+
+    // the channel the item was stored under; new channels get a server side id.
+    return Ok(ep.channel_id.clone());
+}
+
+/// insert a channel with its categories under a new external_id, deriving the slug from
+/// the title when none is given. None if a channel with this title (any case) exists.
+pub(crate) async fn insert_channel(
+    tx: &mut Transaction<'_, Postgres>,
+    ch: &mut Channel,
+) -> Result<Option<Uuid>, AppError>{
+    let categories = ch.category_pairs();
+    ch.category = categories[0].category.clone();
+    let new_external_id = Uuid::new_v4();
+    if ch.slug.is_empty(){
+        ch.slug = slugify(&ch.title);
+    }
+    if ch.slug.is_empty(){
+        ch.slug = format!("channel-{}", new_external_id.simple());
+    }
+
+    let new_channel = sqlx::query!(r#"
             INSERT INTO channel (external_id, title, category, description, managing_editor,
             generator, image_url, image_title, image_link, image_width, image_height, language,
             last_build_date, pub_date, c_link, itunes_new_feed_url, itunes_explicit, itunes_owner_name,
-            itunes_owner_email, sy_update_period, sy_update_frequency)
+            itunes_owner_email, sy_update_period, sy_update_frequency, itunes_type, max_items,
+            slug, custom_path)
             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16,
-            $17, $18, $19, $20, $21)
+            $17, $18, $19, $20, $21, $22, $23, $24, $25)
+            ON CONFLICT ((LOWER(title))) DO NOTHING
+            RETURNING external_id
             "#, new_external_id, ch.title, ch.category, ch.description, 
             ch.managing_editor, ch.generator, ch.image_url, ch.image_title, ch.image_link, ch.image_width, 
             ch.image_height, ch.language,ch.last_build_date, ch.pub_date, ch.c_link, 
             ch.itunes_new_feed_url, ch.itunes_explicit, 
             ch.itunes_owner_name, ch.itunes_owner_email, 
-            ch.sy_update_period, ch.sy_update_frequency
-        ).execute(pg_conn_pool.get_ref())
-        .await
-        .unwrap();
+            ch.sy_update_period, ch.sy_update_frequency,
+            ch.itunes_type, ch.max_items,
+            ch.slug, ch.custom_path,
+        ).fetch_optional(&mut *tx)
+        .await?;
+
+    let new_channel = match new_channel{
+        Some(new_channel) => new_channel,
+        None => return Ok(None),
+    };
+    ch.external_id = new_channel.external_id.to_string();
+    store_channel_categories(tx, &new_channel.external_id, &categories).await?;
+    // a retired slug handed to a new channel stops redirecting.
+    sqlx::query!(r#" DELETE FROM channel_slug_history WHERE slug = $1 "#, ch.slug)
+        .execute(&mut *tx)
+        .await?;
+
+    return Ok(Some(new_channel.external_id));
+}
 
-        ep.channel_id = new_external_id.to_string();
-        xml.write().unwrap().add_channel(ch.external_id.to_string(), ch.title);
-    }
-   
+pub(crate) async fn insert_item(tx: &mut Transaction<'_, Postgres>, ep: &Item) -> Result<(), AppError>{
     sqlx::query!(r#"
         INSERT INTO item (id, channel_id, ep_number, title, author, category, description, content_encoded,
-        enclosure_url, enclosure_type, enclosure_length, i_link, pub_date, itunes_subtitle, itunes_image, itunes_duration)
-        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
-        "#, Uuid::parse_str(&ep.id).unwrap(), Uuid::parse_str(&ep.channel_id).unwrap(), ep.ep_number, ep.title, 
+        enclosure_url, enclosure_type, enclosure_length, i_link, pub_date, itunes_subtitle, itunes_image, itunes_duration,
+        season, guid, enclosure_sha256, object_key)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19, $20)
+        "#, parse_uuid("item.id", &ep.id)?, parse_uuid("item.channel_id", &ep.channel_id)?, ep.ep_number, ep.title, 
         ep.author, ep.category, ep.description, ep.content_encoded, ep.enclosure_url, ep.enclosure_type, ep.enclosure_length, 
-        ep.i_link, ep.pub_date, ep.itunes_subtitle.clone(), ep.itunes_image.clone(), ep.itunes_duration.clone(),
-    ).execute(pg_conn_pool.get_ref())
-    .await
-    .unwrap();
+        ep.i_link, ep.pub_date, ep.itunes_subtitle, ep.itunes_image, ep.itunes_duration,
+        ep.season, ep.guid, ep.enclosure_sha256, ep.object_key,
+    ).execute(&mut *tx)
+    .await?;
+    return Ok(());
+}
+
+/// replace a channel's itunes:category rows.
+async fn store_channel_categories(
+    tx: &mut Transaction<'_, Postgres>,
+    ch_external_id: &Uuid,
+    categories: &[ChannelCategory],
+) -> Result<(), AppError>{
+    sqlx::query!(r#" DELETE FROM channel_category WHERE channel_id = $1 "#, ch_external_id)
+        .execute(&mut *tx)
+        .await?;
+    for (position, c) in categories.iter().enumerate(){
+        sqlx::query!(r#"
+            INSERT INTO channel_category (channel_id, position, category, subcategory)
+            VALUES ($1, $2, $3, $4)
+            "#, ch_external_id, position as i16, c.category, c.subcategory
+        ).execute(&mut *tx)
+        .await?;
+    }
+    return Ok(());
+}
+
+/// googleplay:category elements; Google's categories are flat, so one per top level category.
+fn googleplay_categories_xml(categories: &[ChannelCategory]) -> String{
+    let mut seen = HashSet::new();
+    return categories.iter()
+        .filter(|c| seen.insert(c.category.as_str()))
+        .map(|c| format!(r#"
+        <googleplay:category text="{}"/>"#, xml_escape(&c.category)))
+        .collect();
+}
 
-    return Ok(ch.external_id);
+/// itunes:category elements, subcategories nested under their parent.
+fn itunes_categories_xml(categories: &[ChannelCategory]) -> String{
+    let mut xml = String::new();
+    let mut i = 0;
+    while i < categories.len(){
+        let category = &categories[i].category;
+        let mut subs = String::new();
+        while i < categories.len() && &categories[i].category == category{
+            if let Some(sub) = &categories[i].subcategory{
+                subs.push_str(&format!(r#"
+            <itunes:category text="{}"/>"#, xml_escape(sub)));
+            }
+            i += 1;
+        }
+        if subs.is_empty(){
+            xml.push_str(&format!(r#"
+        <itunes:category text="{}"/>"#, xml_escape(category)));
+        } else {
+            xml.push_str(&format!(r#"
+        <itunes:category text="{}">{}
+        </itunes:category>"#, xml_escape(category), subs));
+        }
+    }
+    return xml;
 }
 
-/// refresh xml with updated db data
-async fn refresh_xml_buffer(
+/// escape text for XML attribute values and element content.
+pub fn xml_escape(text: &str) -> String{
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars(){
+        match c{
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    return escaped;
+}
+
+/// rebuild a channel's cached feed after its rows changed. A channel that is gone from
+/// the DB is dropped from the cache.
+pub(crate) async fn refresh_channel_feed(
+    ch_external_id: &str,
+    pg_conn_pool: &PgPool,
+    feed_cache: &FeedCache,
+) -> Result<(), AppError>{
+    match refresh_xml_buffer(ch_external_id, pg_conn_pool).await{
+        Ok(channel_feeds) => feed_cache.insert(channel_feeds),
+        Err(AppError::NotFound(msg)) => {
+            feed_cache.remove(&parse_uuid("external_id", ch_external_id)?);
+            return Err(AppError::NotFound(msg));
+        },
+        Err(e) => return Err(e),
+    }
+    return Ok(());
+}
+
+/// refresh xml with updated db data; Atom and JSON Feed are rendered from the same data,
+/// for the main feed and for each of the channel's variants.
+pub(crate) async fn refresh_xml_buffer(
     ch_external_id: &str,
     pg_conn_pool: &PgPool,
-) -> Result<String, &'static str>{
-    let ch_external_id = Uuid::parse_str(ch_external_id).unwrap();
+) -> Result<ChannelFeeds, AppError>{
+    let ch_external_id = parse_uuid("external_id", ch_external_id)?;
 
     let ch = match sqlx::query!(
         r#" SELECT * FROM channel WHERE external_id = $1 "#,   
         ch_external_id,
     ).fetch_optional(pg_conn_pool)
-    .await
-    .unwrap(){
+    .await?{
         Some(ch) => {
             ch
         },       
         None => {
-            return Err("couldn't find channel in DB");
+            return Err(AppError::NotFound("couldn't find channel in DB".to_string()));
         }
     };
 
+    let categories = sqlx::query_as!(ChannelCategory,
+        r#" SELECT category, subcategory FROM channel_category WHERE channel_id = $1 ORDER BY position "#,
+        ch_external_id,
+    ).fetch_all(pg_conn_pool)
+    .await?;
+
     let channel = Channel {
+        categories,
         id: ch.id,
         external_id: ch.external_id.to_string(),
         title: ch.title,
@@ -747,46 +1203,151 @@ async fn refresh_xml_buffer(
         itunes_owner_email: ch.itunes_owner_email,
         sy_update_period: ch.sy_update_period,
         sy_update_frequency: ch.sy_update_frequency,
+        itunes_type: ch.itunes_type,
+        max_items: ch.max_items,
+        slug: ch.slug,
+        custom_path: ch.custom_path,
     };
+    // pub_date is free text (RFC 2822 expected), final ordering is done below.
     let items_res: Vec<_> = sqlx::query!(
-        r#"SELECT * FROM item WHERE channel_id = $1"#,
+        r#"SELECT * FROM item WHERE channel_id = $1 
+        ORDER BY season DESC NULLS LAST, ep_number DESC"#,
         ch_external_id,
         ).fetch_all(pg_conn_pool)
-        .await.unwrap();
+        .await?;
 
     let mut items = Vec::<Item>::new();
+    let mut main_feed = HashSet::new();
     for item_res in &items_res{
-        if 1 == 1 {
-            let (itunes_subtitle, itunes_image, itunes_duration) = 
-                if item_res.itunes_duration == "NONE" || item_res.itunes_duration.len() < 2 {
-                    ("", "", "")
-                } else {
-                    (item_res.itunes_subtitle.as_str(), 
-                     item_res.itunes_image.as_str(), 
-                     item_res.itunes_duration.as_str())
-            };
-            items.push(Item{
-                id: item_res.id.to_string(),
-                channel_id: item_res.channel_id.to_string(),
-                ep_number: item_res.ep_number,
-                title: item_res.title.clone(),
-                author: item_res.author.clone(),
-                category: item_res.category.clone(),
-                description: item_res.description.clone(),
-                content_encoded: item_res.content_encoded.clone(),
-                enclosure_url: item_res.enclosure_url.clone(),
-                enclosure_type: item_res.enclosure_type.clone(),
-                enclosure_length: item_res.enclosure_length.clone(),
-                i_link: item_res.i_link.clone(),
-                pub_date: item_res.pub_date.clone(),
-                itunes_subtitle: itunes_subtitle.to_string(),
-                itunes_image: itunes_image.to_string(),
-                itunes_duration: itunes_duration.to_string(),
-            
-            });
+        if item_res.main_feed{
+            main_feed.insert(item_res.id.to_string());
         }
+        let (itunes_subtitle, itunes_duration) = 
+            if item_res.itunes_duration == "NONE" || item_res.itunes_duration.len() < 2 {
+                ("", "")
+            } else {
+                (item_res.itunes_subtitle.as_str(), 
+                 item_res.itunes_duration.as_str())
+        };
+        items.push(Item{
+            id: item_res.id.to_string(),
+            channel_id: item_res.channel_id.to_string(),
+            ep_number: item_res.ep_number,
+            title: item_res.title.clone(),
+            author: item_res.author.clone(),
+            category: item_res.category.clone(),
+            description: item_res.description.clone(),
+            content_encoded: item_res.content_encoded.clone(),
+            enclosure_url: item_res.enclosure_url.clone(),
+            enclosure_type: item_res.enclosure_type.clone(),
+            enclosure_length: item_res.enclosure_length.clone(),
+            i_link: item_res.i_link.clone(),
+            pub_date: item_res.pub_date.clone(),
+            itunes_subtitle: itunes_subtitle.to_string(),
+            itunes_image: item_res.itunes_image.clone(),
+            itunes_duration: itunes_duration.to_string(),
+            season: item_res.season,
+            guid: item_res.guid.clone(),
+            enclosure_sha256: item_res.enclosure_sha256.clone(),
+            object_key: item_res.object_key.clone(),
+        });
     }  
 
+    // newest first; stable sort keeps the season/ep_number order from the query on ties.
+    // Unparseable dates go to the bottom.
+    items.sort_by_key(|item| std::cmp::Reverse(parse_pub_date(&item.pub_date)));
+    let max_items = channel.max_items.map(|max_items| max_items.max(0) as usize).unwrap_or(usize::MAX);
+    // private media isn't public-read; subscriber_feed() swaps in each subscriber's media URLs.
+    if ch.private{
+        for item in items.iter_mut().filter(|item| item.object_key.is_some()){
+            item.enclosure_url = format!("{}/{}", SUBSCRIBER_MEDIA_BASE, item.id);
+        }
+    }
+
+    let main_items: Vec<Item> = items.iter()
+        .filter(|item| main_feed.contains(&item.id))
+        .take(max_items)
+        .cloned()
+        .collect();
+    let feeds = render_feeds(&channel, &main_items, ch.private).await?;
+
+    let mut variant_items = HashMap::<Uuid, HashMap<String, VariantEnclosure>>::new();
+    for ep in sqlx::query_as!(VariantEnclosure, r#"
+        SELECT iv.variant_id, iv.item_id, iv.enclosure_url, iv.enclosure_type, iv.enclosure_length
+        FROM item_variant iv JOIN feed_variant v ON v.id = iv.variant_id
+        WHERE v.channel_id = $1
+        "#, ch_external_id
+    ).fetch_all(pg_conn_pool)
+    .await?{
+        variant_items.entry(ep.variant_id).or_default().insert(ep.item_id.to_string(), ep);
+    }
+    let mut variants = BTreeMap::new();
+    for variant in sqlx::query!(
+        r#" SELECT id, name, title FROM feed_variant WHERE channel_id = $1 "#, ch_external_id
+    ).fetch_all(pg_conn_pool)
+    .await?{
+        let enclosures = variant_items.remove(&variant.id).unwrap_or_default();
+        let items = select_variant_items(&items, &enclosures, ch.private, max_items);
+        let mut variant_channel = channel.clone();
+        if !variant.title.is_empty(){
+            variant_channel.title = variant.title;
+        }
+        variants.insert(variant.name, render_feeds(&variant_channel, &items, ch.private).await?);
+    }
+
+    return Ok(ChannelFeeds{
+        external_id: ch_external_id,
+        slug: channel.slug.clone(),
+        custom_path: channel.custom_path.clone(),
+        private: ch.private,
+        moved: match (ch.moved_to, ch.redirect_after){
+            (Some(moved_to), Some(redirect_after)) => Some(ChannelMove{ moved_to, redirect_after }),
+            _ => None,
+        },
+        feeds,
+        variants,
+    });
+}
+
+/// an episode's audio in a feed variant; None: the episode's own.
+struct VariantEnclosure{
+    variant_id: Uuid,
+    item_id: Uuid,
+    enclosure_url: Option<String>,
+    enclosure_type: Option<String>,
+    enclosure_length: Option<String>,
+}
+
+/// the episodes in a variant, newest `max_items` of `items`, each with the variant's own audio
+/// when it has one.
+fn select_variant_items(items: &[Item], enclosures: &HashMap<String, VariantEnclosure>, private: bool, max_items: usize) -> Vec<Item>{
+    return items.iter()
+        .filter_map(|item|{
+            let enclosure = enclosures.get(&item.id)?;
+            let mut item = item.clone();
+            if let (Some(url), Some(content_type), Some(length)) =
+                (&enclosure.enclosure_url, &enclosure.enclosure_type, &enclosure.enclosure_length){
+                // private: subscriber_variant_media() picks the variant's cut.
+                item.enclosure_url = if private { format!("{}/{}", SUBSCRIBER_MEDIA_BASE, item.id) } else { url.clone() };
+                item.enclosure_type = content_type.clone();
+                item.enclosure_length = length.clone();
+            }
+            return Some(item);
+        })
+        .take(max_items)
+        .collect();
+}
+
+/// one feed's items in every format.
+async fn render_feeds(channel: &Channel, items: &[Item], private: bool) -> Result<FeedBuffers, AppError>{
+    return Ok(FeedBuffers{
+        atom: CachedFeed::build(render_atom(channel, items)).await?,
+        json: CachedFeed::build(render_json_feed(channel, items)?).await?,
+        rss: CachedFeed::build(render_rss(channel, items, private)).await?,
+    });
+}
+
+fn render_rss(channel: &Channel, items: &[Item], private: bool) -> String{
     /*TODO: 
      1. Can have multiple itunes categories, can also nest.
      2. Complete vendor setup for rawvoice tag.
@@ -828,52 +1389,210 @@ async fn refresh_xml_buffer(
         <itunes:summary>{}</itunes:summary>
         <itunes:author>{}</itunes:author>
         <itunes:explicit>{}</itunes:explicit>
-        <itunes:image>{}</itunes:image>
+        <itunes:image href="{}"/>
         <itunes:owner>
             <itunes:name>{}</itunes:name>
             <itunes:email>{}</itunes:email>
         </itunes:owner>
 
         <itunes:subtitle>{}</itunes:subtitle>
-        <itunes:category text="{}"/>
-        <googleplay:category text="{}"/>
+        <itunes:type>{}</itunes:type>{}{}{}
 
     "#, channel.title, channel.managing_editor, channel.c_link, channel.c_link, channel.description, channel.last_build_date,
     channel.language, channel.image_url, channel.image_title, channel.image_link,
     channel.image_width, channel.image_height, channel.itunes_new_feed_url, channel.description,
-    channel.itunes_owner_name, channel.itunes_explicit, channel.image_link, channel.itunes_owner_name,
-    channel.itunes_owner_email, channel.description, channel.category, channel.category,
+    channel.itunes_owner_name, channel.itunes_explicit, xml_escape(&channel.image_url), channel.itunes_owner_name,
+    channel.itunes_owner_email, channel.description, channel.itunes_type, 
+    itunes_categories_xml(&channel.category_pairs()),
+    // keep private feeds out of directories.
+    if private { "\n        <itunes:block>Yes</itunes:block>" } else { "" },
+    googleplay_categories_xml(&channel.category_pairs()),
     /* channel.sy_update_period, channel.sy_update_frequency, channel.itunes_new_feed_url, "", "", "", "", "" */);
 
-    for item in &items{
-        log::info!("TRACE -- ID -------------- {}", &item.id);
-    }
-    loop {
-        let item: Item;
-        match items.pop(){
-            Some(i) => item = i,
-            None => break,
-        }
+    for item in items{
+        let season = match item.season{
+            Some(season) => format!("<itunes:season>{}</itunes:season>", season),
+            None => String::new(),
+        };
+        // episodes without their own artwork show the channel's.
+        let image = if item.itunes_image.is_empty(){
+            &channel.image_url
+        } else {
+            &item.itunes_image
+        };
         xml_buffer.push_str(&format!(r#"
             <item>
                 <title>{}</title>
                 <author>{}</author>
                 <link>{}</link>
                 <pubDate>{}</pubDate>
-                <guid>{}</guid>
+                <guid isPermaLink="false">{}</guid>
                 <category><![CDATA[{}]]></category>
                 <description>{}</description>
                 <content:encoded>{}</content:encoded>
                 <enclosure url="{}" type="{}" length="{}"/>
                 <itunes:summary>{}</itunes:summary>
+                <itunes:episode>{}</itunes:episode>
+                <itunes:image href="{}"/>
+                {}
             </item>
-        "#, item.title, item.author, item.i_link, item.pub_date, item.id, item.category, item.description, item.content_encoded
-        , item.enclosure_url, item.enclosure_type, item.enclosure_length, item.description, /* item.itunes_duration */));
+        "#, item.title, item.author, item.i_link, item.pub_date, xml_escape(item.guid()), item.category, item.description, item.content_encoded
+        , item.enclosure_url, item.enclosure_type, item.enclosure_length, item.description, item.ep_number,
+        xml_escape(image), season
+        /* item.itunes_duration */));
     }
  
     xml_buffer.push_str(
         r#"</channel>
         </rss>"#);
+    return xml_buffer;
+}
+
+/// RFC 2822 pub_date (RFC 3339 accepted) to timestamp. None if unparseable.
+pub(crate) fn parse_pub_date(pub_date: &str) -> Option<DateTime<FixedOffset>>{
+    let pub_date = pub_date.trim();
+    return DateTime::parse_from_rfc2822(pub_date)
+        .or_else(|_| DateTime::parse_from_rfc3339(pub_date))
+        .ok();
+}
+
+#[cfg(test)]
+mod tests{
+    use {super::*, crate::variant_object_key};
+
+    fn s3(key_prefix: &str, key_template: &str) -> S3{
+        return S3{
+            client: S3Client::from_conf(Config::builder().region(Region::new("us-east-1")).build()),
+            bucket: "fake-bucket".to_string(),
+            full_link: "https://fake-bucket.example.com".to_string(),
+            public_link: "https://fake-bucket.example.com".to_string(),
+            temp_dir: std::env::temp_dir().to_string_lossy().to_string(),
+            key_prefix: key_prefix.to_string(),
+            key_template: key_template.to_string(),
+        };
+    }
+
+    fn episode(pub_date: &str, season: Option<i32>) -> Item{
+        return Item{
+            id: "8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c".to_string(),
+            channel_id: String::new(),
+            ep_number: 7,
+            title: "t".to_string(),
+            author: String::new(),
+            category: String::new(),
+            description: String::new(),
+            content_encoded: String::new(),
+            enclosure_url: String::new(),
+            enclosure_type: "audio/mpeg".to_string(),
+            enclosure_length: "0".to_string(),
+            i_link: String::new(),
+            pub_date: pub_date.to_string(),
+            itunes_subtitle: String::new(),
+            itunes_image: String::new(),
+            itunes_duration: String::new(),
+            season,
+            guid: None,
+            enclosure_sha256: None,
+            object_key: None,
+        };
+    }
+
+    #[test]
+    fn episode_key_fills_the_template(){
+        let ep = episode("Wed, 01 Mar 2023 10:00:00 +0000", Some(2));
+        assert_eq!(s3("", "{id}.{ext}").episode_key("art-show", &ep, "mp3"),
+            "8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c.mp3");
+        assert_eq!(s3("podcasts", "{channel_slug}/{year}/{month}/s{season}e{ep_number}-{id}.{ext}").episode_key("art-show", &ep, "mp3"),
+            "podcasts/art-show/2023/03/s2e7-8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c.mp3");
+    }
 
-    return Ok(xml_buffer);
+    #[test]
+    fn episode_key_drops_empty_segments(){
+        let ep = episode("Wed, 01 Mar 2023 10:00:00 +0000", None);
+        assert_eq!(s3("", "{channel_slug}/{season}/{id}.{ext}").episode_key("art-show", &ep, "mp3"),
+            "art-show/8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c.mp3");
+    }
+
+    #[test]
+    fn episode_key_dates_unparseable_episodes_now(){
+        let ep = episode("not a date", None);
+        let key = s3("", "{year}/{month}/{id}.{ext}").episode_key("art-show", &ep, "mp3");
+        assert_eq!(key, format!("{}/8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c.mp3", Utc::now().format("%Y/%m")));
+    }
+
+    fn category(category: &str, subcategory: Option<&str>) -> ChannelCategory{
+        return ChannelCategory{
+            category: category.to_string(),
+            subcategory: subcategory.map(|s| s.to_string()),
+        };
+    }
+
+    #[test]
+    fn itunes_categories_nest_subcategories_under_their_parent(){
+        let xml = itunes_categories_xml(&[
+            category("Arts", Some("Design")), category("Arts", Some("Food")),
+            category("TV & Film", None),
+        ]);
+        let xml: String = xml.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert_eq!(xml, concat!(
+            r#"<itunes:category text="Arts"> <itunes:category text="Design"/> <itunes:category text="Food"/> </itunes:category> "#,
+            r#"<itunes:category text="TV &amp; Film"/>"#));
+    }
+
+    #[test]
+    fn googleplay_gets_each_top_level_category_once(){
+        let xml = googleplay_categories_xml(&[
+            category("Arts", Some("Design")), category("Arts", Some("Food")), category("Technology", None),
+        ]);
+        let xml: Vec<&str> = xml.split_whitespace().collect();
+        assert_eq!(xml.join(" "),
+            r#"<googleplay:category text="Arts"/> <googleplay:category text="Technology"/>"#);
+    }
+
+    fn variant_enclosure(item_id: &str, url: Option<&str>) -> (String, VariantEnclosure){
+        return (item_id.to_string(), VariantEnclosure{
+            variant_id: Uuid::new_v4(),
+            item_id: Uuid::parse_str(item_id).unwrap(),
+            enclosure_url: url.map(str::to_string),
+            enclosure_type: url.map(|_| "audio/mp4".to_string()),
+            enclosure_length: url.map(|_| "42".to_string()),
+        });
+    }
+
+    #[test]
+    fn variants_take_their_episodes_and_their_own_audio(){
+        let ids = [Uuid::new_v4().to_string(), Uuid::new_v4().to_string(), Uuid::new_v4().to_string()];
+        let items: Vec<Item> = ids.iter().map(|id|{
+            let mut ep = episode("Wed, 01 Mar 2023 10:00:00 +0000", None);
+            ep.id = id.clone();
+            ep.enclosure_url = format!("https://fake-bucket.example.com/{}.mp3", id);
+            return ep;
+        }).collect();
+        // the first episode has its own cut, the last uses the episode's audio, the middle one isn't in the variant.
+        let enclosures: HashMap<_, _> = [
+            variant_enclosure(&ids[0], Some("https://fake-bucket.example.com/cut.m4a")),
+            variant_enclosure(&ids[2], None),
+        ].into_iter().collect();
+
+        let selected = select_variant_items(&items, &enclosures, false, usize::MAX);
+        assert_eq!(selected.iter().map(|ep| ep.id.as_str()).collect::<Vec<_>>(), [ids[0].as_str(), ids[2].as_str()]);
+        assert_eq!(selected[0].enclosure_url, "https://fake-bucket.example.com/cut.m4a");
+        assert_eq!(selected[0].enclosure_type, "audio/mp4");
+        assert_eq!(selected[0].enclosure_length, "42");
+        assert_eq!(selected[1].enclosure_url, items[2].enclosure_url);
+        assert_eq!(selected[1].enclosure_type, "audio/mpeg");
+
+        let private = select_variant_items(&items, &enclosures, true, 1);
+        assert_eq!(private.len(), 1);
+        assert_eq!(private[0].enclosure_url, format!("{}/{}", SUBSCRIBER_MEDIA_BASE, ids[0]));
+    }
+
+    #[test]
+    fn variant_audio_keys_follow_the_episode_key(){
+        let s3 = s3("podcasts", "{id}.{ext}");
+        let id = Uuid::parse_str("8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c").unwrap();
+        assert_eq!(variant_object_key(Some("podcasts/show/ep.mp3"), &id, "ad-free", &s3), "podcasts/show/ep.ad-free.mp3");
+        assert_eq!(variant_object_key(Some("podcasts/show.d/ep"), &id, "ad-free", &s3), "podcasts/show.d/ep.ad-free");
+        assert_eq!(variant_object_key(None, &id, "bonus", &s3), "podcasts/8d6c6f0e-5d4b-4a39-9d0b-0c5e6a1f2b3c.bonus.mp3");
+    }
 }
@@ -0,0 +1,194 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpResponse,
+        ActiveTokens, is_valid_token,
+        AppError, parse_uuid,
+        S3, S3DeleteQueue, media_acl,
+        PresigningConfig,
+        FeedCache, PodcastData, EpisodeFiles, Item,
+        prepare_episode, publish_episode, episode_object_key,
+    },
+    validator::Validate,
+    serde::{
+        Serialize, Deserialize,
+    },
+    chrono::{
+        DateTime, Utc,
+    },
+    sqlx::{
+        PgPool, types::Uuid,
+    },
+    std::{
+        collections::BTreeMap,
+        time::Duration,
+    },
+};
+
+/// how long a presigned PUT URL works.
+pub const PRESIGNED_UPLOAD_TTL_SECS: u64 = 60 * 60;
+/// S3 single PUT limit.
+pub const PRESIGNED_UPLOAD_MAX_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
+pub struct PresignUploadForm{
+    /// the episode, as `upload` takes it; session_token inside is checked.
+    pub podcast_data: PodcastData,
+    /// exact size of the audio the client will PUT.
+    #[validate(range(min = 1, max = "PRESIGNED_UPLOAD_MAX_SIZE"))]
+    pub content_length: i64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PresignedUpload{
+    pub episode_id: String,
+    pub method: String,
+    pub url: String,
+    /// must be sent with the PUT exactly as given, they are part of the signature.
+    pub headers: BTreeMap<String, String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FinalizedUpload{
+    pub episode_id: String,
+    pub item: Item,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FinalizeUploadForm{
+    pub session_token: String,
+    pub episode_id: String,
+}
+
+/// POST reserve an episode id and get a presigned PUT for its audio. podcast_data is
+/// validated now and kept until finalize_upload.
+pub async fn presign_upload(
+    form: web::Json<PresignUploadForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.podcast_data.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    form.validate()?;
+    let episode_id = Uuid::new_v4();
+    let object_key = episode_object_key(&form.podcast_data, &episode_id, &s3, &pg_conn_pool).await?;
+    prepare_episode(&mut form.podcast_data.clone(), &episode_id, form.content_length as u64, &object_key, &s3)?;
+    let acl = media_acl(&form.podcast_data.channel.external_id, &pg_conn_pool).await?;
+
+    let presigning_config = PresigningConfig::expires_in(Duration::from_secs(PRESIGNED_UPLOAD_TTL_SECS))
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let presigned = s3.client.put_object()
+        .bucket(&s3.bucket)
+        .key(&object_key)
+        .acl(acl)
+        .content_type("audio/mpeg")
+        .content_length(form.content_length)
+        .presigned(presigning_config)
+        .await
+        .map_err(|e| AppError::Storage(format!("could not presign upload: {}", e)))?;
+    let expires_at = Utc::now() + chrono::Duration::seconds(PRESIGNED_UPLOAD_TTL_SECS as i64);
+
+    let podcast_data = serde_json::to_string(&form.podcast_data)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    sqlx::query!(r#"
+        INSERT INTO presigned_upload (episode_id, object_key, content_length, podcast_data, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#, episode_id, object_key, form.content_length, podcast_data, expires_at
+    ).execute(pg_conn_pool.get_ref())
+    .await?;
+
+    return Ok(HttpResponse::Ok().json(PresignedUpload{
+        episode_id: episode_id.to_string(),
+        method: presigned.method().to_string(),
+        url: presigned.uri().to_string(),
+        headers: presigned.headers().iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect(),
+        expires_at,
+    }));
+}
+
+/// the episode finalize_upload() published, if any.
+async fn published_item(episode_id: &Uuid, pg_conn_pool: &PgPool) -> Result<Option<Item>, AppError>{
+    let item = sqlx::query_as!(Item, r#"
+        SELECT id::TEXT AS "id!", channel_id::TEXT AS "channel_id!", ep_number, title, author, category,
+        description, content_encoded, enclosure_url, enclosure_type, enclosure_length, i_link, pub_date,
+        itunes_subtitle, itunes_image, itunes_duration, season, guid, enclosure_sha256, object_key
+        FROM item WHERE id = $1
+        "#, episode_id
+    ).fetch_optional(pg_conn_pool)
+    .await?;
+    return Ok(item);
+}
+
+/// POST publish an episode whose audio was PUT to a presigned URL. The object has to be
+/// there with the announced size. Fails leave the object and reservation in place, so
+/// finalize can be retried; retrying an upload that was already published returns it again.
+pub async fn finalize_upload(
+    form: web::Json<FinalizeUploadForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+    delete_queue: web::Data<S3DeleteQueue>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let episode_id = parse_uuid("episode_id", &form.episode_id)?;
+    let pending = sqlx::query!(
+        r#" SELECT object_key, content_length, podcast_data FROM presigned_upload WHERE episode_id = $1 "#,
+        episode_id
+    ).fetch_optional(pg_conn_pool.get_ref())
+    .await?;
+    // a retry after a lost response or a failed reservation cleanup: the reservation is
+    // consumed (or about to be) by the episode it reserved.
+    if let Some(item) = published_item(&episode_id, &pg_conn_pool).await?{
+        if let Some(pending) = &pending{
+            if item.object_key.as_deref() != Some(pending.object_key.as_str()){
+                return Err(AppError::Validation(format!("episode {} exists with other audio", episode_id)));
+            }
+            sqlx::query!(r#" DELETE FROM presigned_upload WHERE episode_id = $1 "#, episode_id)
+                .execute(pg_conn_pool.get_ref())
+                .await?;
+        }
+        return Ok(HttpResponse::Ok().json(FinalizedUpload{ episode_id: episode_id.to_string(), item }));
+    }
+    let pending = match pending{
+        Some(pending) => pending,
+        None => return Err(AppError::NotFound(format!("no pending upload {}", episode_id))),
+    };
+
+    let object = s3.client.head_object()
+        .bucket(&s3.bucket)
+        .key(&pending.object_key)
+        .send()
+        .await
+        .map_err(|_| AppError::Validation(format!("{} has not been uploaded", pending.object_key)))?;
+    if object.content_length() != pending.content_length{
+        return Err(AppError::Validation(format!("uploaded {} bytes, expected {}",
+            object.content_length(), pending.content_length)));
+    }
+
+    let podcast_data: PodcastData = serde_json::from_str(&pending.podcast_data)
+        .map_err(|e| AppError::Internal(format!("pending upload {} is corrupt: {}", episode_id, e)))?;
+    let files = EpisodeFiles{
+        id: episode_id,
+        audio: None,
+        audio_size: pending.content_length as u64,
+        artwork: None,
+        object_key: Some(&pending.object_key),
+    };
+    publish_episode(podcast_data, files, &pg_conn_pool, &feed_cache, &s3, &delete_queue).await?;
+
+    sqlx::query!(r#" DELETE FROM presigned_upload WHERE episode_id = $1 "#, episode_id)
+        .execute(pg_conn_pool.get_ref())
+        .await?;
+
+    let item = published_item(&episode_id, &pg_conn_pool).await?
+        .ok_or_else(|| AppError::Internal(format!("episode {} missing after publishing", episode_id)))?;
+    return Ok(HttpResponse::Ok().json(FinalizedUpload{ episode_id: episode_id.to_string(), item }));
+}
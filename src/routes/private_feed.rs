@@ -0,0 +1,481 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpRequest, HttpResponse,
+        ActiveTokens, is_valid_token,
+        AppError, parse_uuid,
+        S3, ObjectCannedAcl, PresigningConfig,
+        FeedCache, FeedFormat, FeedLookup, lookup_feed,
+        legacy_slug,
+    },
+    validator::Validate,
+    serde::{
+        Serialize, Deserialize,
+    },
+    chrono::{
+        DateTime, Utc,
+    },
+    sha2::{
+        Digest, Sha256,
+    },
+    sqlx::{
+        PgPool, types::Uuid,
+    },
+    std::time::Duration,
+};
+
+/// enclosure URL prefix in a private channel's cached feeds, replaced per subscriber with
+/// `/podcast/{slug}/{token}/media`, or `/podcast/{slug}/{token}/variant/{name}/media` in a
+/// variant. Never served as is.
+pub const SUBSCRIBER_MEDIA_BASE: &str = "https://subscriber-media.invalid";
+/// how long the presigned URL a subscriber's media link redirects to works.
+pub const SUBSCRIBER_MEDIA_URL_TTL_SECS: u64 = 6 * 60 * 60;
+/// distinct IPs in SUBSCRIBER_ACTIVITY_DAYS from which a token looks passed around.
+pub const SHARED_TOKEN_IP_THRESHOLD: i64 = 10;
+pub const SUBSCRIBER_ACTIVITY_DAYS: i32 = 7;
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
+pub struct FeedSubscriberForm{
+    pub session_token: String,
+    pub channel_id: String,
+    /// who the token is for, e.g. an email address or order number.
+    #[validate(length(min = 1, max = 255))]
+    pub label: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RevokeFeedSubscriberForm{
+    pub session_token: String,
+    pub subscriber_id: String,
+}
+
+/// a new subscriber's token; it is only ever shown here.
+#[derive(Serialize, Clone, Debug)]
+pub struct FeedSubscriberToken{
+    pub subscriber_id: String,
+    pub token: String,
+    /// feed path, relative to the server.
+    pub feed_path: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct SubscriberSummary{
+    pub id: Uuid,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub last_access: Option<DateTime<Utc>>,
+    /// requests and distinct IPs over the last SUBSCRIBER_ACTIVITY_DAYS.
+    pub recent_requests: i64,
+    pub recent_ips: i64,
+}
+
+impl SubscriberSummary{
+    pub fn likely_shared(&self) -> bool{
+        return self.revoked_at.is_none() && self.recent_ips >= SHARED_TOKEN_IP_THRESHOLD;
+    }
+}
+
+/// a request path with a subscriber token in `/podcast/{slug}/{token}/...` replaced by
+/// "[token]", for the access log.
+pub fn redact_feed_token(path: &str) -> String{
+    let mut segments: Vec<&str> = path.split('/').collect();
+    // ["", "podcast", slug, token or "variant", ...]
+    if segments.len() > 3 && segments[1] == "podcast" && segments[3] != "variant"{
+        segments[3] = "[token]";
+    }
+    return segments.join("/");
+}
+
+/// tokens are stored as their sha256 only.
+pub fn hash_feed_token(token: &str) -> String{
+    return format!("{:x}", Sha256::digest(token.as_bytes()));
+}
+
+pub fn generate_feed_token() -> String{
+    return format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+}
+
+/// ACL for audio stored in a channel: private channels' media is only reachable through
+/// subscriber_media(). Unknown (not yet created) channels are public.
+pub async fn media_acl(ch_external_id: &str, pg_conn_pool: &PgPool) -> Result<ObjectCannedAcl, AppError>{
+    let ch_external_id = match Uuid::parse_str(ch_external_id){
+        Ok(ch_external_id) => ch_external_id,
+        Err(_) => return Ok(ObjectCannedAcl::PublicRead),
+    };
+    let private = sqlx::query!(
+        r#" SELECT private FROM channel WHERE external_id = $1 "#, ch_external_id
+    ).fetch_optional(pg_conn_pool)
+    .await?
+    .map(|ch| ch.private)
+    .unwrap_or(false);
+    return Ok(if private { ObjectCannedAcl::Private } else { ObjectCannedAcl::PublicRead });
+}
+
+/// issue a feed token for a private channel.
+pub async fn create_subscriber(ch_external_id: &Uuid, label: &str, pg_conn_pool: &PgPool) -> Result<FeedSubscriberToken, AppError>{
+    let ch = match sqlx::query!(
+        r#" SELECT slug, private FROM channel WHERE external_id = $1 "#, ch_external_id
+    ).fetch_optional(pg_conn_pool)
+    .await?{
+        Some(ch) => ch,
+        None => return Err(AppError::NotFound("channel does not exist".to_string())),
+    };
+    if !ch.private{
+        return Err(AppError::Validation("channel is not private".to_string()));
+    }
+
+    let subscriber_id = Uuid::new_v4();
+    let token = generate_feed_token();
+    sqlx::query!(r#"
+        INSERT INTO feed_subscriber (id, channel_id, label, token_sha256) VALUES ($1, $2, $3, $4)
+        "#, subscriber_id, ch_external_id, label, hash_feed_token(&token)
+    ).execute(pg_conn_pool)
+    .await?;
+    return Ok(FeedSubscriberToken{
+        subscriber_id: subscriber_id.to_string(),
+        feed_path: format!("/podcast/{}/{}", ch.slug, token),
+        token,
+    });
+}
+
+/// the token stops working at once; its access log is kept.
+pub async fn revoke_subscriber(subscriber_id: &Uuid, pg_conn_pool: &PgPool) -> Result<(), AppError>{
+    let revoked = sqlx::query!(
+        r#" UPDATE feed_subscriber SET revoked_at = NOW() WHERE id = $1 AND revoked_at IS NULL "#, subscriber_id
+    ).execute(pg_conn_pool)
+    .await?;
+    if revoked.rows_affected() == 0{
+        return Err(AppError::NotFound(format!("no active subscriber {}", subscriber_id)));
+    }
+    return Ok(());
+}
+
+pub async fn list_subscribers(ch_external_id: &Uuid, pg_conn_pool: &PgPool) -> Result<Vec<SubscriberSummary>, AppError>{
+    let subscribers = sqlx::query_as!(SubscriberSummary, r#"
+        SELECT s.id, s.label, s.created_at, s.revoked_at,
+        MAX(l.accessed_at) AS last_access,
+        COUNT(l.id) FILTER (WHERE l.accessed_at > NOW() - make_interval(days => $2)) AS "recent_requests!",
+        COUNT(DISTINCT l.ip) FILTER (WHERE l.accessed_at > NOW() - make_interval(days => $2)) AS "recent_ips!"
+        FROM feed_subscriber s LEFT JOIN feed_access_log l ON l.subscriber_id = s.id
+        WHERE s.channel_id = $1
+        GROUP BY s.id ORDER BY s.created_at
+        "#, ch_external_id, SUBSCRIBER_ACTIVITY_DAYS
+    ).fetch_all(pg_conn_pool)
+    .await?;
+    return Ok(subscribers);
+}
+
+/// make a channel private or public again, and set the ACL of its audio to match. Returns
+/// how many objects were changed. The caller rebuilds the feed.
+pub async fn set_channel_private(ch_external_id: &Uuid, private: bool, s3: &S3, pg_conn_pool: &PgPool) -> Result<usize, AppError>{
+    let updated = sqlx::query!(
+        r#" UPDATE channel SET private = $1 WHERE external_id = $2 "#, private, ch_external_id
+    ).execute(pg_conn_pool)
+    .await?;
+    if updated.rows_affected() == 0{
+        return Err(AppError::NotFound("channel does not exist".to_string()));
+    }
+    return apply_media_acl(ch_external_id, s3, pg_conn_pool).await;
+}
+
+/// put the ACL media_acl() gives on every audio object of a channel, variants' included.
+/// Safe to repeat.
+pub async fn apply_media_acl(ch_external_id: &Uuid, s3: &S3, pg_conn_pool: &PgPool) -> Result<usize, AppError>{
+    let acl = media_acl(&ch_external_id.to_string(), pg_conn_pool).await?;
+    let keys = sqlx::query!(
+        r#"
+        SELECT object_key AS "object_key!" FROM item WHERE channel_id = $1 AND object_key IS NOT NULL
+        UNION ALL
+        SELECT iv.object_key FROM item_variant iv JOIN feed_variant v ON v.id = iv.variant_id
+        WHERE v.channel_id = $1 AND iv.object_key IS NOT NULL
+        "#, ch_external_id
+    ).fetch_all(pg_conn_pool)
+    .await?;
+    for ep in &keys{
+        s3.client.put_object_acl()
+            .bucket(&s3.bucket)
+            .key(&ep.object_key)
+            .acl(acl.clone())
+            .send()
+            .await
+            .map_err(|e| AppError::Storage(format!("could not set ACL of {}: {}", ep.object_key, e)))?;
+    }
+    return Ok(keys.len());
+}
+
+/// subscriber for an active token of a channel.
+async fn find_subscriber(token: &str, ch_external_id: &Uuid, pg_conn_pool: &PgPool) -> Result<Uuid, AppError>{
+    let subscriber = sqlx::query!(r#"
+        SELECT id FROM feed_subscriber
+        WHERE token_sha256 = $1 AND channel_id = $2 AND revoked_at IS NULL
+        "#, hash_feed_token(token), ch_external_id
+    ).fetch_optional(pg_conn_pool)
+    .await?;
+    return match subscriber{
+        Some(subscriber) => Ok(subscriber.id),
+        None => Err(AppError::NotFound("no such feed".to_string())),
+    };
+}
+
+/// failing to log must not lock a paying subscriber out.
+async fn log_feed_access(subscriber_id: &Uuid, item_id: Option<Uuid>, req: &HttpRequest, pg_conn_pool: &PgPool){
+    let ip = req.connection_info().realip_remote_addr().map(|ip| ip.to_string());
+    let user_agent = req.headers().get(actix_web::http::header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .map(|ua| ua.chars().take(512).collect::<String>());
+    if let Err(e) = sqlx::query!(r#"
+        INSERT INTO feed_access_log (subscriber_id, item_id, ip, user_agent) VALUES ($1, $2, $3, $4)
+        "#, subscriber_id, item_id, ip, user_agent
+    ).execute(pg_conn_pool)
+    .await{
+        log::error!("log_feed_access: could not log access of {}. Err: {}", subscriber_id, e);
+    }
+}
+
+/// GET a private channel's feed for the subscriber holding `token`, with enclosures pointing
+/// at their own media URLs. `.atom`/`.json` suffix or Accept select the format, as podcast().
+pub async fn subscriber_feed(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    let (ch_slug, token_segment) = path.into_inner();
+    let (token, format) = FeedFormat::from_request(&token_segment, &req);
+    let feed_path = SubscriberFeedPath{
+        ch_slug: &ch_slug,
+        token,
+        variant: None,
+        format,
+        suffix: &token_segment[token.len()..],
+    };
+    return respond_subscriber_feed(&req, feed_path, &pg_conn_pool, &feed_cache).await;
+}
+
+/// GET a feed variant of a private channel for the subscriber holding `token`; its
+/// enclosures point at /podcast/{slug}/{token}/variant/{name}/media.
+pub async fn subscriber_variant_feed(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    let (ch_slug, token, variant_segment) = path.into_inner();
+    let (name, format) = FeedFormat::from_request(&variant_segment, &req);
+    let feed_path = SubscriberFeedPath{
+        ch_slug: &ch_slug,
+        token: &token,
+        variant: Some(name),
+        format,
+        suffix: &variant_segment[name.len()..],
+    };
+    return respond_subscriber_feed(&req, feed_path, &pg_conn_pool, &feed_cache).await;
+}
+
+/// `/podcast/{ch_slug}/{token}[/variant/{variant}]{suffix}`, split up.
+struct SubscriberFeedPath<'a>{
+    ch_slug: &'a str,
+    token: &'a str,
+    variant: Option<&'a str>,
+    format: FeedFormat,
+    suffix: &'a str,
+}
+
+async fn respond_subscriber_feed(
+    req: &HttpRequest,
+    feed_path: SubscriberFeedPath<'_>,
+    pg_conn_pool: &PgPool,
+    feed_cache: &FeedCache,
+) -> Result<HttpResponse, AppError>{
+    let SubscriberFeedPath{ ch_slug, token, variant, format, suffix } = feed_path;
+    let variant_path = variant.map(|name| format!("/variant/{}", name)).unwrap_or_default();
+    let slug = legacy_slug(ch_slug);
+    let channel_feeds = match lookup_feed(&slug, pg_conn_pool, feed_cache).await?{
+        FeedLookup::Found(channel_feeds) => channel_feeds,
+        FeedLookup::Renamed(current) => return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, format!("/podcast/{}/{}{}{}", current, token, variant_path, suffix)))
+            .finish()),
+        FeedLookup::Missing => return Err(AppError::NotFound(format!("no feed for '{}'", slug))),
+    };
+    if !channel_feeds.private{
+        return Err(AppError::NotFound(format!("no feed for '{}'", slug)));
+    }
+    let subscriber_id = find_subscriber(token, &channel_feeds.external_id, pg_conn_pool).await?;
+    log_feed_access(&subscriber_id, None, req, pg_conn_pool).await;
+
+    if let Some(moved_to) = channel_feeds.redirect(){
+        return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, moved_to))
+            .finish());
+    }
+    let feed = match variant{
+        Some(name) => channel_feeds.get_variant(name, format)
+            .ok_or_else(|| AppError::NotFound(format!("no variant '{}' of '{}'", name, slug)))?,
+        None => channel_feeds.get(format),
+    };
+
+    let connection_info = req.connection_info().clone();
+    // the host ends up in the feed body unescaped; variant names are slugs.
+    let host = connection_info.host();
+    if !host.chars().all(|c| c.is_ascii_alphanumeric() || ".-:[]".contains(c)){
+        return Err(AppError::Validation("invalid Host header".to_string()));
+    }
+    let media_base = format!("{}://{}/podcast/{}/{}{}/media", connection_info.scheme(), host, channel_feeds.slug, token, variant_path);
+    let feed = feed.personalize(SUBSCRIBER_MEDIA_BASE, &media_base)?;
+    return Ok(feed.respond(req, format.content_type()));
+}
+
+/// GET an episode's audio for a subscriber: a redirect to a short-lived presigned URL. The
+/// slug must be the token's channel's, current or retired, as in feeds fetched before a rename.
+pub async fn subscriber_media(
+    req: HttpRequest,
+    path: web::Path<(String, String, String)>,
+    pg_conn_pool: web::Data<PgPool>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    let (ch_slug, token, item_id) = path.into_inner();
+    let item_id = parse_uuid("item_id", &item_id)?;
+    let ep = match sqlx::query!(r#"
+        SELECT s.id AS subscriber_id, item.object_key AS "object_key!"
+        FROM feed_subscriber s
+        JOIN channel c ON c.external_id = s.channel_id
+        JOIN item ON item.channel_id = s.channel_id
+        WHERE s.token_sha256 = $1 AND s.revoked_at IS NULL
+        AND item.id = $2 AND item.object_key IS NOT NULL
+        AND (c.slug = $3 OR EXISTS (
+            SELECT 1 FROM channel_slug_history h WHERE h.channel_id = c.external_id AND h.slug = $3))
+        "#, hash_feed_token(&token), item_id, legacy_slug(&ch_slug)
+    ).fetch_optional(pg_conn_pool.get_ref())
+    .await?{
+        Some(ep) => ep,
+        None => return Err(AppError::NotFound("no such episode".to_string())),
+    };
+    log_feed_access(&ep.subscriber_id, Some(item_id), &req, &pg_conn_pool).await;
+    return redirect_to_object(&ep.object_key, &s3).await;
+}
+
+/// GET an episode's audio in a feed variant for a subscriber: the variant's own cut if it
+/// has one, else the episode's.
+pub async fn subscriber_variant_media(
+    req: HttpRequest,
+    path: web::Path<(String, String, String, String)>,
+    pg_conn_pool: web::Data<PgPool>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    let (ch_slug, token, name, item_id) = path.into_inner();
+    let item_id = parse_uuid("item_id", &item_id)?;
+    let ep = match sqlx::query!(r#"
+        SELECT s.id AS subscriber_id, COALESCE(iv.object_key, item.object_key) AS object_key
+        FROM feed_subscriber s
+        JOIN channel c ON c.external_id = s.channel_id
+        JOIN feed_variant v ON v.channel_id = s.channel_id
+        JOIN item_variant iv ON iv.variant_id = v.id
+        JOIN item ON item.id = iv.item_id
+        WHERE s.token_sha256 = $1 AND s.revoked_at IS NULL
+        AND v.name = $2 AND item.id = $3
+        AND (c.slug = $4 OR EXISTS (
+            SELECT 1 FROM channel_slug_history h WHERE h.channel_id = c.external_id AND h.slug = $4))
+        "#, hash_feed_token(&token), name, item_id, legacy_slug(&ch_slug)
+    ).fetch_optional(pg_conn_pool.get_ref())
+    .await?{
+        Some(ep) => ep,
+        None => return Err(AppError::NotFound("no such episode".to_string())),
+    };
+    let object_key = match ep.object_key{
+        Some(object_key) => object_key,
+        None => return Err(AppError::NotFound("no such episode".to_string())),
+    };
+    log_feed_access(&ep.subscriber_id, Some(item_id), &req, &pg_conn_pool).await;
+    return redirect_to_object(&object_key, &s3).await;
+}
+
+/// a redirect to a short-lived presigned GET of a private object.
+async fn redirect_to_object(object_key: &str, s3: &S3) -> Result<HttpResponse, AppError>{
+    let presigning_config = PresigningConfig::expires_in(Duration::from_secs(SUBSCRIBER_MEDIA_URL_TTL_SECS))
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let presigned = s3.client.get_object()
+        .bucket(&s3.bucket)
+        .key(object_key)
+        .presigned(presigning_config)
+        .await
+        .map_err(|e| AppError::Storage(format!("could not presign {}: {}", object_key, e)))?;
+    return Ok(HttpResponse::Found()
+        .insert_header((actix_web::http::header::LOCATION, presigned.uri().to_string()))
+        .insert_header((actix_web::http::header::CACHE_CONTROL, "private, no-store"))
+        .finish());
+}
+
+/// POST issue a feed token for a private channel. The token is only returned here.
+pub async fn add_feed_subscriber(
+    form: web::Json<FeedSubscriberForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    form.validate()?;
+    let ch_external_id = parse_uuid("channel_id", &form.channel_id)?;
+    let subscriber = create_subscriber(&ch_external_id, &form.label, &pg_conn_pool).await?;
+    return Ok(HttpResponse::Ok().json(subscriber));
+}
+
+/// POST revoke one subscriber's token.
+pub async fn revoke_feed_subscriber(
+    form: web::Json<RevokeFeedSubscriberForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    let subscriber_id = parse_uuid("subscriber_id", &form.subscriber_id)?;
+    revoke_subscriber(&subscriber_id, &pg_conn_pool).await?;
+    return Ok(HttpResponse::NoContent().finish());
+}
+
+#[cfg(test)]
+mod tests{
+    use {super::*, crate::CachedFeed};
+
+    #[test]
+    fn feed_tokens_are_hashed_and_unique(){
+        let token = generate_feed_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_feed_token());
+        assert_eq!(hash_feed_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_ne!(hash_feed_token(&token), token);
+    }
+
+    #[test]
+    fn tokens_are_redacted_from_logged_paths(){
+        let token = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+        for (path, redacted) in [
+            (format!("/podcast/show/{}", token), "/podcast/show/[token]"),
+            (format!("/podcast/show/{}.atom", token), "/podcast/show/[token]"),
+            (format!("/podcast/show/{}/media/1", token), "/podcast/show/[token]/media/1"),
+            (format!("/podcast/show/{}/variant/ad-free/media/1", token), "/podcast/show/[token]/variant/ad-free/media/1"),
+            ("/podcast/show/variant/ad-free".to_string(), "/podcast/show/variant/ad-free"),
+            ("/podcast/show".to_string(), "/podcast/show"),
+            ("/channels".to_string(), "/channels"),
+        ]{
+            assert_eq!(redact_feed_token(&path), redacted);
+        }
+    }
+
+    #[actix_web::test]
+    async fn personalize_swaps_the_media_base_and_rehashes(){
+        let body = format!(r#"<enclosure url="{0}/1"/><enclosure url="{0}/2"/>"#, SUBSCRIBER_MEDIA_BASE);
+        let cached = CachedFeed::build(body).await.unwrap();
+        let feed = cached.personalize(SUBSCRIBER_MEDIA_BASE, "https://example.com/podcast/show/t/media").unwrap();
+        assert_eq!(&feed.body[..], br#"<enclosure url="https://example.com/podcast/show/t/media/1"/><enclosure url="https://example.com/podcast/show/t/media/2"/>"#);
+        assert_ne!(feed.etag, cached.etag);
+        assert_eq!(feed.last_modified, cached.last_modified);
+        // brotli is skipped per request; gzip must decode to the new body.
+        assert!(feed.brotli.is_empty());
+        let mut gunzipped = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&feed.gzip[..]), &mut gunzipped).unwrap();
+        assert_eq!(gunzipped.as_bytes(), &feed.body[..]);
+    }
+}
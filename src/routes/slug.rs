@@ -0,0 +1,165 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpRequest, HttpResponse,
+        ActiveTokens, is_valid_token,
+        AppError, parse_uuid,
+        FeedCache, FeedFormat, refresh_channel_feed,
+        validate_slug, validate_custom_path,
+    },
+    validator::Validate,
+    serde::{
+        Serialize, Deserialize,
+    },
+    sqlx::PgPool,
+};
+
+#[derive(Serialize, Deserialize, Validate, Clone, Debug)]
+pub struct ChannelSlugForm{
+    pub session_token: String,
+    pub channel_id: String,
+    #[validate(length(min = 1), custom(function = "validate_slug"))]
+    pub slug: String,
+    /// None removes the channel's custom path.
+    #[serde(default)]
+    #[validate(custom(function = "validate_custom_path"))]
+    pub custom_path: Option<String>,
+}
+
+/// slug for a new channel: lowercase ASCII letters and digits, everything else one dash.
+/// "" if nothing is left.
+pub fn slugify(title: &str) -> String{
+    let mut slug = String::new();
+    for c in title.chars(){
+        if c.is_ascii_alphanumeric(){
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-'){
+            slug.push('-');
+        }
+    }
+    return slug.trim_end_matches('-').to_string();
+}
+
+/// how feed URLs were written before slugs: lowercased title with spaces as dashes.
+/// Current slugs pass through unchanged.
+pub fn legacy_slug(path_segment: &str) -> String{
+    return path_segment.trim().to_lowercase().replace(' ', "-");
+}
+
+/// current slug of the channel that used to be at `slug`.
+pub async fn slug_redirect(slug: &str, pg_conn_pool: &PgPool) -> Result<Option<String>, AppError>{
+    let current = sqlx::query!(r#"
+        SELECT channel.slug FROM channel_slug_history
+        JOIN channel ON channel.external_id = channel_slug_history.channel_id
+        WHERE channel_slug_history.slug = $1
+        "#, slug
+    ).fetch_optional(pg_conn_pool)
+    .await?;
+    return Ok(current.map(|ch| ch.slug));
+}
+
+/// POST change a channel's slug and custom path. The old slug keeps redirecting.
+pub async fn update_channel_slug(
+    form: web::Json<ChannelSlugForm>,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    if !is_valid_token(&form.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    form.validate()?;
+    let ch_external_id = parse_uuid("channel_id", &form.channel_id)?;
+
+    let mut tx = pg_conn_pool.begin().await?;
+    let current = match sqlx::query!(
+        r#" SELECT slug FROM channel WHERE external_id = $1 FOR UPDATE "#, ch_external_id
+    ).fetch_optional(&mut tx)
+    .await?{
+        Some(ch) => ch.slug,
+        None => return Err(AppError::NotFound("channel does not exist".to_string())),
+    };
+
+    if current != form.slug{
+        // taking a retired slug, ours or another channel's, ends its redirect.
+        sqlx::query!(r#" DELETE FROM channel_slug_history WHERE slug = $1 "#, form.slug)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(r#" UPDATE channel SET slug = $1 WHERE external_id = $2 "#, form.slug, ch_external_id)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!(r#"
+            INSERT INTO channel_slug_history (slug, channel_id) VALUES ($1, $2)
+            ON CONFLICT (slug) DO UPDATE SET channel_id = EXCLUDED.channel_id, retired_at = NOW()
+            "#, current, ch_external_id
+        ).execute(&mut tx)
+        .await?;
+    }
+    sqlx::query!(r#" UPDATE channel SET custom_path = $1 WHERE external_id = $2 "#,
+        form.custom_path, ch_external_id
+    ).execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+
+    refresh_channel_feed(&ch_external_id.to_string(), &pg_conn_pool, &feed_cache).await?;
+
+    return Ok(HttpResponse::Ok().finish());
+}
+
+/// GET feed at a channel's custom path. Registered as the default service, so API routes
+/// always win; the format comes from the Accept header.
+pub async fn custom_feed(
+    req: HttpRequest,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+) -> Result<HttpResponse, AppError>{
+    let path = req.path();
+    if req.method() != actix_web::http::Method::GET && req.method() != actix_web::http::Method::HEAD{
+        return Err(AppError::NotFound(format!("no route for {} {}", req.method(), path)));
+    }
+    let channel_feeds = match feed_cache.get_by_custom_path(path){
+        Some(channel_feeds) => channel_feeds,
+        None => match feed_cache.load_by_custom_path(path, &pg_conn_pool).await?{
+            Some(channel_feeds) => channel_feeds,
+            None => return Err(AppError::NotFound(format!("no feed at '{}'", path))),
+        },
+    };
+    if channel_feeds.private{
+        return Err(AppError::NotFound(format!("no feed at '{}'", path)));
+    }
+    if let Some(moved_to) = channel_feeds.redirect(){
+        return Ok(HttpResponse::MovedPermanently()
+            .insert_header((actix_web::http::header::LOCATION, moved_to))
+            .finish());
+    }
+    let format = FeedFormat::from_accept(&req);
+
+    return Ok(channel_feeds.get(format).respond(&req, format.content_type()));
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn slugify_collapses_everything_else_to_single_dashes(){
+        assert_eq!(slugify("Art Show"), "art-show");
+        assert_eq!(slugify("  The -- Art   Show! 2 "), "the-art-show-2");
+        assert_eq!(slugify("Café Olé"), "caf-ol");
+        assert_eq!(slugify("!!!"), "");
+        assert_eq!(slugify(""), "");
+    }
+
+    #[test]
+    fn slugify_output_is_a_valid_slug(){
+        for title in ["Art Show", "--Art--", "Ünïcödé", "a/b\\c", "ep. 1: pilot"]{
+            assert!(validate_slug(&slugify(title)).is_ok(), "{}", title);
+        }
+    }
+
+    #[test]
+    fn legacy_slug_lowercases_titles(){
+        assert_eq!(legacy_slug(" Art Show "), "art-show");
+        assert_eq!(legacy_slug("art-show"), "art-show");
+    }
+}
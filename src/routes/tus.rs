@@ -0,0 +1,381 @@
+use {
+    crate::{
+        RwLock,
+        web, HttpRequest, HttpResponse,
+        ActiveTokens, is_valid_token,
+        AppError, S3, S3DeleteQueue,
+        FeedCache, PodcastData, EpisodeFiles,
+        prepare_episode, publish_episode, episode_object_key,
+    },
+    actix_web::http::{
+        StatusCode, header,
+    },
+    base64::{
+        Engine, engine::general_purpose::STANDARD as BASE64,
+    },
+    serde::{
+        Serialize, Deserialize,
+    },
+    chrono::{
+        DateTime, Utc,
+    },
+    futures::StreamExt,
+    sqlx::{
+        PgPool, types::Uuid,
+    },
+    std::{
+        collections::{
+            HashMap, HashSet,
+        },
+        io::Write,
+        path::PathBuf,
+        sync::Mutex,
+    },
+};
+
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination";
+/// largest episode accepted over tus, bytes.
+pub const TUS_MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// an upload in progress, `{temp_dir}/tus-{id}.json` next to its data in `tus-{id}.part`.
+/// The offset is the size of the .part file, so a restart resumes where the disk left off.
+/// Abandoned uploads are removed by the temp_dir sweep.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TusUpload{
+    pub id: Uuid,
+    pub length: u64,
+    /// what `upload` would receive as podcast_data; checked when the upload is created and
+    /// saved without its session_token.
+    pub podcast_data: PodcastData,
+    pub created_at: DateTime<Utc>,
+    /// set once the finished file went through publish_episode.
+    pub episode_id: Option<Uuid>,
+}
+
+/// uploads with a PATCH being written; a second concurrent PATCH gets a 409.
+#[derive(Default)]
+pub struct TusLocks(Mutex<HashSet<Uuid>>);
+
+struct TusLock<'a>{
+    locks: &'a TusLocks,
+    id: Uuid,
+}
+
+impl TusLocks{
+    fn lock(&self, id: Uuid) -> Option<TusLock<'_>>{
+        if !self.0.lock().unwrap().insert(id){
+            return None;
+        }
+        return Some(TusLock{ locks: self, id });
+    }
+}
+
+impl Drop for TusLock<'_>{
+    fn drop(&mut self){
+        self.locks.0.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TusUpload{
+    fn info_path(temp_dir: &str, id: &Uuid) -> PathBuf{
+        return PathBuf::from(format!("{}/tus-{}.json", temp_dir, id));
+    }
+
+    fn part_path(temp_dir: &str, id: &Uuid) -> PathBuf{
+        return PathBuf::from(format!("{}/tus-{}.part", temp_dir, id));
+    }
+
+    async fn load(temp_dir: &str, id: &Uuid) -> Result<Option<Self>, AppError>{
+        let path = Self::info_path(temp_dir, id);
+        let data = match web::block(move|| std::fs::read(path))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?{
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(AppError::Storage(format!("could not read upload info: {}", e))),
+        };
+        return serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| AppError::Storage(format!("upload info is corrupt: {}", e)));
+    }
+
+    /// write-then-rename so a crash never leaves half an info file.
+    async fn save(&self, temp_dir: &str) -> Result<(), AppError>{
+        let path = Self::info_path(temp_dir, &self.id);
+        let data = serde_json::to_vec(self).map_err(|e| AppError::Internal(e.to_string()))?;
+        return web::block(move||{
+            let temp = path.with_extension("json.tmp");
+            std::fs::write(&temp, data)?;
+            return std::fs::rename(temp, path);
+        }).await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::Storage(format!("could not write upload info: {}", e)));
+    }
+
+    async fn offset(&self, temp_dir: &str) -> Result<u64, AppError>{
+        if self.episode_id.is_some(){
+            return Ok(self.length);
+        }
+        let path = Self::part_path(temp_dir, &self.id);
+        return match web::block(move|| std::fs::metadata(path))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?{
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(AppError::Storage(format!("could not read upload: {}", e))),
+        };
+    }
+
+    async fn remove(&self, temp_dir: &str){
+        for path in [Self::part_path(temp_dir, &self.id), Self::info_path(temp_dir, &self.id)]{
+            let display = path.display().to_string();
+            match web::block(move|| std::fs::remove_file(path)).await{
+                Ok(Ok(())) => {},
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Ok(Err(e)) => log::info!("tus: could not remove {}. Err: {}", display, e),
+                Err(e) => log::info!("tus: could not remove {}. Err: {}", display, e),
+            }
+        }
+    }
+}
+
+fn tus_status(status: StatusCode, message: &str) -> HttpResponse{
+    return HttpResponse::build(status)
+        .content_type(mime::TEXT_PLAIN)
+        .body(message.to_string());
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str>{
+    return req.headers().get(name).and_then(|v| v.to_str().ok());
+}
+
+/// clients must speak our version on everything but OPTIONS.
+fn check_version(req: &HttpRequest) -> Option<HttpResponse>{
+    if header_str(req, "Tus-Resumable") != Some(TUS_VERSION){
+        return Some(HttpResponse::PreconditionFailed()
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .finish());
+    }
+    return None;
+}
+
+/// `Upload-Metadata: key base64value,key2 base64value2`
+pub fn parse_upload_metadata(value: &str) -> Result<HashMap<String, String>, AppError>{
+    let mut metadata = HashMap::new();
+    for pair in value.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()){
+        let (key, encoded) = pair.split_once(' ').unwrap_or((pair, ""));
+        let decoded = BASE64.decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or_else(|| AppError::Validation(format!("Upload-Metadata '{}' is not base64 UTF-8", key)))?;
+        metadata.insert(key.to_string(), decoded);
+    }
+    return Ok(metadata);
+}
+
+/// OPTIONS what this server supports.
+pub async fn tus_options() -> HttpResponse{
+    return HttpResponse::NoContent()
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", TUS_MAX_SIZE.to_string()))
+        .finish();
+}
+
+/// POST create an upload. `Upload-Metadata` carries `podcast_data`, the same JSON `upload`
+/// takes; its session_token is checked here, after that the upload URL is the credential,
+/// so an upload can be resumed after the token is gone.
+pub async fn tus_create(
+    req: HttpRequest,
+    active_tokens: web::Data<RwLock<ActiveTokens>>,
+    pg_conn_pool: web::Data<PgPool>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if let Some(response) = check_version(&req){
+        return Ok(response);
+    }
+    let length = match header_str(&req, "Upload-Length").map(|l| l.parse::<u64>()){
+        Some(Ok(length)) => length,
+        Some(Err(_)) => return Ok(tus_status(StatusCode::BAD_REQUEST, "Upload-Length is not a number")),
+        None => return Ok(tus_status(StatusCode::BAD_REQUEST, "Upload-Length is required")),
+    };
+    if length > TUS_MAX_SIZE{
+        return Ok(tus_status(StatusCode::PAYLOAD_TOO_LARGE, "Upload-Length exceeds Tus-Max-Size"));
+    }
+    let metadata = parse_upload_metadata(header_str(&req, "Upload-Metadata").unwrap_or(""))?;
+    let mut podcast_data: PodcastData = match metadata.get("podcast_data"){
+        Some(json) => serde_json::from_str(json)
+            .map_err(|e| AppError::Validation(format!("invalid podcast_data: {}", e)))?,
+        None => return Err(AppError::Validation("Upload-Metadata needs podcast_data".to_string())),
+    };
+    if !is_valid_token(&podcast_data.session_token, active_tokens).await{
+        return Err(AppError::Unauthorized);
+    }
+    // checked; it must not sit in temp_dir with the upload.
+    podcast_data.session_token.clear();
+    // reject bad metadata now rather than after hundreds of MB.
+    let ep_id = Uuid::new_v4();
+    let object_key = episode_object_key(&podcast_data, &ep_id, &s3, &pg_conn_pool).await?;
+    prepare_episode(&mut podcast_data.clone(), &ep_id, length, &object_key, &s3)?;
+
+    let upload = TusUpload{
+        id: Uuid::new_v4(),
+        length,
+        podcast_data,
+        created_at: Utc::now(),
+        episode_id: None,
+    };
+    upload.save(&s3.temp_dir).await?;
+    let part = TusUpload::part_path(&s3.temp_dir, &upload.id);
+    web::block(move|| std::fs::File::create(part))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::Storage(format!("could not create temp file: {}", e)))?;
+
+    let connection = req.connection_info();
+    return Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION,
+            format!("{}://{}/tus/{}", connection.scheme(), connection.host(), upload.id)))
+        .finish());
+}
+
+/// HEAD how much of an upload has arrived.
+pub async fn tus_head(
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if let Some(response) = check_version(&req){
+        return Ok(response);
+    }
+    let upload = match find_upload(&upload_id, &s3).await?{
+        Some(upload) => upload,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let offset = upload.offset(&s3.temp_dir).await?;
+    return Ok(HttpResponse::Ok()
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .insert_header(("Upload-Length", upload.length.to_string()))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .finish());
+}
+
+async fn find_upload(upload_id: &str, s3: &S3) -> Result<Option<TusUpload>, AppError>{
+    return match Uuid::parse_str(upload_id){
+        Ok(id) => TusUpload::load(&s3.temp_dir, &id).await,
+        Err(_) => Ok(None),
+    };
+}
+
+/// PATCH append bytes at `Upload-Offset`. The request that completes the upload publishes
+/// the episode; if publishing fails, an empty PATCH at the final offset retries it.
+pub async fn tus_patch(
+    req: HttpRequest,
+    mut body: web::Payload,
+    locks: web::Data<TusLocks>,
+    pg_conn_pool: web::Data<PgPool>,
+    feed_cache: web::Data<FeedCache>,
+    s3: web::Data<S3>,
+    delete_queue: web::Data<S3DeleteQueue>,
+) -> Result<HttpResponse, AppError>{
+    if let Some(response) = check_version(&req){
+        return Ok(response);
+    }
+    if header_str(&req, "Content-Type") != Some("application/offset+octet-stream"){
+        return Ok(tus_status(StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/offset+octet-stream"));
+    }
+    let upload_id = req.match_info().get("upload_id").unwrap_or_default();
+    let mut upload = match find_upload(upload_id, &s3).await?{
+        Some(upload) => upload,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let _lock = match locks.lock(upload.id){
+        Some(lock) => lock,
+        None => return Ok(tus_status(StatusCode::CONFLICT, "another PATCH is in progress")),
+    };
+    let mut offset = upload.offset(&s3.temp_dir).await?;
+    if header_str(&req, "Upload-Offset").and_then(|o| o.parse::<u64>().ok()) != Some(offset){
+        return Ok(HttpResponse::Conflict()
+            .insert_header(("Upload-Offset", offset.to_string()))
+            .body("Upload-Offset does not match"));
+    }
+
+    if upload.episode_id.is_none(){
+        let part = TusUpload::part_path(&s3.temp_dir, &upload.id);
+        let mut file = web::block(move|| std::fs::OpenOptions::new().append(true).open(part))
+            .await
+            .map_err(|e| AppError::Internal(e.to_string()))?
+            .map_err(|e| AppError::Storage(format!("could not open upload: {}", e)))?;
+        // what arrived before a dropped connection is kept, that's the point.
+        while let Some(chunk) = body.next().await{
+            let data = match chunk{
+                Ok(data) => data,
+                Err(e) => {
+                    log::info!("tus: upload {} interrupted at {}. Err: {}", upload.id, offset, e);
+                    break;
+                },
+            };
+            if offset + data.len() as u64 > upload.length{
+                return Ok(tus_status(StatusCode::PAYLOAD_TOO_LARGE, "body runs past Upload-Length"));
+            }
+            offset += data.len() as u64;
+            file = web::block(move|| file.write_all(&data).map(|_| file))
+                .await
+                .map_err(|e| AppError::Internal(e.to_string()))?
+                .map_err(|e| AppError::Storage(format!("could not write temp file: {}", e)))?;
+        }
+
+        if offset < upload.length{
+            // keeps the info file as fresh as the data for the temp_dir sweep.
+            upload.save(&s3.temp_dir).await?;
+        } else {
+            let part = TusUpload::part_path(&s3.temp_dir, &upload.id);
+            let files = EpisodeFiles{
+                id: Uuid::new_v4(),
+                audio: Some(&part),
+                audio_size: upload.length,
+                artwork: None,
+                object_key: None,
+            };
+            let episode_id = publish_episode(
+                upload.podcast_data.clone(), files, &pg_conn_pool, &feed_cache, &s3, &delete_queue,
+            ).await?;
+            upload.episode_id = Some(episode_id);
+            upload.save(&s3.temp_dir).await?;
+            if let Err(e) = web::block(move|| std::fs::remove_file(part)).await{
+                log::info!("tus: could not remove data of {}. Err: {}", upload.id, e);
+            }
+        }
+    }
+
+    let mut response = HttpResponse::NoContent();
+    response.insert_header(("Upload-Offset", offset.to_string()));
+    if let Some(episode_id) = upload.episode_id{
+        response.insert_header(("Episode-Id", episode_id.to_string()));
+    }
+    return Ok(response.finish());
+}
+
+/// DELETE abandon an upload (termination extension). Published episodes are unaffected.
+pub async fn tus_delete(
+    req: HttpRequest,
+    upload_id: web::Path<String>,
+    locks: web::Data<TusLocks>,
+    s3: web::Data<S3>,
+) -> Result<HttpResponse, AppError>{
+    if let Some(response) = check_version(&req){
+        return Ok(response);
+    }
+    let upload = match find_upload(&upload_id, &s3).await?{
+        Some(upload) => upload,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let _lock = match locks.lock(upload.id){
+        Some(lock) => lock,
+        None => return Ok(tus_status(StatusCode::CONFLICT, "a PATCH is in progress")),
+    };
+    upload.remove(&s3.temp_dir).await;
+    return Ok(HttpResponse::NoContent().finish());
+}
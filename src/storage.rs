@@ -0,0 +1,606 @@
+use {
+    crate::{
+        AppError, S3, StorageGcSettings,
+        ByteStream, ObjectCannedAcl,
+    },
+    aws_sdk_s3::model::{
+        CompletedMultipartUpload, CompletedPart,
+    },
+    serde::Serialize,
+    futures::{
+        StreamExt, TryStreamExt,
+    },
+    chrono::{
+        DateTime, Duration, TimeZone, Utc,
+    },
+    sha2::{
+        Digest, Sha256,
+    },
+    md5::Md5,
+    base64::{
+        Engine, engine::general_purpose::STANDARD as BASE64,
+    },
+    sqlx::PgPool,
+    std::{
+        collections::BTreeMap,
+        io::{
+            Read, Seek, SeekFrom,
+        },
+        path::{
+            Path, PathBuf,
+        },
+        time::SystemTime,
+    },
+};
+
+/// part size for multipart uploads; files up to one part go in a single PUT.
+pub const MULTIPART_PART_SIZE: u64 = 8 * 1024 * 1024;
+/// parts in flight at once, each holds a part sized buffer.
+pub const MULTIPART_CONCURRENCY: usize = 4;
+/// tries per part (or single PUT) before the upload is given up.
+pub const UPLOAD_ATTEMPTS: u32 = 3;
+
+/// digests of a file as uploaded, checked against the stored object.
+#[derive(Serialize, Clone, Debug)]
+pub struct ObjectChecksums{
+    pub size: u64,
+    /// hex, stored on the item as enclosure_sha256.
+    pub sha256: String,
+    /// hex MD5 of the whole file.
+    pub md5: String,
+    /// the ETag S3 computes: the MD5, or for multipart the MD5 of the part MD5s and "-{parts}".
+    pub etag: String,
+    #[serde(skip)]
+    part_md5s: Vec<[u8; 16]>,
+}
+
+/// database references compared with the objects actually in the bucket.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct StorageReport{
+    pub objects: usize,
+    pub referenced: usize,
+    /// enclosures hosted elsewhere, e.g. imported without downloading.
+    pub external: usize,
+    /// key -> what refers to it, for references with no object.
+    pub missing: BTreeMap<String, String>,
+    /// key -> last modified, for objects nothing refers to.
+    pub orphaned: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl StorageReport{
+    pub fn is_consistent(&self) -> bool{
+        return self.missing.is_empty() && self.orphaned.is_empty();
+    }
+}
+
+/// what a garbage collection pass removed, or would remove on a dry run.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct GcOutcome{
+    pub report: StorageReport,
+    pub deleted_objects: Vec<String>,
+    /// orphans inside the grace period, left alone.
+    pub recent_orphans: usize,
+    pub deleted_temp_files: Vec<PathBuf>,
+    /// presigned_upload rows dropped after expires_at plus the grace period.
+    pub expired_reservations: u64,
+    pub failures: Vec<String>,
+}
+
+/// object key of a URL pointing into our bucket, directly or through public_base_url.
+pub fn object_key_for_url<'a>(url: &'a str, s3: &S3) -> Option<&'a str>{
+    return url.strip_prefix(&s3.public_link)
+        .or_else(|| url.strip_prefix(&s3.full_link))
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|key| !key.is_empty());
+}
+
+/// key -> last modified of every object in the bucket, below key_prefix if one is set.
+pub async fn list_bucket_keys(s3: &S3) -> Result<BTreeMap<String, DateTime<Utc>>, AppError>{
+    let mut keys = BTreeMap::new();
+    let mut pages = s3.client.list_objects_v2()
+        .bucket(&s3.bucket)
+        .set_prefix(s3.list_prefix())
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await{
+        let page = page.map_err(|e| AppError::Storage(format!("could not list bucket: {}", e)))?;
+        for object in page.contents().unwrap_or_default(){
+            if let Some(key) = object.key(){
+                // no timestamp reads as brand new, so it is never collected.
+                let last_modified = object.last_modified()
+                    .and_then(|t| Utc.timestamp_opt(t.secs(), 0).single())
+                    .unwrap_or_else(Utc::now);
+                keys.insert(key.to_string(), last_modified);
+            }
+        }
+    }
+    return Ok(keys);
+}
+
+/// every object key the database refers to, with a description of the referring row.
+/// Rows parked in item_orphaned count too, their media is kept for manual recovery.
+pub async fn referenced_keys(pg_conn_pool: &PgPool, s3: &S3) -> Result<(BTreeMap<String, String>, usize), AppError>{
+    let mut referenced = BTreeMap::new();
+    let mut external = 0;
+    // item_orphaned predates object_key, its keys come from the URL.
+    for ep in sqlx::query!(r#"
+        SELECT id AS "id!", enclosure_url AS "enclosure_url!", object_key, 'item' AS "source!" FROM item
+        UNION ALL
+        SELECT id, enclosure_url, NULL, 'orphaned item' FROM item_orphaned
+        "#
+    ).fetch_all(pg_conn_pool)
+    .await?{
+        match ep.object_key.as_deref().or_else(|| object_key_for_url(&ep.enclosure_url, s3)){
+            Some(key) => {
+                referenced.insert(key.to_string(), format!("{} {} enclosure", ep.source, ep.id));
+            },
+            None => external += 1,
+        }
+    }
+    // reserved for a presigned PUT, possibly still in flight.
+    for pending in sqlx::query!(r#" SELECT episode_id, object_key FROM presigned_upload "#)
+        .fetch_all(pg_conn_pool)
+        .await?{
+        referenced.insert(pending.object_key, format!("presigned upload {}", pending.episode_id));
+    }
+    for ep in sqlx::query!(r#"
+        SELECT iv.item_id, v.name, iv.object_key AS "object_key!"
+        FROM item_variant iv JOIN feed_variant v ON v.id = iv.variant_id
+        WHERE iv.object_key IS NOT NULL
+        "#
+    ).fetch_all(pg_conn_pool)
+    .await?{
+        referenced.insert(ep.object_key, format!("item {} '{}' variant enclosure", ep.item_id, ep.name));
+    }
+    for variant in sqlx::query!(r#" SELECT owner_id, size, object_key FROM artwork_variant "#)
+        .fetch_all(pg_conn_pool)
+        .await?{
+        referenced.insert(variant.object_key, format!("artwork {} {}px", variant.owner_id, variant.size));
+    }
+    return Ok((referenced, external));
+}
+
+/// compare item enclosures and artwork variants with the bucket listing.
+pub async fn check_storage(pg_conn_pool: &PgPool, s3: &S3) -> Result<StorageReport, AppError>{
+    let objects = list_bucket_keys(s3).await?;
+    let (referenced, external) = referenced_keys(pg_conn_pool, s3).await?;
+    return Ok(StorageReport{
+        objects: objects.len(),
+        referenced: referenced.len(),
+        external,
+        missing: referenced.iter()
+            .filter(|(key, _)| !objects.contains_key(*key))
+            .map(|(key, owner)| (key.clone(), owner.clone()))
+            .collect(),
+        orphaned: objects.into_iter()
+            .filter(|(key, _)| !referenced.contains_key(key))
+            .collect(),
+    });
+}
+
+/// reconcile the bucket with the database and clear out temp_dir. Orphans past the grace
+/// period are deleted when `delete_orphans`; temp files past their TTL are always removed.
+/// Missing objects are only reported, the rows that need them are left for an operator.
+/// `dry_run` reports what would go without touching anything. Without a key_prefix the
+/// bucket may hold other apps' objects, so orphans there are only deleted with `all_bucket`.
+pub async fn collect_garbage(
+    settings: &StorageGcSettings,
+    delete_orphans: bool,
+    all_bucket: bool,
+    dry_run: bool,
+    pg_conn_pool: &PgPool,
+    s3: &S3,
+) -> Result<GcOutcome, AppError>{
+    if delete_orphans && !dry_run && s3.key_prefix.is_empty() && !all_bucket{
+        return Err(AppError::Validation(
+            "no key_prefix is set: deleting orphans would cover the whole bucket; confirm with --all-bucket or [storage_gc] all_bucket".to_string()));
+    }
+    let mut outcome = GcOutcome::default();
+    // presigned uploads never finalized; their objects turn into orphans below.
+    if !dry_run{
+        let expired = sqlx::query!(r#"
+            DELETE FROM presigned_upload WHERE expires_at < NOW() - make_interval(hours => $1)
+            "#, settings.orphan_grace_hours as i32
+        ).execute(pg_conn_pool)
+        .await?;
+        outcome.expired_reservations = expired.rows_affected();
+    }
+    let report = check_storage(pg_conn_pool, s3).await?;
+
+    let cutoff = Utc::now() - Duration::hours(settings.orphan_grace_hours as i64);
+    for (key, last_modified) in &report.orphaned{
+        if *last_modified > cutoff{
+            outcome.recent_orphans += 1;
+            continue;
+        }
+        if !delete_orphans{
+            continue;
+        }
+        if dry_run{
+            outcome.deleted_objects.push(key.clone());
+            continue;
+        }
+        match s3.client.delete_object()
+            .bucket(&s3.bucket)
+            .key(key)
+            .send()
+            .await{
+            Ok(_) => outcome.deleted_objects.push(key.clone()),
+            Err(e) => outcome.failures.push(format!("could not delete {}: {}", key, e)),
+        }
+    }
+
+    let ttl = std::time::Duration::from_secs(settings.temp_file_ttl_hours * 60 * 60);
+    let (deleted, failures) = sweep_temp_dir(&s3.temp_dir, ttl, dry_run);
+    outcome.deleted_temp_files = deleted;
+    outcome.failures.extend(failures);
+    outcome.report = report;
+    return Ok(outcome);
+}
+
+/// remove regular files in `temp_dir` not modified for `ttl`: upload_object files never
+/// published, and anything left behind by a crash mid import/export.
+pub fn sweep_temp_dir(temp_dir: &str, ttl: std::time::Duration, dry_run: bool) -> (Vec<PathBuf>, Vec<String>){
+    let mut deleted = Vec::new();
+    let mut failures = Vec::new();
+    let entries = match std::fs::read_dir(temp_dir){
+        Ok(entries) => entries,
+        Err(e) => return (deleted, vec![format!("could not read {}: {}", temp_dir, e)]),
+    };
+    let now = SystemTime::now();
+    for entry in entries.flatten(){
+        let path = entry.path();
+        let expired = entry.metadata()
+            .ok()
+            .filter(|m| m.is_file())
+            .and_then(|m| m.modified().ok())
+            .and_then(|modified| now.duration_since(modified).ok())
+            .map(|age| age > ttl)
+            .unwrap_or(false);
+        if !expired{
+            continue;
+        }
+        if dry_run{
+            deleted.push(path);
+            continue;
+        }
+        match std::fs::remove_file(&path){
+            Ok(()) => deleted.push(path),
+            Err(e) => failures.push(format!("could not remove {}: {}", path.display(), e)),
+        }
+    }
+    return (deleted, failures);
+}
+
+/// run collect_garbage every `interval_minutes` when `[storage_gc] enabled`.
+pub fn start_storage_gc(settings: StorageGcSettings, pg_conn_pool: PgPool, s3: S3){
+    if !settings.enabled{
+        return;
+    }
+    let delete_orphans = settings.delete_orphans && (settings.all_bucket || !s3.key_prefix.is_empty());
+    if settings.delete_orphans && !delete_orphans{
+        log::error!("storage gc: delete_orphans needs a key_prefix or all_bucket = true; only reporting orphans");
+    }
+    tokio::spawn(async move {
+        let period = std::time::Duration::from_secs(settings.interval_minutes.max(1) * 60);
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop{
+            interval.tick().await;
+            match collect_garbage(&settings, delete_orphans, settings.all_bucket, false, &pg_conn_pool, &s3).await{
+                Ok(outcome) => {
+                    log::info!("storage gc: {} objects, {} orphaned ({} deleted, {} recent), {} missing, {} temp files removed",
+                        outcome.report.objects, outcome.report.orphaned.len(), outcome.deleted_objects.len(),
+                        outcome.recent_orphans, outcome.report.missing.len(), outcome.deleted_temp_files.len());
+                    for (key, owner) in &outcome.report.missing{
+                        log::error!("storage gc: {} refers to missing object {}", owner, key);
+                    }
+                    for failure in &outcome.failures{
+                        log::error!("storage gc: {}", failure);
+                    }
+                },
+                Err(e) => log::error!("storage gc: pass failed. Err: {}", e),
+            }
+        }
+    });
+}
+
+/// read `path` once, hashing it whole and per MULTIPART_PART_SIZE part.
+pub fn checksum_file(path: &Path) -> std::io::Result<ObjectChecksums>{
+    let mut file = std::fs::File::open(path)?;
+    let mut sha256 = Sha256::new();
+    let mut md5 = Md5::new();
+    let mut part_md5s = Vec::new();
+    let mut size = 0;
+    let mut buf = vec![0; 1024 * 1024];
+    loop{
+        let mut part = Md5::new();
+        let mut part_len = 0;
+        while part_len < MULTIPART_PART_SIZE{
+            let want = buf.len().min((MULTIPART_PART_SIZE - part_len) as usize);
+            let n = file.read(&mut buf[..want])?;
+            if n == 0{
+                break;
+            }
+            sha256.update(&buf[..n]);
+            md5.update(&buf[..n]);
+            part.update(&buf[..n]);
+            part_len += n as u64;
+        }
+        if part_len == 0 && !part_md5s.is_empty(){
+            break;
+        }
+        part_md5s.push(part.finalize().into());
+        size += part_len;
+        if part_len < MULTIPART_PART_SIZE{
+            break;
+        }
+    }
+    let md5 = format!("{:x}", md5.finalize());
+    let etag = match part_md5s.len(){
+        1 => md5.clone(),
+        parts => format!("{:x}-{}", Md5::digest(part_md5s.concat()), parts),
+    };
+    return Ok(ObjectChecksums{
+        size,
+        sha256: format!("{:x}", sha256.finalize()),
+        md5,
+        etag,
+        part_md5s,
+    });
+}
+
+/// upload a file to `key` with the canned `acl`. Files over one part go up as a multipart upload
+/// with MULTIPART_CONCURRENCY parts in parallel; every request is sent with its Content-MD5
+/// and retried up to UPLOAD_ATTEMPTS times. A failed multipart upload is aborted. The
+/// stored object's size and ETag are checked afterwards and it is deleted on a mismatch.
+pub async fn upload_file(key: &str, path: &Path, content_type: &str, acl: ObjectCannedAcl, s3: &S3) -> Result<ObjectChecksums, AppError>{
+    let owned_path = path.to_path_buf();
+    let checksums = tokio::task::spawn_blocking(move|| checksum_file(&owned_path))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| AppError::Storage(format!("could not read {}: {}", path.display(), e)))?;
+
+    if checksums.part_md5s.len() == 1{
+        put_single(key, path, content_type, acl, &checksums, s3).await?;
+    }else{
+        put_multipart(key, path, content_type, acl, &checksums, s3).await?;
+    }
+
+    if let Err(e) = verify_object(key, &checksums, s3).await{
+        log::error!("upload_file: {} failed verification, removing it. Err: {}", key, e);
+        if let Err(e) = s3.client.delete_object().bucket(&s3.bucket).key(key).send().await{
+            log::error!("upload_file: could not remove {}. Err: {}", key, e);
+        }
+        return Err(e);
+    }
+    return Ok(checksums);
+}
+
+/// compare the stored object's size and ETag with what was sent.
+pub async fn verify_object(key: &str, checksums: &ObjectChecksums, s3: &S3) -> Result<(), AppError>{
+    let head = s3.client.head_object()
+        .bucket(&s3.bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| AppError::Storage(format!("could not check {}: {}", key, e)))?;
+    if head.content_length() as u64 != checksums.size{
+        return Err(AppError::Storage(format!("{} holds {} bytes, sent {}", key, head.content_length(), checksums.size)));
+    }
+    let etag = head.e_tag().unwrap_or_default().trim_matches('"');
+    if etag != checksums.etag{
+        return Err(AppError::Storage(format!("{} has ETag {}, expected {}", key, etag, checksums.etag)));
+    }
+    return Ok(());
+}
+
+async fn put_single(key: &str, path: &Path, content_type: &str, acl: ObjectCannedAcl, checksums: &ObjectChecksums, s3: &S3) -> Result<(), AppError>{
+    let content_md5 = BASE64.encode(checksums.part_md5s[0]);
+    for attempt in 1..=UPLOAD_ATTEMPTS{
+        let stream = ByteStream::from_path(path)
+            .await
+            .map_err(|e| AppError::Storage(format!("could not read {}: {}", path.display(), e)))?;
+        match s3.client.put_object()
+            .bucket(&s3.bucket)
+            .key(key)
+            .acl(acl.clone())
+            .content_type(content_type)
+            .content_md5(&content_md5)
+            .body(stream)
+            .send()
+            .await{
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                log::error!("put_single: {} attempt {} failed. Err: {}", key, attempt, e);
+                retry_backoff(attempt).await;
+            },
+        }
+    }
+    return Err(AppError::Storage(format!("failed to upload {}", key)));
+}
+
+async fn put_multipart(key: &str, path: &Path, content_type: &str, acl: ObjectCannedAcl, checksums: &ObjectChecksums, s3: &S3) -> Result<(), AppError>{
+    let created = s3.client.create_multipart_upload()
+        .bucket(&s3.bucket)
+        .key(key)
+        .acl(acl)
+        .content_type(content_type)
+        .send()
+        .await
+        .map_err(|e| AppError::Storage(format!("could not start upload of {}: {}", key, e)))?;
+    let upload_id = created.upload_id()
+        .ok_or_else(|| AppError::Storage(format!("no upload id for {}", key)))?
+        .to_string();
+
+    let parts = futures::stream::iter(checksums.part_md5s.iter().enumerate())
+        .map(|(index, part_md5)| put_part(key, &upload_id, path, index, part_md5, checksums.size, s3))
+        .buffer_unordered(MULTIPART_CONCURRENCY)
+        .try_collect::<Vec<CompletedPart>>()
+        .await;
+    let completed = match parts{
+        Ok(mut parts) => {
+            parts.sort_by_key(|part| part.part_number());
+            s3.client.complete_multipart_upload()
+                .bucket(&s3.bucket)
+                .key(key)
+                .upload_id(&upload_id)
+                .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+                .send()
+                .await
+                .map(|_| ())
+                .map_err(|e| AppError::Storage(format!("could not complete upload of {}: {}", key, e)))
+        },
+        Err(e) => Err(e),
+    };
+    // a complete can go through with its response lost; the object then already checks out.
+    let completed = match completed{
+        Err(e) if verify_object(key, checksums, s3).await.is_ok() => {
+            log::warn!("put_multipart: {} stored despite error on complete. Err: {}", key, e);
+            Ok(())
+        },
+        completed => completed,
+    };
+    if completed.is_err(){
+        // parts of an unaborted upload are billed but never listed, gc can't see them.
+        if let Err(e) = s3.client.abort_multipart_upload()
+            .bucket(&s3.bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .send()
+            .await{
+            log::error!("put_multipart: could not abort upload {} of {}. Remove manually. Err: {}", upload_id, key, e);
+        }
+    }
+    return completed;
+}
+
+async fn put_part(
+    key: &str,
+    upload_id: &str,
+    path: &Path,
+    index: usize,
+    part_md5: &[u8; 16],
+    file_size: u64,
+    s3: &S3,
+) -> Result<CompletedPart, AppError>{
+    let part_number = index as i32 + 1;
+    let offset = index as u64 * MULTIPART_PART_SIZE;
+    let len = MULTIPART_PART_SIZE.min(file_size - offset);
+    let owned_path = path.to_path_buf();
+    let data = tokio::task::spawn_blocking(move|| -> std::io::Result<Vec<u8>>{
+        let mut file = std::fs::File::open(owned_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0; len as usize];
+        file.read_exact(&mut data)?;
+        return Ok(data);
+    }).await
+    .map_err(|e| AppError::Internal(e.to_string()))?
+    .map_err(|e| AppError::Storage(format!("could not read part {} of {}: {}", part_number, path.display(), e)))?;
+    let content_md5 = BASE64.encode(part_md5);
+
+    for attempt in 1..=UPLOAD_ATTEMPTS{
+        match s3.client.upload_part()
+            .bucket(&s3.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .content_length(len as i64)
+            .content_md5(&content_md5)
+            .body(ByteStream::from(data.clone()))
+            .send()
+            .await{
+            Ok(output) => {
+                return Ok(CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(output.e_tag().map(|etag| etag.to_string()))
+                    .build());
+            },
+            Err(e) => {
+                log::error!("put_part: {} part {} attempt {} failed. Err: {}", key, part_number, attempt, e);
+                retry_backoff(attempt).await;
+            },
+        }
+    }
+    return Err(AppError::Storage(format!("failed to upload part {} of {}", part_number, key)));
+}
+
+/// 1s, 2s between tries; nothing after the last.
+async fn retry_backoff(attempt: u32){
+    if attempt < UPLOAD_ATTEMPTS{
+        tokio::time::sleep(std::time::Duration::from_secs(1 << (attempt - 1))).await;
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use {
+        super::*,
+        crate::{
+            Config, Region, S3Client,
+        },
+        sqlx::types::Uuid,
+    };
+
+    /// checksums of `len` patterned bytes, written to a temp file.
+    fn checksums_of(len: u64) -> (Vec<u8>, ObjectChecksums){
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let path = std::env::temp_dir().join(format!("checksum-{}", Uuid::new_v4()));
+        std::fs::write(&path, &data).unwrap();
+        let checksums = checksum_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        return (data, checksums.unwrap());
+    }
+
+    #[test]
+    fn empty_file_is_one_empty_part(){
+        let (_, checksums) = checksums_of(0);
+        assert_eq!(checksums.size, 0);
+        assert_eq!(checksums.md5, "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(checksums.etag, checksums.md5);
+        assert_eq!(checksums.sha256, "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(checksums.part_md5s.len(), 1);
+    }
+
+    #[test]
+    fn single_part_etag_is_the_md5(){
+        for len in [1, MULTIPART_PART_SIZE]{
+            let (data, checksums) = checksums_of(len);
+            assert_eq!(checksums.size, len);
+            assert_eq!(checksums.md5, format!("{:x}", Md5::digest(&data)));
+            assert_eq!(checksums.sha256, format!("{:x}", Sha256::digest(&data)));
+            assert_eq!(checksums.etag, checksums.md5);
+            assert_eq!(checksums.part_md5s.len(), 1);
+        }
+    }
+
+    #[test]
+    fn multipart_etag_hashes_the_part_md5s(){
+        for (len, parts) in [(2 * MULTIPART_PART_SIZE, 2), (2 * MULTIPART_PART_SIZE + 1, 3)]{
+            let (data, checksums) = checksums_of(len);
+            let part_md5s: Vec<u8> = data.chunks(MULTIPART_PART_SIZE as usize)
+                .flat_map(|part| Md5::digest(part).to_vec())
+                .collect();
+            assert_eq!(checksums.size, len);
+            assert_eq!(checksums.md5, format!("{:x}", Md5::digest(&data)));
+            assert_eq!(checksums.part_md5s.len(), parts);
+            assert_eq!(checksums.etag, format!("{:x}-{}", Md5::digest(&part_md5s), parts));
+        }
+    }
+
+    #[actix_web::test]
+    async fn orphans_in_an_unprefixed_bucket_need_all_bucket(){
+        let s3 = S3{
+            client: S3Client::from_conf(Config::builder().region(Region::new("us-east-1")).build()),
+            bucket: "fake-bucket".to_string(),
+            full_link: "https://fake-bucket.example.com".to_string(),
+            public_link: "https://fake-bucket.example.com".to_string(),
+            temp_dir: std::env::temp_dir().to_string_lossy().to_string(),
+            key_prefix: String::new(),
+            key_template: "{id}.{ext}".to_string(),
+        };
+        // refused before the database or the bucket is touched.
+        let pg_conn_pool = PgPool::connect_lazy("postgres://nobody@127.0.0.1:1/none").unwrap();
+        let refused = collect_garbage(&StorageGcSettings::default(), true, false, false, &pg_conn_pool, &s3).await;
+        assert!(matches!(refused, Err(AppError::Validation(_))));
+    }
+}
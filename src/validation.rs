@@ -0,0 +1,322 @@
+use {
+    crate::ChannelCategory,
+    chrono::DateTime,
+    validator::ValidationError,
+};
+
+/// Apple Podcasts category taxonomy: (category, subcategories).
+/// https://podcasters.apple.com/support/1691-apple-podcasts-categories
+pub const APPLE_CATEGORIES: &[(&str, &[&str])] = &[
+    ("Arts", &["Books", "Design", "Fashion & Beauty", "Food", "Performing Arts", "Visual Arts"]),
+    ("Business", &["Careers", "Entrepreneurship", "Investing", "Management", "Marketing", "Non-Profit"]),
+    ("Comedy", &["Comedy Interviews", "Improv", "Stand-Up"]),
+    ("Education", &["Courses", "How To", "Language Learning", "Self-Improvement"]),
+    ("Fiction", &["Comedy Fiction", "Drama", "Science Fiction"]),
+    ("Government", &[]),
+    ("History", &[]),
+    ("Health & Fitness", &["Alternative Health", "Fitness", "Medicine", "Mental Health",
+        "Nutrition", "Sexuality"]),
+    ("Kids & Family", &["Education for Kids", "Parenting", "Pets & Animals", "Stories for Kids"]),
+    ("Leisure", &["Animation & Manga", "Automotive", "Aviation", "Crafts", "Games", "Hobbies",
+        "Home & Garden", "Video Games"]),
+    ("Music", &["Music Commentary", "Music History", "Music Interviews"]),
+    ("News", &["Business News", "Daily News", "Entertainment News", "News Commentary", "Politics",
+        "Sports News", "Tech News"]),
+    ("Religion & Spirituality", &["Buddhism", "Christianity", "Hinduism", "Islam", "Judaism",
+        "Religion", "Spirituality"]),
+    ("Science", &["Astronomy", "Chemistry", "Earth Sciences", "Life Sciences", "Mathematics",
+        "Natural Sciences", "Nature", "Physics", "Social Sciences"]),
+    ("Society & Culture", &["Documentary", "Personal Journals", "Philosophy", "Places & Travel",
+        "Relationships"]),
+    ("Sports", &["Baseball", "Basketball", "Cricket", "Fantasy Sports", "Football", "Golf", "Hockey",
+        "Rugby", "Running", "Soccer", "Swimming", "Tennis", "Volleyball", "Wilderness", "Wrestling"]),
+    ("Technology", &[]),
+    ("True Crime", &[]),
+    ("TV & Film", &["After Shows", "Film History", "Film Interviews", "Film Reviews", "TV Reviews"]),
+];
+
+/// ISO 639-1 language codes.
+pub const ISO_639_1: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az", "ba", "be", "bg", "bh",
+    "bi", "bm", "bn", "bo", "br", "bs", "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy", "da",
+    "de", "dv", "dz", "ee", "el", "en", "eo", "es", "et", "eu", "fa", "ff", "fi", "fj", "fo", "fr",
+    "fy", "ga", "gd", "gl", "gn", "gu", "gv", "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz",
+    "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu", "ja", "jv", "ka", "kg", "ki", "kj",
+    "kk", "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky", "la", "lb", "lg", "li", "ln",
+    "lo", "lt", "lu", "lv", "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my", "na", "nb",
+    "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny", "oc", "oj", "om", "or", "os", "pa", "pi",
+    "pl", "ps", "pt", "qu", "rm", "rn", "ro", "ru", "rw", "sa", "sc", "sd", "se", "sg", "si", "sk",
+    "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw", "ta", "te", "tg", "th", "ti",
+    "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty", "ug", "uk", "ur", "uz", "ve", "vi", "vo",
+    "wa", "wo", "xh", "yi", "yo", "za", "zh", "zu",
+];
+
+pub const SY_UPDATE_PERIODS: &[&str] = &["hourly", "daily", "weekly", "monthly", "yearly"];
+
+fn error(code: &'static str, message: String) -> ValidationError{
+    return ValidationError::new(code).with_message(message.into());
+}
+
+/// top level Apple category, e.g. "Technology".
+pub fn validate_apple_category(category: &str) -> Result<(), ValidationError>{
+    if APPLE_CATEGORIES.iter().any(|(c, _)| *c == category){
+        return Ok(());
+    }
+    return Err(error("apple_category",
+        format!("'{}' is not an Apple Podcasts category", category)));
+}
+
+/// ISO 639-1 code with optional region: "en", "en-us", "es-419".
+pub fn validate_language(language: &str) -> Result<(), ValidationError>{
+    let language = language.to_lowercase();
+    let mut parts = language.splitn(2, '-');
+    let code = parts.next().unwrap_or("");
+    let region_ok = match parts.next(){
+        Some(region) => (region.len() == 2 && region.chars().all(|c| c.is_ascii_alphabetic()))
+            || (region.len() == 3 && region.chars().all(|c| c.is_ascii_digit())),
+        None => true,
+    };
+    if ISO_639_1.contains(&code) && region_ok{
+        return Ok(());
+    }
+    return Err(error("language",
+        format!("'{}' is not an ISO 639-1 language code", language)));
+}
+
+/// RFC 2822 (RFC 3339 accepted).
+pub fn validate_date(date: &str) -> Result<(), ValidationError>{
+    let date = date.trim();
+    if DateTime::parse_from_rfc2822(date).is_ok() || DateTime::parse_from_rfc3339(date).is_ok(){
+        return Ok(());
+    }
+    return Err(error("date", format!("'{}' is not an RFC 2822 date", date)));
+}
+
+/// optional fields are sent as "".
+pub fn validate_optional_url(url: &str) -> Result<(), ValidationError>{
+    if url.is_empty() || validator::ValidateUrl::validate_url(&url){
+        return Ok(());
+    }
+    return Err(error("url", format!("'{}' is not a valid URL", url)));
+}
+
+pub fn validate_itunes_type(itunes_type: &str) -> Result<(), ValidationError>{
+    if itunes_type == "episodic" || itunes_type == "serial"{
+        return Ok(());
+    }
+    return Err(error("itunes_type", "must be 'episodic' or 'serial'".to_string()));
+}
+
+pub fn validate_sy_update_period(period: &str) -> Result<(), ValidationError>{
+    if SY_UPDATE_PERIODS.contains(&period){
+        return Ok(());
+    }
+    return Err(error("sy_update_period",
+        format!("must be one of {}", SY_UPDATE_PERIODS.join(", "))));
+}
+
+pub fn validate_sy_update_frequency(frequency: &str) -> Result<(), ValidationError>{
+    return match frequency.parse::<u32>(){
+        Ok(f) if f > 0 => Ok(()),
+        _ => Err(error("sy_update_frequency", "must be a positive integer".to_string())),
+    };
+}
+
+/// an address, or the RSS form with a name: "editor@example.com (Jane Doe)".
+pub fn validate_rss_email(value: &str) -> Result<(), ValidationError>{
+    let value = value.trim();
+    let (address, name_ok) = match value.split_once('('){
+        Some((address, name)) => (address.trim(), name.len() > 1 && name.ends_with(')')),
+        None => (value.trim(), true),
+    };
+    if name_ok && validator::ValidateEmail::validate_email(&address.to_string()){
+        return Ok(());
+    }
+    return Err(error("email", format!("'{}' is not an email address or 'address (name)'", value)));
+}
+
+/// "NONE", seconds, MM:SS or HH:MM:SS.
+pub fn validate_itunes_duration(duration: &str) -> Result<(), ValidationError>{
+    let parts: Vec<&str> = duration.split(':').collect();
+    let ok = duration == "NONE"
+        || (parts.len() <= 3 && parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())));
+    if ok{
+        return Ok(());
+    }
+    return Err(error("itunes_duration",
+        "must be NONE, seconds, MM:SS or HH:MM:SS".to_string()));
+}
+
+/// up to 3 distinct (category, subcategory) pairs from the Apple taxonomy.
+pub fn validate_channel_categories(categories: &[ChannelCategory]) -> Result<(), ValidationError>{
+    if categories.len() > 3{
+        return Err(error("categories", "at most 3 categories per channel".to_string()));
+    }
+    for (i, c) in categories.iter().enumerate(){
+        let subcategories = match APPLE_CATEGORIES.iter().find(|(name, _)| *name == c.category){
+            Some((_, subcategories)) => subcategories,
+            None => return Err(error("categories",
+                format!("'{}' is not an Apple Podcasts category", c.category))),
+        };
+        if let Some(sub) = &c.subcategory{
+            if !subcategories.contains(&sub.as_str()){
+                return Err(error("categories",
+                    format!("'{}' is not a subcategory of '{}'", sub, c.category)));
+            }
+        }
+        if categories[..i].contains(c){
+            return Err(error("categories", format!("'{}' is listed twice", c.category)));
+        }
+    }
+    return Ok(());
+}
+
+/// paths owned by the API; custom feed paths can't shadow them.
+pub const RESERVED_PATH_PREFIXES: &[&str] = &[
+    "/podcast", "/channel", "/upload", "/get_auth", "/health_check", "/import", "/tus",
+    "/presign_upload", "/finalize_upload", "/feed_subscriber",
+    "/feed_variant", "/replace_episode_audio",
+];
+
+/// lowercase ASCII letters and digits separated by single dashes; "" lets the server derive it.
+pub fn validate_slug(slug: &str) -> Result<(), ValidationError>{
+    let ok = slug.is_empty() || (slug.len() <= 100
+        && slug.split('-').all(|part| !part.is_empty()
+            && part.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())));
+    if ok{
+        return Ok(());
+    }
+    return Err(error("slug", "must be lowercase letters and digits separated by single dashes".to_string()));
+}
+
+/// absolute URL path such as "/feeds/my-show.xml", outside the API's own routes.
+pub fn validate_custom_path(path: &str) -> Result<(), ValidationError>{
+    let chars_ok = path.chars().all(|c| c.is_ascii_alphanumeric() || "/._-".contains(c));
+    if !path.starts_with('/') || path.len() < 2 || path.len() > 255 || !chars_ok
+        || path.ends_with('/') || path.contains("//") || path.split('/').any(|s| s == "." || s == ".."){
+        return Err(error("custom_path",
+            "must be an absolute path of letters, digits, '.', '_', '-' and '/'".to_string()));
+    }
+    let lowercase = path.to_lowercase();
+    if RESERVED_PATH_PREFIXES.iter().any(|prefix| lowercase.starts_with(prefix)){
+        return Err(error("custom_path", format!("'{}' is reserved", path)));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn category(category: &str, subcategory: Option<&str>) -> ChannelCategory{
+        return ChannelCategory{
+            category: category.to_string(),
+            subcategory: subcategory.map(|s| s.to_string()),
+        };
+    }
+
+    #[test]
+    fn rss_emails(){
+        for email in ["editor@example.com", "editor@example.com (Jane Doe)", " editor@example.com(Jane) "]{
+            assert!(validate_rss_email(email).is_ok(), "{}", email);
+        }
+        for email in ["", "Jane Doe", "editor@example.com (", "editor@example.com ()", "editor@example.com Jane",
+            "(Jane) editor@example.com", "editor@example.com (Jane"]{
+            assert!(validate_rss_email(email).is_err(), "{}", email);
+        }
+    }
+
+    #[test]
+    fn languages(){
+        for language in ["en", "EN", "en-us", "en-GB", "es-419", "zh"]{
+            assert!(validate_language(language).is_ok(), "{}", language);
+        }
+        for language in ["", "eng", "english", "xx", "en-", "en-usa", "es-41", "en_us"]{
+            assert!(validate_language(language).is_err(), "{}", language);
+        }
+    }
+
+    #[test]
+    fn itunes_durations(){
+        for duration in ["NONE", "0", "3600", "12:34", "1:02:03"]{
+            assert!(validate_itunes_duration(duration).is_ok(), "{}", duration);
+        }
+        for duration in ["", "none", "1:2:3:4", "12:", ":34", "1h", "-5", "1.5"]{
+            assert!(validate_itunes_duration(duration).is_err(), "{}", duration);
+        }
+    }
+
+    #[test]
+    fn apple_categories(){
+        assert!(validate_apple_category("Technology").is_ok());
+        assert!(validate_apple_category("technology").is_err());
+        assert!(validate_apple_category("Design").is_err());
+
+        let valid = [
+            vec![],
+            vec![category("Arts", None)],
+            vec![category("Arts", Some("Design")), category("Arts", Some("Books")), category("Technology", None)],
+        ];
+        for categories in valid{
+            assert!(validate_channel_categories(&categories).is_ok(), "{:?}", categories);
+        }
+        let invalid = [
+            vec![category("Art", None)],
+            vec![category("Arts", Some("Politics"))],
+            vec![category("Arts", None), category("Arts", None)],
+            vec![category("Arts", None), category("News", None), category("History", None), category("Technology", None)],
+        ];
+        for categories in invalid{
+            assert!(validate_channel_categories(&categories).is_err(), "{:?}", categories);
+        }
+    }
+
+    #[test]
+    fn dates_urls_and_channel_settings(){
+        assert!(validate_date("Wed, 01 Mar 2023 10:00:00 +0000").is_ok());
+        assert!(validate_date("2023-03-01T10:00:00Z").is_ok());
+        assert!(validate_date("March 1st").is_err());
+        assert!(validate_optional_url("").is_ok());
+        assert!(validate_optional_url("https://example.com/feed.xml").is_ok());
+        assert!(validate_optional_url("example.com").is_err());
+        assert!(validate_itunes_type("serial").is_ok());
+        assert!(validate_itunes_type("Serial").is_err());
+        assert!(validate_sy_update_period("weekly").is_ok());
+        assert!(validate_sy_update_period("fortnightly").is_err());
+        assert!(validate_sy_update_frequency("2").is_ok());
+        for frequency in ["0", "-1", "two", ""]{
+            assert!(validate_sy_update_frequency(frequency).is_err(), "{}", frequency);
+        }
+    }
+
+    #[test]
+    fn slugs(){
+        for slug in ["", "art", "art-show", "art-show-2", "2024"]{
+            assert!(validate_slug(slug).is_ok(), "{}", slug);
+        }
+        for slug in ["Art", "art show", "-art", "art-", "art--show", "art_show", "café", &"a".repeat(101)]{
+            assert!(validate_slug(slug).is_err(), "{}", slug);
+        }
+        assert!(validate_slug(&"a".repeat(100)).is_ok());
+    }
+
+    #[test]
+    fn custom_paths(){
+        for path in ["/feeds/art-show.xml", "/a", "/Art_Show/rss.xml", "/shows/podcast.xml"]{
+            assert!(validate_custom_path(path).is_ok(), "{}", path);
+        }
+        for path in ["feeds/art.xml", "/", "/feeds/", "/feeds//art.xml", "/feeds/../art.xml", "/./art.xml",
+            "/art show.xml", "/art?x=1", &format!("/{}", "a".repeat(255))]{
+            assert!(validate_custom_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn custom_paths_stay_off_api_routes(){
+        // prefixes, so routes like /channels and /upload_object are covered too.
+        for path in ["/podcast/art-show", "/Podcast/x", "/upload_object", "/health_check_xml", "/tus/1",
+            "/feed_variant/x", "/channels", "/podcasts.xml"]{
+            assert!(validate_custom_path(path).is_err(), "{}", path);
+        }
+    }
+}